@@ -0,0 +1,132 @@
+use crate::input::Extensions;
+use indicatif::ProgressBar;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// What happened to the entries of an archive besides the decoded images.
+pub struct ArchiveReport {
+    /// image entries that were handed to the visitor
+    pub visited: usize,
+    /// entries that were not image files according to their extension
+    pub skipped: usize,
+    /// image entries that could not be read or decoded, with the reason
+    pub failed: Vec<(String, String)>,
+}
+
+pub fn is_tar(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    path.is_file()
+        && (name.ends_with(".tar") || name.ends_with(".tar.gz") || name.ends_with(".tgz"))
+}
+
+pub fn is_zip(path: &Path) -> bool {
+    path.is_file()
+        && path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("zip"))
+}
+
+/// Hands the bytes of every image entry in a zip archive to `visit`. An
+/// error returned by `visit` is recorded as a failed entry.
+pub fn for_each_zip_entry(
+    path: &Path,
+    extensions: &Extensions,
+    mut visit: impl FnMut(&str, &[u8]) -> Result<(), String>,
+) -> Result<ArchiveReport, String> {
+    let open_err = |e: &dyn std::fmt::Display| format!("Could not open {}: {}", path.display(), e);
+    let file = File::open(path).map_err(|e| open_err(&e))?;
+    let mut zip = zip::ZipArchive::new(file).map_err(|e| open_err(&e))?;
+
+    let mut entries = Vec::new();
+    let mut skipped = 0;
+    for i in 0..zip.len() {
+        let entry = zip.by_index(i).map_err(|e| open_err(&e))?;
+        if entry.is_file() && extensions.matches(Path::new(entry.name())) {
+            entries.push(i);
+        } else if entry.is_file() {
+            skipped += 1;
+        }
+    }
+
+    let visited = entries.len();
+    let bar = ProgressBar::new(visited as u64);
+    let mut failed = Vec::new();
+    for i in entries {
+        let mut entry = zip.by_index(i).map_err(|e| open_err(&e))?;
+        let mut bytes = Vec::with_capacity(entry.size() as usize);
+        let result = entry
+            .read_to_end(&mut bytes)
+            .map_err(|e| e.to_string())
+            .and_then(|_| visit(entry.name(), &bytes));
+        if let Err(e) = result {
+            failed.push((entry.name().to_string(), e));
+        }
+        bar.inc(1);
+    }
+    bar.finish_and_clear();
+
+    Ok(ArchiveReport {
+        visited,
+        skipped,
+        failed,
+    })
+}
+
+/// Streams a `.tar` or `.tar.gz` archive, handing the bytes of every image
+/// entry to `visit` one at a time so only a single image is held in memory.
+/// An error returned by `visit` is recorded as a failed entry.
+pub fn for_each_tar_entry(
+    path: &Path,
+    extensions: &Extensions,
+    mut visit: impl FnMut(&str, &[u8]) -> Result<(), String>,
+) -> Result<ArchiveReport, String> {
+    let open_err = |e: &dyn std::fmt::Display| format!("Could not read {}: {}", path.display(), e);
+    let file = File::open(path).map_err(|e| open_err(&e))?;
+    let name = path.to_string_lossy().to_ascii_lowercase();
+    let reader: Box<dyn Read> = if name.ends_with(".gz") || name.ends_with(".tgz") {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let mut tar = tar::Archive::new(reader);
+
+    let bar = ProgressBar::new_spinner();
+    let mut visited = 0;
+    let mut skipped = 0;
+    let mut failed = Vec::new();
+    for entry in tar.entries().map_err(|e| open_err(&e))? {
+        let mut entry = entry.map_err(|e| open_err(&e))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry
+            .path()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if !extensions.matches(Path::new(&name)) {
+            skipped += 1;
+            continue;
+        }
+        let mut bytes = Vec::with_capacity(entry.size() as usize);
+        let result = entry
+            .read_to_end(&mut bytes)
+            .map_err(|e| e.to_string())
+            .and_then(|_| visit(&name, &bytes));
+        if let Err(e) = result {
+            failed.push((name, e));
+        }
+        visited += 1;
+        bar.inc(1);
+    }
+    bar.finish_and_clear();
+
+    Ok(ArchiveReport {
+        visited,
+        skipped,
+        failed,
+    })
+}
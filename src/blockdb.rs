@@ -1,203 +1,993 @@
-use std::cmp::PartialOrd;
+use crate::input::stable_hash_bytes;
+#[cfg(feature = "serde")]
+use serde::de::{self, Deserializer};
+#[cfg(feature = "serde")]
+use serde::ser::{SerializeSeq, Serializer};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, PartialOrd};
+use std::collections::BinaryHeap;
+use std::convert::TryInto;
 use std::fmt::{Debug, Display, Error, Formatter, Write};
+use std::io;
 use std::ops::{Add, Mul, Sub};
 
-pub struct BlockDb<T, I> {
-    root: Option<Box<Node<T, I>>>,
+/// A kd-tree over keys with `N` components, cycling through them level by
+/// level.
+pub struct BlockDb<T, I, const N: usize = 3> {
+    root: Option<Box<Node<T, I, N>>>,
+    len: usize,
+    /// at least as many as the tree has levels
+    levels: usize,
+    /// `balance_factor` past which inserts rebuild the tree
+    max_balance_factor: f64,
+    /// items added so far, the `Node::order` of the next one
+    added: usize,
+    /// keys the items `insert` adds, `None` unless the tree was built by
+    /// `new`
+    keyfn: Option<KeyFn<T, I, N>>,
 }
 
-#[derive(Debug, Copy, Clone)]
-enum Dimension {
-    First,
-    Second,
-    Third,
+/// The key function `BlockDb::new` keeps for `BlockDb::insert`.
+type KeyFn<T, I, const N: usize> = Box<dyn FnMut(&I) -> [T; N] + Send + Sync>;
+
+/// Default `BlockDb::set_max_balance_factor`.
+const MAX_BALANCE_FACTOR: f64 = 3.0;
+
+/// Subtrees with fewer nodes are built on the thread of their parent.
+const PARALLEL_BUILD_MIN: usize = 4096;
+
+/// Trees with at most this many levels aren't rebuilt by inserts.
+const DEPTH_SLACK: usize = 8;
+
+#[cfg(test)]
+thread_local! {
+    /// nodes `find_closest` looked at on this thread
+    static VISITED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
 }
 
-impl Dimension {
-    fn next(self) -> Self {
-        match self {
-            Self::First => Dimension::Second,
-            Self::Second => Dimension::Third,
-            Self::Third => Dimension::First,
-        }
+#[derive(Debug)]
+struct Node<T, I, const N: usize> {
+    key: [T; N],
+    item: I,
+    /// index of the key component this node splits by
+    dim: usize,
+    /// how many items were added to the tree before this one, of equally
+    /// close items the searches return the one added first
+    order: usize,
+    right: Option<Box<Node<T, I, N>>>,
+    left: Option<Box<Node<T, I, N>>>,
+}
+
+type Subtree<'a, T, I, const N: usize> = Option<&'a Node<T, I, N>>;
+
+/// A node found by a k-closest search, ordered by its squared distance and
+/// then by when it was added, so the heap of them drops the farthest and,
+/// among equally far ones, the last added.
+struct Candidate<'a, T: KeyElem, I, const N: usize> {
+    dist: T::Dist,
+    node: &'a Node<T, I, N>,
+}
+
+impl<T: KeyElem, I, const N: usize> Ord for Candidate<'_, T, I, N> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        cmp_rank(
+            &(self.dist, self.node.order),
+            &(other.dist, other.node.order),
+        )
     }
 }
 
-impl From<Dimension> for usize {
-    fn from(d: Dimension) -> Self {
-        match d {
-            Dimension::First => 0,
-            Dimension::Second => 1,
-            Dimension::Third => 2,
-        }
+impl<T: KeyElem, I, const N: usize> PartialOrd for Candidate<'_, T, I, N> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
-#[derive(Debug)]
-struct Node<T, I> {
-    key: [T; 3],
-    item: I,
-    dim: Dimension,
-    right: Option<Box<Node<T, I>>>,
-    left: Option<Box<Node<T, I>>>,
+impl<T: KeyElem, I, const N: usize> PartialEq for Candidate<'_, T, I, N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
 }
 
+impl<T: KeyElem, I, const N: usize> Eq for Candidate<'_, T, I, N> {}
+
 pub trait KeyElem:
-    Copy + PartialOrd + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + Into<i64>
+    Copy + PartialOrd + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self>
 {
+    /// What squared distances between keys are measured in, `i64` for
+    /// integer components and `f64` for floating point ones.
+    type Dist: Distance;
+    fn widen(self) -> Self::Dist;
+}
+
+macro_rules! key_elem {
+    ($($t:ty => $d:ty),*) => {$(
+        impl KeyElem for $t {
+            type Dist = $d;
+            fn widen(self) -> $d {
+                self.into()
+            }
+        }
+    )*};
+}
+key_elem!(i16 => i64, i32 => i64, i64 => i64, f32 => f64, f64 => f64);
+
+/// Squared distances between keys, see `KeyElem::Dist`.
+pub trait Distance:
+    Copy + PartialOrd + Debug + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self>
+{
+    const ZERO: Self;
+    const ONE: Self;
+    /// Farther than any two keys are apart.
+    const MAX: Self;
+    /// A total order, for floats that of `f64::total_cmp`.
+    fn total_cmp(&self, other: &Self) -> Ordering;
+    fn to_f64(self) -> f64;
+}
+
+impl Distance for i64 {
+    const ZERO: Self = 0;
+    const ONE: Self = 1;
+    const MAX: Self = i64::MAX;
+    fn total_cmp(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+    }
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Distance for f64 {
+    const ZERO: Self = 0.0;
+    const ONE: Self = 1.0;
+    const MAX: Self = f64::INFINITY;
+    fn total_cmp(&self, other: &Self) -> Ordering {
+        f64::total_cmp(self, other)
+    }
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+/// Keys components and items `BlockDb::save_to` can write, in `BYTES`
+/// bytes each.
+pub trait Record: Sized {
+    const BYTES: usize;
+    /// Tells types apart in saved files, even when they have the same size.
+    const TAG: u8;
+    fn write(&self, out: &mut Vec<u8>);
+    /// The value in `bytes`, `None` if they don't hold a valid one.
+    fn read(bytes: &[u8]) -> Option<Self>;
 }
-impl KeyElem for i16 {}
-impl KeyElem for i32 {}
-impl KeyElem for i64 {}
 
-impl<T, I> Display for Node<T, I>
+macro_rules! le_record {
+    ($($t:ty = $tag:expr),*) => {$(
+        impl Record for $t {
+            const BYTES: usize = std::mem::size_of::<$t>();
+            const TAG: u8 = $tag;
+            fn write(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+            fn read(bytes: &[u8]) -> Option<Self> {
+                bytes.try_into().ok().map(<$t>::from_le_bytes)
+            }
+        }
+    )*};
+}
+le_record!(i16 = 1, i32 = 2, i64 = 3, f32 = 4, f64 = 5);
+
+/// First bytes of files written by `BlockDb::save_to`.
+const FILE_MAGIC: &[u8; 8] = b"CLGNKDTR";
+
+/// Files written in another format version are refused. Version 2 added
+/// `Node::order`, version 3 the `Record::TAG`s of keys and items.
+const FILE_VERSION: u16 = 3;
+
+impl<T, I, const N: usize> Display for Node<T, I, N>
 where
     I: Debug,
     T: Display,
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
-        write!(f, "key: ({},{},{}) ", self.key[0], self.key[1], self.key[2])?;
-        writeln!(f, "dim: {:?} ", self.dim)?;
-        if let Some(l) = &self.left {
-            write!(f, " left: \n {}", l)?;
-        };
-        if let Some(r) = &self.right {
-            write!(f, " right: \n {}", r)?;
+        // every node before its left subtree, and that before its right one
+        let mut stack = vec![("", self)];
+        while let Some((side, node)) = stack.pop() {
+            write!(f, "{}key: ({}) ", side, join(&node.key))?;
+            writeln!(f, "dim: {:?} ", node.dim)?;
+            if let Some(r) = &node.right {
+                stack.push((" right: \n ", r));
+            }
+            if let Some(l) = &node.left {
+                stack.push((" left: \n ", l));
+            }
         }
         Ok(())
     }
 }
 
-impl<T, I> Node<T, I>
+impl<T, I, const N: usize> Node<T, I, N>
 where
     T: KeyElem,
 {
     fn is_leaf(&self) -> bool {
         self.left.is_none() && self.right.is_none()
     }
-    fn squared_dist(&self, target: &[T; 3]) -> i64 {
-        let k = &self.key;
-        let d0 = target[0].into() - k[0].into();
-        let d1 = target[1].into() - k[1].into();
-        let d2 = target[2].into() - k[2].into();
-        return (d0 * d0) + (d1 * d1) + (d2 * d2);
+    fn squared_dist(&self, target: &[T; N]) -> T::Dist {
+        squared_dist(&self.key, target)
     }
+
+    /// What searches for the closest node to `target` go by, smallest
+    /// first as ordered by `cmp_rank`: the distance, then the `order`.
+    fn rank(&self, target: &[T; N]) -> (T::Dist, usize) {
+        (self.squared_dist(target), self.order)
+    }
+}
+
+fn cmp_rank<D: Distance>(a: &(D, usize), b: &(D, usize)) -> Ordering {
+    a.0.total_cmp(&b.0).then(a.1.cmp(&b.1))
 }
 
-impl<T, I> BlockDb<T, I>
+fn squared_dist<T: KeyElem, const N: usize>(key: &[T; N], target: &[T; N]) -> T::Dist {
+    weighted_dist(key, target, &[T::Dist::ONE; N])
+}
+
+/// The squared differences of the components, each times its weight.
+fn weighted_dist<T: KeyElem, const N: usize>(
+    key: &[T; N],
+    target: &[T; N],
+    weights: &[T::Dist; N],
+) -> T::Dist {
+    key.iter()
+        .zip(target)
+        .zip(weights)
+        .fold(T::Dist::ZERO, |sum, ((&k, &t), &w)| {
+            let d = t.widen() - k.widen();
+            sum + d * d * w
+        })
+}
+
+impl<T, I, const N: usize> BlockDb<T, I, N>
 where
     T: KeyElem,
 {
-    pub fn new(items: Vec<I>, keyfn: fn(&I) -> [T; 3]) -> Self {
-        let mut nodes: Vec<Box<Node<T, I>>> = Vec::with_capacity(items.len());
+    /// Builds the tree from items keyed by `keyfn`, which may be a function
+    /// or a closure capturing e.g. weights. Of equally close items searches
+    /// find the earliest in `items`. The tree keeps `keyfn` to key the
+    /// items `insert` adds.
+    #[allow(dead_code)]
+    pub fn new(items: Vec<I>, mut keyfn: impl FnMut(&I) -> [T; N] + Send + Sync + 'static) -> Self
+    where
+        T: Send,
+        I: Send,
+    {
+        let keyed = items.into_iter().map(|item| (keyfn(&item), item)).collect();
+        let mut blkdb = Self::from_keyed(keyed);
+        blkdb.keyfn = Some(Box::new(keyfn));
+        blkdb
+    }
 
-        for item in items {
+    /// Builds the tree from items whose keys were already computed, e.g. in
+    /// parallel. Of equally close items searches find the earliest in
+    /// `items`.
+    pub fn from_keyed(items: Vec<([T; N], I)>) -> Self
+    where
+        T: Send,
+        I: Send,
+    {
+        let mut nodes: Vec<Box<Node<T, I, N>>> = Vec::with_capacity(items.len());
+
+        for (order, (key, item)) in items.into_iter().enumerate() {
             let n = Node {
-                key: keyfn(&item),
-                item: item,
-                dim: Dimension::First,
+                key,
+                item,
+                dim: 0,
+                order,
                 right: None,
                 left: None,
             };
             nodes.push(Box::from(n));
         }
-        return BlockDb {
-            root: Self::build_tree(nodes, Dimension::First),
+        let added = nodes.len();
+        Self::from_nodes(nodes, added)
+    }
+
+    fn from_nodes(nodes: Vec<Box<Node<T, I, N>>>, added: usize) -> Self
+    where
+        T: Send,
+        I: Send,
+    {
+        let len = nodes.len();
+        let mut blkdb = BlockDb {
+            root: Self::build_tree(nodes, 0),
+            len,
+            levels: 0,
+            max_balance_factor: MAX_BALANCE_FACTOR,
+            added,
+            keyfn: None,
         };
+        blkdb.levels = blkdb.depth();
+        blkdb
     }
 
-    fn build_tree(mut nodes: Vec<Box<Node<T, I>>>, dim: Dimension) -> Option<Box<Node<T, I>>> {
-        if nodes.len() < 2 {
-            return nodes.pop().map(|mut n| {
-                n.dim = dim;
-                n
-            });
+    /// Adds an item keyed by the key function the tree was built with, see
+    /// `insert_keyed`.
+    ///
+    /// # Panics
+    ///
+    /// If the tree wasn't built by `new`, so it has no key function.
+    #[allow(dead_code)]
+    pub fn insert(&mut self, item: I)
+    where
+        T: Send,
+        I: Send,
+    {
+        let keyfn = self
+            .keyfn
+            .as_mut()
+            .expect("only trees built by BlockDb::new have a key function");
+        let key = keyfn(&item);
+        self.insert_keyed(key, item);
+    }
+
+    /// Adds an item without building the tree anew: it becomes a leaf below
+    /// the nodes it falls on the side of. Inserted items don't balance the
+    /// tree, sorted ones make a list of it, so once its `balance_factor`
+    /// passes the maximum it is rebuilt.
+    #[allow(dead_code)]
+    pub fn insert_keyed(&mut self, key: [T; N], item: I)
+    where
+        T: Send,
+        I: Send,
+    {
+        let mut slot = &mut self.root;
+        let mut dim = 0;
+        let mut depth = 1;
+        while let Some(node) = slot {
+            depth += 1;
+            // the left subtree holds the larger keys of the split dimension
+            slot = if key[node.dim] < node.key[node.dim] {
+                &mut node.right
+            } else {
+                &mut node.left
+            };
+            dim = (node.dim + 1) % N;
+        }
+        *slot = Some(Box::new(Node {
+            key,
+            item,
+            dim,
+            order: self.added,
+            right: None,
+            left: None,
+        }));
+        self.len += 1;
+        self.added += 1;
+        self.levels = self.levels.max(depth);
+        if self.levels > DEPTH_SLACK && self.balance_factor() > self.max_balance_factor {
+            self.rebuild();
+        }
+    }
+
+    /// Builds the tree anew from the items in it, balanced. Which of
+    /// equally close items searches find stays the same.
+    pub fn rebuild(&mut self)
+    where
+        T: Send,
+        I: Send,
+    {
+        let mut nodes = Vec::with_capacity(self.len);
+        let mut stack: Vec<_> = self.root.take().into_iter().collect();
+        while let Some(mut node) = stack.pop() {
+            stack.extend(node.left.take());
+            stack.extend(node.right.take());
+            nodes.push(node);
+        }
+        let max_balance_factor = self.max_balance_factor;
+        let keyfn = self.keyfn.take();
+        *self = Self::from_nodes(nodes, self.added);
+        self.max_balance_factor = max_balance_factor;
+        self.keyfn = keyfn;
+    }
+
+    /// How many times as many levels the tree may have as a balanced one
+    /// would, 1 for a balanced tree. Removals aren't taken into account
+    /// until the tree is rebuilt.
+    pub fn balance_factor(&self) -> f64 {
+        let balanced = (usize::BITS - self.len.leading_zeros()).max(1);
+        self.levels.max(1) as f64 / f64::from(balanced)
+    }
+
+    /// Sets the `balance_factor` past which inserts rebuild the tree,
+    /// `f64::INFINITY` leaves rebuilding to the caller.
+    #[allow(dead_code)]
+    pub fn set_max_balance_factor(&mut self, factor: f64) {
+        self.max_balance_factor = factor;
+    }
+
+    /// The keys and items in the tree, the left subtree of every node
+    /// before the node and its right subtree.
+    #[allow(dead_code)]
+    pub fn iter(&self) -> Iter<'_, T, I, N> {
+        let mut iter = Iter {
+            stack: Vec::with_capacity(self.depth()),
+        };
+        iter.push_left(self.root.as_deref());
+        iter
+    }
+
+    /// Takes out an item with exactly `key`, if there is one. Its node is
+    /// replaced by the one with the smallest key component it splits by from
+    /// the left, or failing that the largest from the right, which keeps
+    /// the larger keys left and the smaller ones right.
+    #[allow(dead_code)]
+    pub fn remove(&mut self, key: [T; N]) -> Option<I> {
+        let path = Self::path_to(self.root.as_deref()?, &key)?;
+        let removed = Self::remove_at(Self::slot_at(&mut self.root, &path));
+        self.len -= 1;
+        Some(removed.item)
+    }
+
+    /// The turns from `root` to a node with `key`, `true` for left. Left
+    /// subtrees are searched before right ones.
+    fn path_to(root: &Node<T, I, N>, key: &[T; N]) -> Option<Vec<bool>> {
+        let mut steps = Vec::new();
+        let mut stack = vec![(root, None)];
+        while let Some((node, step)) = stack.pop() {
+            let index = steps.len();
+            steps.push(step);
+            if node.key == *key {
+                return Some(Self::turns(&steps, index));
+            }
+            // equal components may have gone either way
+            let dim = node.dim;
+            if key[dim] <= node.key[dim] {
+                stack.extend(node.right.as_deref().map(|r| (r, Some((index, false)))));
+            }
+            if key[dim] >= node.key[dim] {
+                stack.extend(node.left.as_deref().map(|l| (l, Some((index, true)))));
+            }
         }
-        let mut left = nodes;
-        let index: usize = dim.into();
-        let median = left.len() / 2;
-        left.sort_by(|a, b| {
-            if b.key[index] < a.key[index] {
-                std::cmp::Ordering::Less
+        None
+    }
+
+    /// The turns to the node visited `index`th, given for every visited
+    /// node the index of its parent and whether it is the left child.
+    fn turns(steps: &[Option<(usize, bool)>], mut index: usize) -> Vec<bool> {
+        let mut path = Vec::new();
+        while let Some((parent, left)) = steps[index] {
+            path.push(left);
+            index = parent;
+        }
+        path.reverse();
+        path
+    }
+
+    fn slot_at<'a>(
+        mut slot: &'a mut Option<Box<Node<T, I, N>>>,
+        path: &[bool],
+    ) -> &'a mut Option<Box<Node<T, I, N>>> {
+        for &left in path {
+            let node = slot.as_mut().expect("paths lead to nodes");
+            slot = if left {
+                &mut node.left
             } else {
-                std::cmp::Ordering::Greater
+                &mut node.right
+            };
+        }
+        slot
+    }
+
+    /// Unlinks the node in `slot`, returning it without its subtrees. Its
+    /// place is taken by the node with the smallest key component it splits
+    /// by from the left, or failing that the largest from the right; that
+    /// one's place by the same, and so on until a leaf is unlinked.
+    fn remove_at(mut slot: &mut Option<Box<Node<T, I, N>>>) -> Box<Node<T, I, N>> {
+        loop {
+            if slot.as_ref().expect("only nodes are removed").is_leaf() {
+                return slot.take().expect("only nodes are removed");
             }
-        });
-        let right = left.split_off(median);
-        let mut curr = left.pop()?;
-        curr.left = Self::build_tree(left, dim.next());
-        curr.right = Self::build_tree(right, dim.next());
-        curr.dim = dim;
-        return Some(curr);
+            let node = slot.as_deref_mut().expect("only nodes are removed");
+            let (subtree, smallest) = if node.left.is_some() {
+                (&mut node.left, true)
+            } else {
+                (&mut node.right, false)
+            };
+            let root = subtree.as_deref().expect("only nodes are removed");
+            let (_, path) = Self::extreme_path(root, node.dim, smallest);
+            let next = Self::slot_at(subtree, &path);
+            // the removed contents move down to where the replacement was
+            let replacement = next.as_deref_mut().expect("paths lead to nodes");
+            std::mem::swap(&mut node.key, &mut replacement.key);
+            std::mem::swap(&mut node.item, &mut replacement.item);
+            std::mem::swap(&mut node.order, &mut replacement.order);
+            slot = next;
+        }
     }
 
-    pub fn find_closest_pos(&self, pos: [T; 3]) -> Option<&I> {
-        self.root
-            .as_ref()
-            .map(|root| &Self::find_closest(root, pos).item)
+    /// The smallest or largest key component `dim` below `root` and the
+    /// turns to it, of equal ones the first with left subtrees searched
+    /// before right ones.
+    fn extreme_path(root: &Node<T, I, N>, dim: usize, smallest: bool) -> (T, Vec<bool>) {
+        let beats = |a: T, b: T| if smallest { a < b } else { a > b };
+        let mut best = (root.key[dim], 0);
+        let mut steps = Vec::new();
+        let mut stack = vec![(root, None)];
+        while let Some((node, step)) = stack.pop() {
+            let index = steps.len();
+            steps.push(step);
+            if beats(node.key[dim], best.0) {
+                best = (node.key[dim], index);
+            }
+            for (left, child) in [(false, &node.right), (true, &node.left)] {
+                // splitting by `dim` leaves the larger components left
+                if node.dim == dim && left == smallest {
+                    continue;
+                }
+                stack.extend(child.as_deref().map(|c| (c, Some((index, left)))));
+            }
+        }
+        (best.0, Self::turns(&steps, best.1))
     }
 
-    fn find_closest(node: &Node<T, I>, pos: [T; 3]) -> &Node<T, I> {
-        if node.is_leaf() {
-            return node;
-        };
-        let index = node.dim as usize;
-        let is_less = pos[index] < node.key[index];
-        let best = if is_less {
-            node.left
-                .as_ref()
-                .map_or(node, |l| Self::find_closest(l, pos))
-        } else {
-            node.right
-                .as_ref()
-                .map_or(node, |r| Self::find_closest(r, pos))
-        };
+    /// Number of items in the tree.
+    pub fn len(&self) -> usize {
+        self.len
+    }
 
-        let best = Self::pick_closer_node(&pos, best, node);
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
 
-        // If best distance intersects the boundary then search the other branch
-        let best = if Self::get_dist(node.dim, &node.key, &pos) < best.squared_dist(&pos) {
-            let best2 = if !is_less {
-                node.left
-                    .as_ref()
-                    .map_or(node, |l| Self::find_closest(l, pos))
+    /// Number of levels of the tree, 0 if it is empty.
+    pub fn depth(&self) -> usize {
+        let mut deepest = 0;
+        let mut stack: Vec<_> = self.root.iter().map(|root| (root, 1)).collect();
+        while let Some((node, level)) = stack.pop() {
+            deepest = deepest.max(level);
+            for child in node.left.iter().chain(&node.right) {
+                stack.push((child, level + 1));
+            }
+        }
+        deepest
+    }
+
+    /// Builds the subtrees of large nodes on separate threads, which gives
+    /// the same tree as building them one after the other. Smaller subtrees
+    /// are built from a stack of tasks instead of recursing, and splitting
+    /// at the median keeps the threads as few levels deep as a balanced
+    /// tree.
+    fn build_tree(nodes: Vec<Box<Node<T, I, N>>>, dim: usize) -> Option<Box<Node<T, I, N>>>
+    where
+        T: Send,
+        I: Send,
+    {
+        /// Building `nodes` into a subtree splitting by `dim` first, or
+        /// giving a node the two subtrees built last.
+        enum Task<T, I, const N: usize> {
+            Build(Vec<Box<Node<T, I, N>>>, usize),
+            Link(Box<Node<T, I, N>>),
+        }
+        let mut tasks = vec![Task::Build(nodes, dim)];
+        let mut built = Vec::new();
+        while let Some(task) = tasks.pop() {
+            let (mut left, dim) = match task {
+                Task::Build(nodes, dim) => (nodes, dim),
+                Task::Link(mut node) => {
+                    node.right = built.pop().expect("subtrees are built before linked");
+                    node.left = built.pop().expect("subtrees are built before linked");
+                    built.push(Some(node));
+                    continue;
+                }
+            };
+            if left.len() < 2 {
+                built.push(left.pop().map(|mut n| {
+                    n.dim = dim;
+                    n
+                }));
+                continue;
+            }
+            let index = dim;
+            let median = left.len() / 2;
+            // larger keys first, only the node at the split needs to be in place
+            left.select_nth_unstable_by(median - 1, |a, b| {
+                b.key[index]
+                    .partial_cmp(&a.key[index])
+                    .unwrap_or(Ordering::Equal)
+            });
+            let right = left.split_off(median);
+            let mut curr = left.pop().expect("the split leaves nodes left");
+            curr.dim = dim;
+            let next = (dim + 1) % N;
+            if right.len() < PARALLEL_BUILD_MIN {
+                // the left subtree is built first, so the right is on top
+                tasks.push(Task::Link(curr));
+                tasks.push(Task::Build(right, next));
+                tasks.push(Task::Build(left, next));
             } else {
-                node.right
-                    .as_ref()
-                    .map_or(node, |r| Self::find_closest(r, pos))
+                (curr.left, curr.right) = rayon::join(
+                    || Self::build_tree(left, next),
+                    || Self::build_tree(right, next),
+                );
+                built.push(Some(curr));
+            }
+        }
+        built.pop().expect("the whole tree is built last")
+    }
+
+    #[cfg(test)]
+    pub fn find_closest_pos(&self, pos: [T; N]) -> Option<&I> {
+        self.find_closest_with_dist(pos).map(|(item, _, _)| item)
+    }
+
+    /// The closest item to `pos` and its squared distance, found by going
+    /// through every key instead of searching the tree. Slow, but there is
+    /// nothing it could miss.
+    pub fn find_closest_brute(&self, pos: [T; N]) -> Option<(&I, T::Dist)> {
+        self.preorder()
+            .min_by(|a, b| cmp_rank(&a.rank(&pos), &b.rank(&pos)))
+            .map(|node| (&node.item, node.squared_dist(&pos)))
+    }
+
+    /// The item closest to `pos` with its key and squared distance, of
+    /// equally close ones the one added first.
+    pub fn find_closest_with_dist(&self, pos: [T; N]) -> Option<(&I, [T; N], T::Dist)> {
+        self.root.as_ref().map(|root| {
+            let node = Self::find_closest(root, pos);
+            (&node.item, node.key, node.squared_dist(&pos))
+        })
+    }
+
+    /// Searches the side of every node `pos` is on first, and the other one
+    /// unless the splitting plane is farther than the closest node found so
+    /// far, which an equally close one added earlier could be behind.
+    /// The nodes whose subtrees are being searched are kept on a stack,
+    /// each with the closest node of its first side once that is searched.
+    fn find_closest(root: &Node<T, I, N>, pos: [T; N]) -> &Node<T, I, N> {
+        let mut stack = Vec::new();
+        let mut descend = Some(root);
+        // the closest node of the subtree searched last
+        let mut found = root;
+        loop {
+            if let Some(node) = descend.take() {
+                #[cfg(test)]
+                VISITED.with(|visited| visited.set(visited.get() + 1));
+                if node.is_leaf() {
+                    found = node;
+                } else {
+                    stack.push((node, None));
+                    match Self::sides(node, &pos).0 {
+                        Some(near) => {
+                            descend = Some(near);
+                            continue;
+                        }
+                        None => found = node,
+                    }
+                }
+            }
+            let (node, near_best) = match stack.last_mut() {
+                Some(frame) => frame,
+                None => return found,
+            };
+            let node = *node;
+            found = match near_best {
+                None => {
+                    let best = Self::pick_closer_node(&pos, found, node);
+                    // If best distance intersects the boundary then search the other branch
+                    let plane = Self::get_dist(node.dim, &node.key, &pos);
+                    if plane * plane <= best.squared_dist(&pos) {
+                        if let Some(far) = Self::sides(node, &pos).1 {
+                            *near_best = Some(best);
+                            descend = Some(far);
+                            continue;
+                        }
+                        Self::pick_closer_node(&pos, best, node)
+                    } else {
+                        best
+                    }
+                }
+                Some(best) => Self::pick_closer_node(&pos, best, found),
             };
-            Self::pick_closer_node(&pos, best, best2)
+            found = Self::pick_closer_node(&pos, found, node);
+            stack.pop();
+        }
+    }
+
+    /// The subtrees of `node` on the side of `pos` and on the other side.
+    fn sides<'a>(
+        node: &'a Node<T, I, N>,
+        pos: &[T; N],
+    ) -> (Subtree<'a, T, I, N>, Subtree<'a, T, I, N>) {
+        // the left subtree holds the larger keys of the split dimension
+        let index = node.dim;
+        if pos[index] < node.key[index] {
+            (node.right.as_deref(), node.left.as_deref())
         } else {
-            best
-        };
+            (node.left.as_deref(), node.right.as_deref())
+        }
+    }
+
+    /// The `k` items closest to `pos` with their squared distances, closest
+    /// first, or all of them if there are fewer. Of items equally far away
+    /// the ones added first are kept, and come first.
+    pub fn find_k_closest(&self, pos: [T; N], k: usize) -> Vec<(&I, T::Dist)> {
+        self.k_closest_nodes(pos, k, 1.0, &[T::Dist::ONE; N], &|_| true)
+            .into_iter()
+            .map(|(d, n)| (&n.item, d))
+            .collect()
+    }
+
+    /// Like `find_k_closest`, but with the keys of the items instead of
+    /// their distances.
+    pub fn find_k_closest_keyed(&self, pos: [T; N], k: usize) -> Vec<(&[T; N], &I)> {
+        self.k_closest_nodes(pos, k, 1.0, &[T::Dist::ONE; N], &|_| true)
+            .into_iter()
+            .map(|(_, n)| (&n.key, &n.item))
+            .collect()
+    }
+
+    /// An item at most `1 + epsilon` times as far from `pos` as the closest
+    /// one. Skips the branches that can't hold anything that much closer,
+    /// which with long keys are most of them.
+    pub fn find_closest_approx(&self, pos: [T; N], epsilon: f64) -> Option<&I> {
+        let slack = (1.0 + epsilon).powi(2);
+        self.k_closest_nodes(pos, 1, slack, &[T::Dist::ONE; N], &|_| true)
+            .first()
+            .map(|(_, n)| &n.item)
+    }
+
+    /// The item closest to `pos` when the squared difference of each key
+    /// component counts `weights` times, with that distance. The weights
+    /// can differ from search to search of the same tree, a weight of 0
+    /// ignores the component.
+    ///
+    /// # Panics
+    ///
+    /// If a weight is negative.
+    #[allow(dead_code)]
+    pub fn find_closest_weighted(&self, pos: [T; N], weights: [T; N]) -> Option<(&I, T::Dist)> {
+        let weights = weights.map(KeyElem::widen);
+        assert!(
+            weights.iter().all(|&w| w >= T::Dist::ZERO),
+            "negative key weights"
+        );
+        self.k_closest_nodes(pos, 1, 1.0, &weights, &|_| true)
+            .first()
+            .map(|&(dist, n)| (&n.item, dist))
+    }
+
+    /// The closest item to `pos` that `accept` lets through, with its
+    /// squared distance. The tree is searched as usual, only the rejected
+    /// items are passed over; `None` if every item is rejected.
+    #[allow(dead_code)]
+    pub fn find_closest_where<F: Fn(&I) -> bool>(
+        &self,
+        pos: [T; N],
+        accept: F,
+    ) -> Option<(&I, T::Dist)> {
+        self.k_closest_nodes(pos, 1, 1.0, &[T::Dist::ONE; N], &accept)
+            .first()
+            .map(|&(dist, n)| (&n.item, dist))
+    }
 
-        Self::pick_closer_node(&pos, best, node)
+    /// Every item at most `max_sq_dist` from `pos`, squared, in no
+    /// particular order. Empty if nothing is that close.
+    #[allow(dead_code)]
+    pub fn find_within(&self, pos: [T; N], max_sq_dist: T::Dist) -> Vec<&I> {
+        let mut found = Vec::new();
+        if let (Some(root), true) = (&self.root, max_sq_dist >= T::Dist::ZERO) {
+            Self::collect_within(root, &pos, max_sq_dist, &mut found);
+        }
+        found
+    }
+
+    fn collect_within<'a>(
+        root: &'a Node<T, I, N>,
+        pos: &[T; N],
+        max_sq_dist: T::Dist,
+        found: &mut Vec<&'a I>,
+    ) {
+        let mut stack = vec![root];
+        while let Some(node) = stack.pop() {
+            if node.squared_dist(pos) <= max_sq_dist {
+                found.push(&node.item);
+            }
+            let (near, far) = Self::sides(node, pos);
+            let plane = Self::get_dist(node.dim, &node.key, pos);
+            if plane * plane <= max_sq_dist {
+                stack.extend(far);
+            }
+            stack.extend(near);
+        }
+    }
+
+    /// The `k` nodes closest to `pos` with their squared distances, closest
+    /// first, searched with `slack`, `weights` and `accept` as in
+    /// `collect_closest`.
+    fn k_closest_nodes(
+        &self,
+        pos: [T; N],
+        k: usize,
+        slack: f64,
+        weights: &[T::Dist; N],
+        accept: &impl Fn(&I) -> bool,
+    ) -> Vec<(T::Dist, &Node<T, I, N>)> {
+        let mut best = BinaryHeap::with_capacity(k.min(1024) + 1);
+        if let (Some(root), true) = (&self.root, k > 0) {
+            Self::collect_closest(root, &pos, k, slack, weights, accept, &mut best);
+        }
+        best.into_sorted_vec()
+            .into_iter()
+            .map(|c| (c.dist, c.node))
+            .collect()
+    }
+
+    /// Gathers the `k` nodes closest to `pos` into `best`, a max-heap whose
+    /// top is the farthest of them, only looking behind a splitting plane if
+    /// it is at most that one over `slack` away, both squared and with the
+    /// differences of each key component counting `weights` times. Nodes
+    /// whose item `accept` rejects are searched below but not gathered, so
+    /// they don't narrow the search either. Every node is kept on a stack
+    /// until the side of it `pos` is on has been searched, `true` once it
+    /// has, so its far side is looked at with the closest nodes found there.
+    fn collect_closest<'a>(
+        root: &'a Node<T, I, N>,
+        pos: &[T; N],
+        k: usize,
+        slack: f64,
+        weights: &[T::Dist; N],
+        accept: &impl Fn(&I) -> bool,
+        best: &mut BinaryHeap<Candidate<'a, T, I, N>>,
+    ) {
+        let mut stack = vec![(root, false)];
+        while let Some((node, near_searched)) = stack.pop() {
+            let (near, far) = Self::sides(node, pos);
+            if near_searched {
+                let plane = Self::get_dist(node.dim, &node.key, pos);
+                let plane = plane * plane * weights[node.dim];
+                let farthest = best.peek().map_or(T::Dist::MAX, |c| c.dist);
+                if best.len() < k || plane.to_f64() * slack <= farthest.to_f64() {
+                    stack.extend(far.map(|far| (far, false)));
+                }
+                continue;
+            }
+            #[cfg(test)]
+            VISITED.with(|visited| visited.set(visited.get() + 1));
+            let candidate = Candidate {
+                dist: weighted_dist(&node.key, pos, weights),
+                node,
+            };
+            let closer = best.len() < k || best.peek().map_or(true, |top| candidate < *top);
+            if closer && accept(&node.item) {
+                best.push(candidate);
+                if best.len() > k {
+                    best.pop();
+                }
+            }
+            if far.is_some() {
+                stack.push((node, true));
+            }
+            stack.extend(near.map(|near| (near, false)));
+        }
     }
 
     fn pick_closer_node<'a>(
-        pos: &[T; 3],
-        n1: &'a Node<T, I>,
-        n2: &'a Node<T, I>,
-    ) -> &'a Node<T, I> {
-        if n1.squared_dist(pos) < n2.squared_dist(pos) {
+        pos: &[T; N],
+        n1: &'a Node<T, I, N>,
+        n2: &'a Node<T, I, N>,
+    ) -> &'a Node<T, I, N> {
+        if cmp_rank(&n1.rank(pos), &n2.rank(pos)) == Ordering::Less {
             n1
         } else {
             n2
         }
     }
 
-    fn get_dist(dim: Dimension, k1: &[T; 3], k2: &[T; 3]) -> i64 {
-        let n1 = k1[dim as usize];
-        let n2 = k2[dim as usize];
+    fn get_dist(dim: usize, k1: &[T; N], k2: &[T; N]) -> T::Dist {
+        let n1 = k1[dim].widen();
+        let n2 = k2[dim].widen();
         if n1 > n2 {
-            n1.into() - n2.into()
+            n1 - n2
         } else {
-            n2.into() - n1.into()
+            n2 - n1
         }
     }
 }
 
-impl<T, I> BlockDb<T, I>
+/// Borrowing iterator over a `BlockDb`, see `BlockDb::iter`. It keeps the
+/// nodes still to be visited on a stack, so deep trees don't overflow the
+/// call stack.
+pub struct Iter<'a, T, I, const N: usize> {
+    stack: Vec<&'a Node<T, I, N>>,
+}
+
+impl<'a, T, I, const N: usize> Iter<'a, T, I, N> {
+    fn push_left(&mut self, mut node: Option<&'a Node<T, I, N>>) {
+        while let Some(n) = node {
+            self.stack.push(n);
+            node = n.left.as_deref();
+        }
+    }
+}
+
+impl<'a, T, I, const N: usize> Iterator for Iter<'a, T, I, N> {
+    type Item = (&'a [T; N], &'a I);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        self.push_left(node.right.as_deref());
+        Some((&node.key, &node.item))
+    }
+}
+
+impl<'a, T: KeyElem, I, const N: usize> IntoIterator for &'a BlockDb<T, I, N> {
+    type Item = (&'a [T; N], &'a I);
+    type IntoIter = Iter<'a, T, I, N>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Consuming iterator over a `BlockDb`, in the order of `BlockDb::iter`.
+pub struct IntoIter<T, I, const N: usize> {
+    stack: Vec<Box<Node<T, I, N>>>,
+}
+
+impl<T, I, const N: usize> IntoIter<T, I, N> {
+    fn push_left(&mut self, mut node: Option<Box<Node<T, I, N>>>) {
+        while let Some(mut n) = node {
+            node = n.left.take();
+            self.stack.push(n);
+        }
+    }
+}
+
+impl<T, I, const N: usize> Iterator for IntoIter<T, I, N> {
+    type Item = ([T; N], I);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut node = self.stack.pop()?;
+        self.push_left(node.right.take());
+        Some((node.key, node.item))
+    }
+}
+
+impl<T, I, const N: usize> IntoIterator for BlockDb<T, I, N> {
+    type Item = ([T; N], I);
+    type IntoIter = IntoIter<T, I, N>;
+
+    fn into_iter(mut self) -> Self::IntoIter {
+        let mut iter = IntoIter { stack: Vec::new() };
+        iter.push_left(self.root.take());
+        iter
+    }
+}
+
+impl<T, I, const N: usize> Drop for BlockDb<T, I, N> {
+    fn drop(&mut self) {
+        drop_nodes(self.root.take().into_iter().collect());
+    }
+}
+
+impl<T, I, const N: usize> Drop for IntoIter<T, I, N> {
+    fn drop(&mut self) {
+        drop_nodes(std::mem::take(&mut self.stack));
+    }
+}
+
+/// Drops `nodes` and their subtrees one node at a time, where dropping
+/// the boxes would recurse as deep as the subtrees go.
+fn drop_nodes<T, I, const N: usize>(mut nodes: Vec<Box<Node<T, I, N>>>) {
+    while let Some(mut node) = nodes.pop() {
+        nodes.extend(node.left.take());
+        nodes.extend(node.right.take());
+    }
+}
+
+#[allow(dead_code)]
+impl<T, I, const N: usize> BlockDb<T, I, N>
 where
     T: Display,
 {
@@ -205,32 +995,302 @@ where
         let mut out = String::new();
         out.push_str("graph rtree {\n");
         if let Some(root) = &self.root {
-            Self::to_dot(&root, &mut out, 0);
+            Self::to_dot(root, &mut out);
         }
-        out.push_str("}");
-        return out;
+        out.push('}');
+        out
     }
 
-    fn to_dot(node: &Node<T, I>, w: &mut dyn Write, id: u64) -> u64 {
-        writeln!(
-            w,
-            "{} [label=\"{}@({},{},{})\"]",
-            id, node.dim as usize, node.key[0], node.key[1], node.key[2]
-        )
-        .unwrap();
-        let mut next_id = id + 1;
-        if let Some(l) = &node.left {
-            writeln!(w, "{} -- {} [label=\"left\"]", id, next_id).unwrap();
-            next_id = Self::to_dot(l, w, next_id);
+    /// Numbers the nodes below `root` in the order they are written, every
+    /// node before its left subtree and that before its right one.
+    fn to_dot(root: &Node<T, I, N>, w: &mut dyn Write) {
+        let mut stack = vec![(None, root)];
+        let mut id = 0u64;
+        while let Some((parent, node)) = stack.pop() {
+            if let Some((parent, side)) = parent {
+                writeln!(w, "{} -- {} [label=\"{}\"]", parent, id, side).unwrap();
+            }
+            writeln!(w, "{} [label=\"{}@({})\"]", id, node.dim, join(&node.key)).unwrap();
+            if let Some(r) = &node.right {
+                stack.push((Some((id, "right")), r));
+            }
+            if let Some(l) = &node.left {
+                stack.push((Some((id, "left")), l));
+            }
+            id += 1;
         }
-        if let Some(r) = &node.right {
-            writeln!(w, "{} -- {} [label=\"right\"]", id, next_id).unwrap();
-            next_id = Self::to_dot(r, w, next_id);
+    }
+}
+
+/// A node as it is serialized, without its subtrees but with whether it
+/// has them.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct FlatNode<K, I> {
+    key: K,
+    item: I,
+    dim: usize,
+    order: usize,
+    left: bool,
+    right: bool,
+}
+
+/// Serializes the nodes as a sequence, every node before its left subtree
+/// and that before its right one, so deep trees don't recurse.
+#[cfg(feature = "serde")]
+impl<T, I, const N: usize> Serialize for BlockDb<T, I, N>
+where
+    T: Serialize,
+    I: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.len))?;
+        for node in self.preorder() {
+            seq.serialize_element(&FlatNode {
+                key: &node.key[..],
+                item: &node.item,
+                dim: node.dim,
+                order: node.order,
+                left: node.left.is_some(),
+                right: node.right.is_some(),
+            })?;
         }
-        return next_id;
+        seq.end()
     }
 }
 
+/// Puts the tree back together exactly as it was serialized, so it answers
+/// every search the same way.
+#[cfg(feature = "serde")]
+impl<'de, T, I, const N: usize> Deserialize<'de> for BlockDb<T, I, N>
+where
+    T: KeyElem + Deserialize<'de>,
+    I: Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let flat: Vec<FlatNode<Vec<T>, I>> = Vec::deserialize(deserializer)?;
+        let mut nodes = Vec::with_capacity(flat.len());
+        for node in flat {
+            let key_len = node.key.len();
+            let key = node.key.try_into().map_err(|_| {
+                de::Error::invalid_length(key_len, &format!("keys of {} components", N).as_str())
+            })?;
+            nodes.push(FlatNode {
+                key,
+                item: node.item,
+                dim: node.dim,
+                order: node.order,
+                left: node.left,
+                right: node.right,
+            });
+        }
+        Self::assemble(nodes).map_err(de::Error::custom)
+    }
+}
+
+impl<T, I, const N: usize> BlockDb<T, I, N> {
+    /// Every node before its left subtree, and that before its right one.
+    fn preorder(&self) -> impl Iterator<Item = &Node<T, I, N>> {
+        let mut stack: Vec<&Node<T, I, N>> = self.root.as_deref().into_iter().collect();
+        std::iter::from_fn(move || {
+            let node = stack.pop()?;
+            stack.extend(node.right.as_deref());
+            stack.extend(node.left.as_deref());
+            Some(node)
+        })
+    }
+}
+
+impl<T: KeyElem, I, const N: usize> BlockDb<T, I, N> {
+    /// Puts a tree back together from its nodes in `preorder`, without
+    /// recursing.
+    fn assemble(nodes: Vec<FlatNode<[T; N], I>>) -> Result<Self, String> {
+        let len = nodes.len();
+        let added = nodes.iter().map(|node| node.order.saturating_add(1)).max();
+        // in reverse every node comes after its subtrees, the left one last
+        let mut subtrees = Vec::new();
+        for node in nodes.into_iter().rev() {
+            if node.dim >= N {
+                return Err(format!("no key component {}", node.dim));
+            }
+            let mut subtree = |has: bool| {
+                if !has {
+                    return Ok(None);
+                }
+                subtrees
+                    .pop()
+                    .map(Some)
+                    .ok_or_else(|| "a subtree is missing".to_string())
+            };
+            let left = subtree(node.left)?;
+            let right = subtree(node.right)?;
+            subtrees.push(Box::new(Node {
+                key: node.key,
+                item: node.item,
+                dim: node.dim,
+                order: node.order,
+                left,
+                right,
+            }));
+        }
+        if subtrees.len() > 1 {
+            return Err("nodes are left over".to_string());
+        }
+        let mut blkdb = BlockDb {
+            root: subtrees.pop(),
+            len,
+            levels: 0,
+            max_balance_factor: MAX_BALANCE_FACTOR,
+            added: added.unwrap_or(0),
+            keyfn: None,
+        };
+        blkdb.levels = blkdb.depth();
+        Ok(blkdb)
+    }
+
+    /// Writes the tree in a binary format `load_from` reads back: a header
+    /// with the format version, the size and type of keys and items and the
+    /// number of nodes, then the nodes in `preorder` and a checksum.
+    pub fn save_to<W: io::Write>(&self, mut w: W) -> io::Result<()>
+    where
+        T: Record,
+        I: Record,
+    {
+        let node_bytes = N * T::BYTES + 11 + I::BYTES;
+        let mut out = Vec::with_capacity(32 + self.len * node_bytes);
+        out.extend_from_slice(FILE_MAGIC);
+        out.extend_from_slice(&FILE_VERSION.to_le_bytes());
+        out.push(T::BYTES as u8);
+        out.push(T::TAG);
+        out.extend_from_slice(&(N as u16).to_le_bytes());
+        out.extend_from_slice(&(I::BYTES as u32).to_le_bytes());
+        out.push(I::TAG);
+        out.extend_from_slice(&(self.len as u64).to_le_bytes());
+        for node in self.preorder() {
+            for component in &node.key {
+                component.write(&mut out);
+            }
+            out.extend_from_slice(&(node.dim as u16).to_le_bytes());
+            out.push(u8::from(node.left.is_some()) | u8::from(node.right.is_some()) << 1);
+            out.extend_from_slice(&(node.order as u64).to_le_bytes());
+            node.item.write(&mut out);
+        }
+        let checksum = stable_hash_bytes(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        w.write_all(&out)
+    }
+
+    /// Reads a tree written by `save_to`, the same as it was saved. Other
+    /// format versions, key and item types and damaged or cut off files are
+    /// refused with an `InvalidData` error.
+    pub fn load_from<R: io::Read>(mut r: R) -> io::Result<Self>
+    where
+        T: Record,
+        I: Record,
+    {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        let mut rest = &bytes[..];
+        if take(&mut rest, FILE_MAGIC.len()).ok() != Some(&FILE_MAGIC[..]) {
+            return Err(invalid("not a tile database".to_string()));
+        }
+        let version = u16::from_le_bytes(take_array(&mut rest)?);
+        if version != FILE_VERSION {
+            return Err(invalid(format!(
+                "database format {}, this version of collagen reads format {}",
+                version, FILE_VERSION
+            )));
+        }
+        let (body, checksum) = bytes.split_at(bytes.len().saturating_sub(8).max(10));
+        if checksum.len() != 8 || stable_hash_bytes(body).to_le_bytes() != checksum {
+            return Err(invalid("the database is damaged or cut off".to_string()));
+        }
+        let mut rest = &body[10..];
+        let [key_bytes, key_tag] = take_array(&mut rest)?;
+        let key_bytes = usize::from(key_bytes);
+        let key_len = usize::from(u16::from_le_bytes(take_array(&mut rest)?));
+        if (key_bytes, key_len) != (T::BYTES, N) {
+            return Err(invalid(format!(
+                "keys of {} components of {} bytes, not {} of {}",
+                key_len,
+                key_bytes,
+                N,
+                T::BYTES
+            )));
+        }
+        if key_tag != T::TAG {
+            return Err(invalid(format!("keys of type {}, not {}", key_tag, T::TAG)));
+        }
+        let item_bytes = u32::from_le_bytes(take_array(&mut rest)?) as usize;
+        let [item_tag] = take_array(&mut rest)?;
+        if (item_bytes, item_tag) != (I::BYTES, I::TAG) {
+            return Err(invalid(format!(
+                "items of {} bytes and type {}, not {} and {}",
+                item_bytes,
+                item_tag,
+                I::BYTES,
+                I::TAG
+            )));
+        }
+        let len = u64::from_le_bytes(take_array(&mut rest)?);
+        let node_bytes = N * T::BYTES + 11 + I::BYTES;
+        if len.checked_mul(node_bytes as u64) != Some(rest.len() as u64) {
+            return Err(invalid(format!(
+                "{} bytes can't hold {} nodes",
+                rest.len(),
+                len
+            )));
+        }
+        let mut nodes = Vec::with_capacity(len as usize);
+        for bytes in rest.chunks_exact(node_bytes) {
+            let (key, bytes) = bytes.split_at(N * T::BYTES);
+            let key: Option<Vec<T>> = key.chunks_exact(T::BYTES).map(T::read).collect();
+            let key = key.and_then(|key| key.try_into().ok());
+            let order = u64::from_le_bytes(bytes[3..11].try_into().expect("8 bytes"));
+            let item = I::read(&bytes[11..]);
+            let (key, item) = key
+                .zip(item)
+                .ok_or_else(|| invalid("a node holds an invalid key or item".to_string()))?;
+            nodes.push(FlatNode {
+                key,
+                item,
+                dim: usize::from(u16::from_le_bytes([bytes[0], bytes[1]])),
+                order: order
+                    .try_into()
+                    .map_err(|_| invalid(format!("item number {} is too large", order)))?,
+                left: bytes[2] & 1 != 0,
+                right: bytes[2] & 2 != 0,
+            });
+        }
+        Self::assemble(nodes).map_err(invalid)
+    }
+}
+
+/// Splits the first `n` bytes off `rest`.
+fn take<'a>(rest: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if rest.len() < n {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the database is cut off",
+        ));
+    }
+    let (taken, left) = rest.split_at(n);
+    *rest = left;
+    Ok(taken)
+}
+
+fn take_array<const L: usize>(rest: &mut &[u8]) -> io::Result<[u8; L]> {
+    Ok(take(rest, L)?.try_into().expect("took L bytes"))
+}
+
+/// The components of a key separated by commas.
+fn join<T: Display>(key: &[T]) -> String {
+    key.iter()
+        .map(|k| k.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
 #[test]
 fn test_r_tree() {
     let coords: Vec<(i64, i64, i64)> = vec![
@@ -276,8 +1336,15 @@ fn test_r_tree() {
 }
 
 use quickcheck_macros::quickcheck;
+#[cfg(test)]
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+#[cfg(test)]
+use std::sync::Arc;
 
 #[quickcheck]
+#[allow(clippy::unnecessary_fold)]
 fn points_are_found_without_overflow(points: Vec<(i16, i16, i16)>) -> bool {
     let blkdb = BlockDb::new(points.clone(), |x| [x.0, x.1, x.2]);
     points
@@ -291,3 +1358,998 @@ fn points_are_found_without_overflow(points: Vec<(i16, i16, i16)>) -> bool {
         })
         .fold(true, |x, y| x && y)
 }
+
+#[quickcheck]
+fn k_closest_matches_brute_force(points: Vec<(i16, i16, i16)>, pos: (i16, i16, i16)) -> bool {
+    let key = |x: &(i16, i16, i16)| [x.0, x.1, x.2];
+    let pos = key(&pos);
+    let dist = |p: &(i16, i16, i16)| squared_dist(&key(p), &pos);
+    let blkdb = BlockDb::new(points.clone(), key);
+    let found: Vec<i64> = blkdb
+        .find_k_closest(pos, 5)
+        .iter()
+        .map(|(_, d)| *d)
+        .collect();
+    let mut expected: Vec<i64> = points.iter().map(dist).collect();
+    expected.sort_unstable();
+    expected.truncate(5);
+    found == expected
+}
+
+#[quickcheck]
+fn within_matches_brute_force(
+    points: Vec<(i16, i16, i16)>,
+    pos: (i16, i16, i16),
+    radius: u16,
+) -> bool {
+    let key = |x: &(i16, i16, i16)| [x.0, x.1, x.2];
+    let pos = key(&pos);
+    let dist = |p: &(i16, i16, i16)| squared_dist(&key(p), &pos);
+    let max_sq_dist = i64::from(radius).pow(2);
+    let blkdb = BlockDb::new(points.clone(), key);
+    let mut found: Vec<_> = blkdb
+        .find_within(pos, max_sq_dist)
+        .into_iter()
+        .copied()
+        .collect();
+    let mut expected: Vec<_> = points
+        .into_iter()
+        .filter(|p| dist(p) <= max_sq_dist)
+        .collect();
+    found.sort_unstable();
+    expected.sort_unstable();
+    found == expected
+}
+
+#[test]
+fn nothing_is_within_an_unreachable_radius() {
+    let blkdb = BlockDb::new(vec![[10i16, 10, 10], [10, 10, 10]], |p| *p);
+    assert!(blkdb.find_within([0, 0, 0], 299).is_empty());
+    assert_eq!(blkdb.find_within([0, 0, 0], 300).len(), 2);
+    assert!(blkdb.find_within([10, 10, 10], -1).is_empty());
+    assert!(BlockDb::<i16, (), 3>::new(Vec::new(), |_| [0; 3])
+        .find_within([0; 3], 100)
+        .is_empty());
+}
+
+#[quickcheck]
+fn closest_distance_matches_its_key(points: Vec<(i16, i16, i16)>, pos: (i16, i16, i16)) -> bool {
+    let pos = [pos.0, pos.1, pos.2];
+    let blkdb = BlockDb::new(points, |x| [x.0, x.1, x.2]);
+    match blkdb.find_closest_with_dist(pos) {
+        None => blkdb.find_closest_pos(pos).is_none(),
+        Some((item, key, dist)) => {
+            let brute = (0..3)
+                .map(|i| (i64::from(key[i]) - i64::from(pos[i])).pow(2))
+                .sum::<i64>();
+            key == [item.0, item.1, item.2]
+                && dist == brute
+                && blkdb.find_k_closest(pos, 1)[0].1 == dist
+        }
+    }
+}
+
+#[test]
+fn inserted_items_are_found() {
+    let mut rng = StdRng::seed_from_u64(82);
+    let mut blkdb = BlockDb::new(Vec::new(), |p: &[i16; 3]| *p);
+    let mut points = Vec::new();
+    for i in 0..3000 {
+        let point: [i16; 3] = std::array::from_fn(|_| rng.gen_range(-500..500));
+        blkdb.insert(point);
+        points.push(point);
+        assert_eq!(blkdb.len(), points.len());
+        if i % 7 == 0 {
+            let pos: [i16; 3] = std::array::from_fn(|_| rng.gen_range(-500..500));
+            let dist = |p: &[i16; 3]| squared_dist(p, &pos);
+            let (_, _, closest) = blkdb.find_closest_with_dist(pos).unwrap();
+            assert_eq!(Some(closest), points.iter().map(dist).min());
+            let within = blkdb.find_within(pos, 10_000).len();
+            assert_eq!(within, points.iter().filter(|p| dist(p) <= 10_000).count());
+        }
+    }
+    assert!(blkdb.depth() <= 3 * 12, "depth {}", blkdb.depth());
+}
+
+#[test]
+fn sorted_inserts_keep_the_tree_shallow() {
+    let mut blkdb = BlockDb::new(vec![0i16], |&i| [i; 3]);
+    for i in 1..2000 {
+        blkdb.insert(i);
+    }
+    assert!(blkdb.depth() <= 3 * 11, "depth {}", blkdb.depth());
+    assert_eq!(blkdb.find_closest_pos([1500, 1500, 1501]), Some(&1500));
+    assert_eq!(blkdb.find_within([0, 0, 0], 12).len(), 3);
+}
+
+#[test]
+fn removed_items_are_gone() {
+    let mut rng = StdRng::seed_from_u64(83);
+    for size in [1, 2, 5, 40, 300] {
+        let points: Vec<[i16; 3]> = clustered_keys(&mut rng, size, -6..6);
+        let mut blkdb = BlockDb::new(points.clone(), |p| *p);
+        let mut left = points.clone();
+        let mut order = points;
+        order.shuffle(&mut rng);
+        for point in order {
+            assert_eq!(blkdb.remove(point), Some(point));
+            let gone = left.iter().position(|p| *p == point).unwrap();
+            left.swap_remove(gone);
+            for _ in 0..5 {
+                let pos: [i16; 3] = std::array::from_fn(|_| rng.gen_range(-8..8));
+                let dist = |p: &[i16; 3]| squared_dist(p, &pos);
+                let closest = blkdb.find_closest_with_dist(pos).map(|(_, _, d)| d);
+                assert_eq!(closest, left.iter().map(dist).min(), "size={}", size);
+                let within = blkdb.find_within(pos, 20).len();
+                assert_eq!(within, left.iter().filter(|p| dist(p) <= 20).count());
+            }
+        }
+        assert!(blkdb.remove([0, 0, 0]).is_none());
+        assert_eq!(blkdb.depth(), 0);
+        assert!(blkdb.is_empty());
+    }
+}
+
+#[test]
+fn missing_keys_remove_nothing() {
+    let mut blkdb = BlockDb::new(vec![[1i16, 2, 3], [4, 5, 6]], |p| *p);
+    assert!(blkdb.remove([1, 2, 4]).is_none());
+    assert_eq!(blkdb.find_within([0, 0, 0], 1000).len(), 2);
+    assert_eq!(blkdb.remove([4, 5, 6]), Some([4, 5, 6]));
+    assert_eq!(blkdb.find_closest_pos([9, 9, 9]), Some(&[1, 2, 3]));
+}
+
+#[test]
+fn iterators_yield_every_item_once() {
+    let mut rng = StdRng::seed_from_u64(85);
+    let mut points: Vec<[i16; 3]> = (0..500)
+        .map(|_| std::array::from_fn(|_| rng.gen_range(-10..10)))
+        .collect();
+    points.sort_unstable();
+    let keys = points.clone();
+    let balanced = BlockDb::new((0..points.len()).collect(), move |&i| keys[i]);
+    // sorted inserts make the deepest trees before they get rebuilt
+    let mut inserted = BlockDb::from_keyed(Vec::new());
+    for (i, point) in points.iter().enumerate() {
+        inserted.insert_keyed(*point, i);
+    }
+    let keys = points.clone();
+    let mut removed = BlockDb::new((0..points.len()).collect(), move |&i| keys[i]);
+    for point in &points[..250] {
+        removed.remove(*point);
+    }
+    for (blkdb, len) in [(balanced, 500), (inserted, 500), (removed, 250)] {
+        let borrowed: Vec<_> = (&blkdb).into_iter().map(|(k, &i)| (*k, i)).collect();
+        assert_eq!(borrowed.len(), len);
+        assert_eq!(borrowed.len(), blkdb.len());
+        assert!(borrowed.iter().all(|&(key, i)| key == points[i]));
+        let mut owned: Vec<_> = blkdb.into_iter().collect();
+        assert_eq!(owned, borrowed);
+        owned.sort_unstable_by_key(|&(_, i)| i);
+        owned.dedup_by_key(|&mut (_, i)| i);
+        assert_eq!(owned.len(), len);
+    }
+    let empty = BlockDb::<i16, (), 3>::from_keyed(Vec::new());
+    assert_eq!(empty.iter().count(), 0);
+}
+
+#[test]
+fn rebuilt_trees_are_searched_faster() {
+    let mut rng = StdRng::seed_from_u64(86);
+    let mut blkdb = BlockDb::from_keyed(Vec::new());
+    blkdb.set_max_balance_factor(f64::INFINITY);
+    // mostly sorted along the first component
+    for i in 0..100_000 {
+        let key = [
+            (i / 50) as i16,
+            rng.gen_range(0..100),
+            rng.gen_range(0..100),
+        ];
+        blkdb.insert_keyed(key, i);
+    }
+    let queries: Vec<[i16; 3]> = (0..100)
+        .map(|_| {
+            [
+                rng.gen_range(0..2000),
+                rng.gen_range(0..100),
+                rng.gen_range(0..100),
+            ]
+        })
+        .collect();
+    let visits = |blkdb: &BlockDb<i16, i32, 3>| {
+        VISITED.with(|visited| visited.set(0));
+        let found: Vec<_> = queries
+            .iter()
+            .map(|&pos| blkdb.find_closest_with_dist(pos).unwrap().2)
+            .collect();
+        (VISITED.with(|visited| visited.get()), found)
+    };
+    let (skewed, skewed_found) = visits(&blkdb);
+    // the default maximum would have rebuilt it
+    assert!(blkdb.balance_factor() > MAX_BALANCE_FACTOR);
+    blkdb.rebuild();
+    assert!(blkdb.balance_factor() < 1.5, "{}", blkdb.balance_factor());
+    assert_eq!(blkdb.len(), 100_000);
+    let (rebuilt, rebuilt_found) = visits(&blkdb);
+    assert_eq!(skewed_found, rebuilt_found);
+    assert!(
+        skewed >= 10 * rebuilt,
+        "{} visits, {} rebuilt",
+        skewed,
+        rebuilt
+    );
+}
+
+/// Checks the searches of a tree of random keys with `N` components against
+/// going through all of them.
+#[cfg(test)]
+fn searches_agree_with_brute_force<const N: usize>(seed: u64) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    for size in [1, 10, 1000] {
+        let points: Vec<[i16; N]> = (0..size)
+            .map(|_| std::array::from_fn(|_| rng.gen_range(-30..30)))
+            .collect();
+        let blkdb = BlockDb::new(points.clone(), |p| *p);
+        assert!(blkdb.to_dot_str().starts_with("graph"));
+        for _ in 0..20 {
+            let pos: [i16; N] = std::array::from_fn(|_| rng.gen_range(-40..40));
+            let dist = |p: &[i16; N]| squared_dist(p, &pos);
+            let mut dists: Vec<i64> = points.iter().map(dist).collect();
+            dists.sort_unstable();
+            let (item, key, closest) = blkdb.find_closest_with_dist(pos).unwrap();
+            assert_eq!((closest, dist(item), *item), (dists[0], closest, key));
+            let found: Vec<i64> = blkdb.find_k_closest(pos, 5).iter().map(|f| f.1).collect();
+            assert_eq!(found, dists[..size.min(5)], "N={} size={}", N, size);
+            let radius = dists[dists.len() / 2];
+            let within = blkdb.find_within(pos, radius).len();
+            assert_eq!(within, dists.iter().filter(|&&d| d <= radius).count());
+        }
+    }
+}
+
+#[test]
+fn keys_of_any_length_are_searched_exactly() {
+    searches_agree_with_brute_force::<1>(1);
+    searches_agree_with_brute_force::<3>(3);
+    searches_agree_with_brute_force::<12>(12);
+}
+
+#[test]
+fn key_functions_may_keep_state() {
+    fn key(p: &(i16, i16)) -> [i16; 2] {
+        [p.0, p.1]
+    }
+    let points = vec![(1, 2), (5, 5), (-3, 0)];
+    let by_fn = BlockDb::new(points.clone(), key);
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::clone(&calls);
+    let scale = 2;
+    let mut by_closure = BlockDb::new(points, move |p| {
+        counted.fetch_add(1, AtomicOrdering::Relaxed);
+        [p.0 * scale, p.1 * scale]
+    });
+    assert_eq!(calls.load(AtomicOrdering::Relaxed), 3);
+    assert_eq!(by_fn.find_closest_pos([4, 4]), Some(&(5, 5)));
+    assert_eq!(by_closure.find_closest_pos([4, 4]), Some(&(1, 2)));
+    // inserts key by the same closure
+    by_closure.insert((2, 2));
+    assert_eq!(calls.load(AtomicOrdering::Relaxed), 4);
+    assert_eq!(by_closure.find_closest_pos([4, 4]), Some(&(2, 2)));
+}
+
+#[quickcheck]
+fn closest_of_wide_keys_matches_brute_force(
+    points: Vec<(i32, i32, i32)>,
+    pos: (i32, i32, i32),
+) -> bool {
+    // a quarter of the range, so three squared differences fit an i64
+    let key = |p: &(i32, i32, i32)| [p.0 as i64 >> 2, p.1 as i64 >> 2, p.2 as i64 >> 2];
+    let pos = key(&pos);
+    let dist = |p: &(i32, i32, i32)| squared_dist(&key(p), &pos);
+    let blkdb = BlockDb::new(points.clone(), key);
+    let closest = points.iter().map(dist).min();
+    blkdb.find_closest_pos(pos).map(dist) == closest
+}
+
+#[quickcheck]
+fn closest_of_fractional_keys_matches_brute_force(
+    points: Vec<(i16, i16, i16)>,
+    pos: (i16, i16, i16),
+) -> bool {
+    // under 1/8, so the gaps shrink when squared
+    let key = |p: &(i16, i16, i16)| [p.0, p.1, p.2].map(|c| f32::from(c) / 262_144.0);
+    let pos = key(&pos);
+    let blkdb = BlockDb::new(points.clone(), key);
+    let closest = points
+        .iter()
+        .map(|p| squared_dist(&key(p), &pos))
+        .min_by(f64::total_cmp);
+    blkdb
+        .find_closest_pos(pos)
+        .map(|p| squared_dist(&key(p), &pos))
+        == closest
+}
+
+/// Whether the tree finds something as close to `pos` as going through
+/// every key does, which finds the closest of `points` itself.
+#[cfg(test)]
+fn closest_is_as_close_as_a_scan<T: KeyElem + Send>(points: Vec<[T; 3]>, pos: [T; 3]) -> bool {
+    let closest = points
+        .iter()
+        .map(|p| squared_dist(p, &pos))
+        .min_by(Distance::total_cmp);
+    let blkdb = BlockDb::new(points, |p| *p);
+    let scanned = blkdb.find_closest_brute(pos);
+    let searched = blkdb.find_closest_with_dist(pos);
+    scanned.map(|(p, dist)| (squared_dist(p, &pos), dist)) == closest.map(|d| (d, d))
+        && searched.map(|(_, _, dist)| dist) == closest
+}
+
+#[quickcheck]
+fn closest_i16_keys_are_as_close_as_a_scan(
+    points: Vec<(i16, i16, i16)>,
+    pos: (i16, i16, i16),
+) -> bool {
+    let key = |p: (i16, i16, i16)| [p.0, p.1, p.2];
+    closest_is_as_close_as_a_scan(points.into_iter().map(key).collect(), key(pos))
+}
+
+#[quickcheck]
+fn closest_i32_keys_are_as_close_as_a_scan(
+    points: Vec<(i32, i32, i32)>,
+    pos: (i32, i32, i32),
+) -> bool {
+    // a quarter of the range, so three squared differences fit an i64
+    let key = |p: (i32, i32, i32)| [p.0 >> 2, p.1 >> 2, p.2 >> 2];
+    closest_is_as_close_as_a_scan(points.into_iter().map(key).collect(), key(pos))
+}
+
+#[quickcheck]
+fn closest_i64_keys_are_as_close_as_a_scan(
+    points: Vec<(i64, i64, i64)>,
+    pos: (i64, i64, i64),
+) -> bool {
+    let key = |p: (i64, i64, i64)| [p.0 >> 34, p.1 >> 34, p.2 >> 34];
+    closest_is_as_close_as_a_scan(points.into_iter().map(key).collect(), key(pos))
+}
+
+#[quickcheck]
+fn closest_f32_keys_are_as_close_as_a_scan(
+    points: Vec<(f32, f32, f32)>,
+    pos: (f32, f32, f32),
+) -> bool {
+    // infinite components are infinitely far from each other
+    let key = |p: (f32, f32, f32)| [p.0, p.1, p.2].map(|c| if c.is_finite() { c } else { 0.0 });
+    closest_is_as_close_as_a_scan(points.into_iter().map(key).collect(), key(pos))
+}
+
+#[test]
+fn clustered_f32_keys_are_as_close_as_a_scan() {
+    // steps of a quarter, so the gaps to the planes are mostly under 1
+    let mut rng = StdRng::seed_from_u64(970);
+    for size in [1, 50, 5000] {
+        let points: Vec<[f32; 3]> = (0..size)
+            .map(|_| std::array::from_fn(|_| rng.gen_range(0..4) as f32 / 4.0))
+            .collect();
+        for _ in 0..50 {
+            let pos = std::array::from_fn(|_| rng.gen_range(-0.5..1.5));
+            assert!(
+                closest_is_as_close_as_a_scan(points.clone(), pos),
+                "{} keys",
+                size
+            );
+        }
+    }
+}
+
+#[test]
+fn clustered_keys_are_as_close_as_a_scan() {
+    // few distinct values, so most queries have ties
+    let mut rng = StdRng::seed_from_u64(97);
+    for size in [0, 1, 2, 50, 5000] {
+        let points: Vec<[i32; 3]> = (0..size)
+            .map(|_| std::array::from_fn(|_| rng.gen_range(0..4) * 10))
+            .collect();
+        for _ in 0..50 {
+            let pos = std::array::from_fn(|_| rng.gen_range(-5..40));
+            assert!(
+                closest_is_as_close_as_a_scan(points.clone(), pos),
+                "{} keys",
+                size
+            );
+        }
+    }
+}
+
+/// `len` keys with components of the few `values`, so plenty of them are
+/// duplicates.
+#[cfg(test)]
+fn clustered_keys<const N: usize>(
+    rng: &mut StdRng,
+    len: usize,
+    values: std::ops::Range<i16>,
+) -> Vec<[i16; N]> {
+    (0..len)
+        .map(|_| std::array::from_fn(|_| rng.gen_range(values.clone())))
+        .collect()
+}
+
+/// Keys with two values per component, so each of them is shared by about
+/// a thousandth of `len` items, which are numbered in the order given.
+#[cfg(test)]
+fn duplicated_keys(len: usize, seed: u64) -> Vec<([i16; 3], i64)> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..len as i64)
+        .map(|i| (std::array::from_fn(|_| rng.gen_range(0..2) * 10), i))
+        .collect()
+}
+
+/// Checks that of equally close items the searches of `blkdb` find the
+/// lowest numbered one in `items`.
+#[cfg(test)]
+fn first_added_wins(blkdb: &BlockDb<i16, i64>, items: &[([i16; 3], i64)]) {
+    let mut rng = StdRng::seed_from_u64(99);
+    for _ in 0..100 {
+        let pos: [i16; 3] = std::array::from_fn(|_| rng.gen_range(-5..15));
+        let mut ranked: Vec<(i64, i64)> = items
+            .iter()
+            .map(|(key, i)| (squared_dist(key, &pos), *i))
+            .collect();
+        ranked.sort_unstable();
+        let first = ranked[0].1;
+        assert_eq!(blkdb.find_closest_pos(pos), Some(&first), "{:?}", pos);
+        assert_eq!(blkdb.find_closest_brute(pos).unwrap().0, &first);
+        assert_eq!(blkdb.find_closest_approx(pos, 0.0), Some(&first));
+        let k_closest: Vec<(i64, i64)> = blkdb
+            .find_k_closest(pos, 20)
+            .into_iter()
+            .map(|(i, dist)| (dist, *i))
+            .collect();
+        assert_eq!(k_closest, ranked[..20]);
+    }
+}
+
+#[test]
+fn equally_close_items_are_found_in_the_order_added() {
+    let items = duplicated_keys(5000, 7);
+    let mut blkdb = BlockDb::from_keyed(items.clone());
+    first_added_wins(&blkdb, &items);
+    blkdb.rebuild();
+    first_added_wins(&blkdb, &items);
+
+    // the same items in another order win by the new one
+    let mut shuffled = items.clone();
+    shuffled.shuffle(&mut StdRng::seed_from_u64(8));
+    let renumbered: Vec<_> = shuffled
+        .iter()
+        .zip(0..)
+        .map(|(&(key, _), i)| (key, i))
+        .collect();
+    first_added_wins(&BlockDb::from_keyed(renumbered.clone()), &renumbered);
+}
+
+#[test]
+fn inserted_items_come_after_the_ones_built_from() {
+    let items = duplicated_keys(3000, 11);
+    let (built, inserted) = items.split_at(1000);
+    let mut blkdb = BlockDb::from_keyed(built.to_vec());
+    for &(key, i) in inserted {
+        blkdb.insert_keyed(key, i);
+    }
+    first_added_wins(&blkdb, &items);
+
+    // removing takes out some item of the key, not necessarily the first
+    for &(key, _) in &items[..50] {
+        blkdb.remove(key);
+    }
+    blkdb.insert_keyed([0, 0, 0], 3000);
+    let mut left: Vec<i64> = blkdb.iter().map(|(_, &i)| i).collect();
+    left.sort_unstable();
+    let mut kept = items.clone();
+    kept.push(([0, 0, 0], 3000));
+    kept.retain(|(_, i)| left.binary_search(i).is_ok());
+    assert_eq!(kept.len(), 2951);
+    first_added_wins(&blkdb, &kept);
+}
+
+#[test]
+fn saved_trees_keep_the_order_items_were_added() {
+    let items = duplicated_keys(4000, 13);
+    let mut blkdb = BlockDb::from_keyed(items[..3000].to_vec());
+    for &(key, i) in &items[3000..] {
+        blkdb.insert_keyed(key, i);
+    }
+    let mut bytes = Vec::new();
+    blkdb.save_to(&mut bytes).unwrap();
+    let mut loaded = BlockDb::<i16, i64>::load_from(&bytes[..]).unwrap();
+    first_added_wins(&loaded, &items);
+    loaded.insert_keyed([10, 10, 10], 4000);
+    assert_eq!(
+        loaded.find_closest_pos([10, 10, 10]),
+        blkdb.find_closest_pos([10, 10, 10])
+    );
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_string(&blkdb).unwrap();
+        let back: BlockDb<i16, i64> = serde_json::from_str(&json).unwrap();
+        first_added_wins(&back, &items);
+    }
+}
+
+#[quickcheck]
+fn weighted_closest_matches_brute_force(
+    points: Vec<(i16, i16, i16)>,
+    pos: (i16, i16, i16),
+    weights: (u8, u8, u8),
+) -> bool {
+    // none, small and as large as keys get
+    let weight = |w: u8| match w % 4 {
+        0 => 0,
+        1 => i16::MAX,
+        _ => i16::from(w / 4),
+    };
+    let weights = [weight(weights.0), weight(weights.1), weight(weights.2)];
+    let key = |p: &(i16, i16, i16)| [p.0, p.1, p.2];
+    let pos = key(&pos);
+    let blkdb = BlockDb::from_keyed(points.iter().map(key).zip(0..).collect::<Vec<_>>());
+    let closest = points
+        .iter()
+        .zip(0..)
+        .map(|(p, i)| (weighted_dist(&key(p), &pos, &weights.map(i64::from)), i))
+        .min();
+    blkdb
+        .find_closest_weighted(pos, weights)
+        .map(|(&i, dist)| (dist, i))
+        == closest
+}
+
+#[test]
+fn one_tree_is_searched_with_different_weights() {
+    let points = vec![[100, 10, 0], [10, 100, 0], [50, 50, 90]];
+    let blkdb = BlockDb::new(points, |p| *p);
+    let pos = [90, 90, 0];
+    assert_eq!(
+        blkdb.find_closest_weighted(pos, [10, 1, 1]),
+        Some((&[100, 10, 0], 7400))
+    );
+    assert_eq!(
+        blkdb.find_closest_weighted(pos, [1, 10, 1]),
+        Some((&[10, 100, 0], 7400))
+    );
+    assert_eq!(
+        blkdb.find_closest_weighted(pos, [1, 1, 0]),
+        Some((&[50, 50, 90], 3200))
+    );
+    assert_eq!(
+        blkdb.find_closest_weighted(pos, [1, 1, 1]),
+        blkdb.find_closest_brute(pos)
+    );
+    // nothing counts, so the first item wins
+    assert_eq!(
+        blkdb.find_closest_weighted(pos, [0, 0, 0]),
+        Some((&[100, 10, 0], 0))
+    );
+    let empty: BlockDb<i16, i32> = BlockDb::from_keyed(Vec::new());
+    assert_eq!(empty.find_closest_weighted(pos, [1, 1, 1]), None);
+}
+
+#[test]
+fn rejected_items_are_passed_over() {
+    let mut rng = StdRng::seed_from_u64(101);
+    for size in [1, 2, 30, 3000] {
+        let points: Vec<[i16; 3]> = (0..size)
+            .map(|_| std::array::from_fn(|_| rng.gen_range(-50..50)))
+            .collect();
+        let blkdb = BlockDb::from_keyed(points.iter().copied().zip(0..).collect::<Vec<_>>());
+        for _ in 0..50 {
+            let pos: [i16; 3] = std::array::from_fn(|_| rng.gen_range(-60..60));
+            let mut ranked: Vec<(i64, i32)> = points
+                .iter()
+                .zip(0..)
+                .map(|(p, i)| (squared_dist(p, &pos), i))
+                .collect();
+            ranked.sort_unstable();
+            // the closest few are rejected, the next one is found
+            for rejected in 0..=ranked.len().min(4) {
+                let nearest: Vec<i32> = ranked[..rejected].iter().map(|r| r.1).collect();
+                let found = blkdb.find_closest_where(pos, |i| !nearest.contains(i));
+                let expected = ranked.get(rejected).map(|&(dist, i)| (i, dist));
+                assert_eq!(found.map(|(&i, dist)| (i, dist)), expected, "size={}", size);
+            }
+        }
+    }
+}
+
+#[quickcheck]
+fn filtered_closest_matches_a_filtered_scan(
+    points: Vec<(i16, i16, i16)>,
+    pos: (i16, i16, i16),
+    modulus: u8,
+) -> bool {
+    let key = |p: &(i16, i16, i16)| [p.0, p.1, p.2];
+    let pos = key(&pos);
+    let modulus = i32::from(modulus % 5) + 2;
+    let accept = |i: &i32| i % modulus == 0;
+    let keyed: Vec<([i16; 3], i32)> = points.iter().map(key).zip(0..).collect();
+    // the accepted items keep their order, so ties go the same way
+    let accepted: Vec<_> = keyed.iter().filter(|(_, i)| accept(i)).copied().collect();
+    let scanned = BlockDb::from_keyed(accepted)
+        .find_closest_brute(pos)
+        .map(|(&i, dist)| (i, dist));
+    let blkdb = BlockDb::from_keyed(keyed);
+    blkdb
+        .find_closest_where(pos, accept)
+        .map(|(&i, dist)| (i, dist))
+        == scanned
+}
+
+#[test]
+fn nothing_is_found_when_everything_is_rejected() {
+    let blkdb = BlockDb::new(vec![[1i16, 2, 3], [4, 5, 6]], |p| *p);
+    assert_eq!(blkdb.find_closest_where([0, 0, 0], |_| false), None);
+    assert_eq!(
+        blkdb.find_closest_where([0, 0, 0], |p| p[0] > 1),
+        Some((&[4, 5, 6], 77))
+    );
+}
+
+#[test]
+fn closest_searches_look_at_few_nodes() {
+    let mut rng = StdRng::seed_from_u64(89);
+    let points: Vec<[i16; 3]> = (0..100_000)
+        .map(|_| std::array::from_fn(|_| rng.gen_range(0..256)))
+        .collect();
+    let blkdb = BlockDb::new(points, |p| *p);
+    VISITED.with(|visited| visited.set(0));
+    for _ in 0..1000 {
+        let pos: [i16; 3] = std::array::from_fn(|_| rng.gen_range(0..256));
+        blkdb.find_closest_pos(pos);
+    }
+    // comparing the unsquared distance to the plane took over 6000
+    let visited = VISITED.with(|visited| visited.get());
+    assert!(visited < 1000 * 100, "{} nodes visited", visited);
+}
+
+#[test]
+fn opposite_corners_of_the_color_cube_are_far_apart() {
+    let corners = vec![[0i16, 0, 0], [255, 255, 255], [250, 250, 250]];
+    let blkdb = BlockDb::new(corners, |c| *c);
+    assert_eq!(
+        blkdb.find_closest_pos([255, 255, 255]),
+        Some(&[255, 255, 255])
+    );
+    assert_eq!(blkdb.find_closest_pos([10, 10, 10]), Some(&[0, 0, 0]));
+    let found = blkdb.find_k_closest([0, 0, 0], 3);
+    let dists: Vec<i64> = found.iter().map(|f| f.1).collect();
+    assert_eq!(dists, [0, 3 * 250 * 250, 3 * 255 * 255]);
+    assert_eq!(blkdb.find_within([0, 0, 0], 3 * 255 * 255 - 1).len(), 2);
+}
+
+#[test]
+#[ignore = "timing, run with --ignored --nocapture"]
+fn building_a_million_keys() {
+    let mut rng = StdRng::seed_from_u64(91);
+    let points: Vec<[i16; 3]> = (0..1_000_000)
+        .map(|_| std::array::from_fn(|_| rng.gen_range(0..256)))
+        .collect();
+    for threads in [1, 2, 4, 8] {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let start = std::time::Instant::now();
+        let blkdb = pool.install(|| BlockDb::new(points.clone(), |p| *p));
+        eprintln!(
+            "built {} keys on {} threads in {:.1?}",
+            blkdb.len(),
+            threads,
+            start.elapsed()
+        );
+    }
+}
+
+#[test]
+fn trees_built_in_parallel_are_the_same() {
+    let mut rng = StdRng::seed_from_u64(92);
+    let points: Vec<[i16; 3]> = clustered_keys(&mut rng, 5 * PARALLEL_BUILD_MIN, 0..40);
+    let build = |threads| {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        pool.install(|| BlockDb::new(points.clone(), |p| *p).to_dot_str())
+    };
+    assert_eq!(build(1), build(4));
+}
+
+/// A tree where every node only has a left child, with a larger key.
+#[cfg(test)]
+fn list_of(len: usize) -> BlockDb<i32, i32, 3> {
+    let mut root = None;
+    for i in (0..len as i32).rev() {
+        root = Some(Box::new(Node {
+            key: [i; 3],
+            item: i,
+            dim: i as usize % 3,
+            order: i as usize,
+            right: None,
+            left: root,
+        }));
+    }
+    BlockDb {
+        root,
+        len,
+        levels: len,
+        max_balance_factor: f64::INFINITY,
+        added: len,
+        keyfn: None,
+    }
+}
+
+#[test]
+fn closest_is_found_in_a_list_of_a_million_nodes() {
+    let len = 1_000_000;
+    let blkdb = list_of(len);
+    for pos in [[-5, 0, 0], [500_000, 500_001, 499_999], [2_000_000; 3]] {
+        let (&closest, ..) = blkdb.find_closest_with_dist(pos).unwrap();
+        let expected = (pos[0] + pos[1] + pos[2]) / 3;
+        assert_eq!(closest, expected.clamp(0, len as i32 - 1));
+    }
+}
+
+#[test]
+fn a_list_of_a_million_nodes_is_searched_and_removed_from() {
+    let mut blkdb = list_of(1_000_000);
+    let k_closest: Vec<_> = blkdb
+        .find_k_closest([500_000; 3], 3)
+        .into_iter()
+        .map(|(&i, _)| i)
+        .collect();
+    assert_eq!(k_closest, [500_000, 499_999, 500_001]);
+    let mut within: Vec<_> = blkdb.find_within([900_000; 3], 12).into_iter().collect();
+    within.sort_unstable();
+    assert_eq!(within, [&899_998, &899_999, &900_000, &900_001, &900_002]);
+    assert_eq!(blkdb.remove([500_000; 3]), Some(500_000));
+    assert_eq!(blkdb.remove([999_999; 3]), Some(999_999));
+    assert_eq!(blkdb.find_closest_pos([500_000; 3]), Some(&499_999));
+    assert_eq!(blkdb.len(), 999_998);
+}
+
+#[test]
+fn deep_trees_are_written_out() {
+    let blkdb = list_of(100_000);
+    assert_eq!(blkdb.depth(), 100_000);
+    let dot = blkdb.to_dot_str();
+    assert!(dot.ends_with("99999 [label=\"0@(99999,99999,99999)\"]\n}"));
+    let text = blkdb.root.as_ref().unwrap().to_string();
+    assert_eq!(text.matches(" left: ").count(), 99_999);
+}
+
+#[test]
+#[ignore = "slow without --release"]
+fn two_million_keys_are_built_and_written_out() {
+    let mut rng = StdRng::seed_from_u64(94);
+    let points: Vec<[i32; 3]> = (0..2_000_000)
+        .map(|_| std::array::from_fn(|_| rng.gen_range(0..1000)))
+        .collect();
+    let blkdb = BlockDb::new(points, |p| *p);
+    assert!(blkdb.depth() <= 22, "depth {}", blkdb.depth());
+    assert_eq!(blkdb.to_dot_str().matches(" -- ").count(), 2_000_000 - 1);
+}
+
+#[cfg(feature = "serde")]
+#[quickcheck]
+fn trees_survive_a_round_trip(
+    points: Vec<(i16, i16, i16)>,
+    inserted: Vec<(i16, i16, i16)>,
+) -> bool {
+    let key = |p: &(i16, i16, i16)| [p.0, p.1, p.2];
+    let mut blkdb = BlockDb::new(points, key);
+    for p in inserted {
+        blkdb.insert_keyed(key(&p), p);
+    }
+    let json = serde_json::to_string(&blkdb).unwrap();
+    let back: BlockDb<i16, (i16, i16, i16)> = serde_json::from_str(&json).unwrap();
+    let probes = [[0, 0, 0], [-300, 5, 12_000], [i16::MAX, i16::MIN, 0]];
+    back.to_dot_str() == blkdb.to_dot_str()
+        && back.len() == blkdb.len()
+        && back.depth() == blkdb.depth()
+        && probes.iter().all(|&pos| {
+            back.find_closest_with_dist(pos) == blkdb.find_closest_with_dist(pos)
+                && back.find_k_closest(pos, 4) == blkdb.find_k_closest(pos, 4)
+                && back.find_within(pos, 1 << 24) == blkdb.find_within(pos, 1 << 24)
+        })
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn deep_trees_survive_a_round_trip() {
+    let blkdb = list_of(100_000);
+    let json = serde_json::to_string(&blkdb).unwrap();
+    let back: BlockDb<i32, i32> = serde_json::from_str(&json).unwrap();
+    assert_eq!(back.depth(), 100_000);
+    assert_eq!(back.find_closest_pos([77, 77, 78]), Some(&77));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn malformed_trees_are_rejected() {
+    fn parse(json: &str) -> serde_json::Result<usize> {
+        serde_json::from_str::<BlockDb<i16, u8>>(json).map(|db| db.len())
+    }
+    let leaf = r#"{"key":[1,2,3],"item":7,"dim":1,"order":0,"left":false,"right":false}"#;
+    assert_eq!(parse(&format!("[{}]", leaf)).unwrap(), 1);
+    assert_eq!(parse("[]").unwrap(), 0);
+    assert!(parse(&format!("[{},{}]", leaf, leaf)).is_err());
+    assert!(parse(&format!(
+        "[{}]",
+        leaf.replace("\"left\":false", "\"left\":true")
+    ))
+    .is_err());
+    assert!(parse(&format!("[{}]", leaf.replace("[1,2,3]", "[1,2]"))).is_err());
+    assert!(parse(&format!("[{}]", leaf.replace("\"dim\":1", "\"dim\":3"))).is_err());
+}
+
+#[cfg(test)]
+fn saved_sample() -> (BlockDb<i16, i32>, Vec<u8>) {
+    let mut rng = StdRng::seed_from_u64(96);
+    let points: Vec<[i16; 3]> = (0..2000).map(|_| rng.gen()).collect();
+    let mut blkdb = BlockDb::from_keyed(points.into_iter().zip(0..).collect::<Vec<_>>());
+    blkdb.insert_keyed([1, 2, 3], -1);
+    let mut bytes = Vec::new();
+    blkdb.save_to(&mut bytes).unwrap();
+    (blkdb, bytes)
+}
+
+#[test]
+fn saved_trees_are_loaded_the_same() {
+    let (blkdb, bytes) = saved_sample();
+    let back = BlockDb::<i16, i32>::load_from(&bytes[..]).unwrap();
+    assert_eq!(back.to_dot_str(), blkdb.to_dot_str());
+    assert_eq!((back.len(), back.depth()), (blkdb.len(), blkdb.depth()));
+    let mut rng = StdRng::seed_from_u64(97);
+    for _ in 0..100 {
+        let pos = rng.gen();
+        assert_eq!(
+            back.find_closest_with_dist(pos),
+            blkdb.find_closest_with_dist(pos)
+        );
+    }
+    let mut empty = Vec::new();
+    BlockDb::<i16, i32>::from_keyed(Vec::new())
+        .save_to(&mut empty)
+        .unwrap();
+    assert!(BlockDb::<i16, i32>::load_from(&empty[..])
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn damaged_files_are_not_loaded() {
+    let (_, bytes) = saved_sample();
+    let load = |bytes: &[u8]| BlockDb::<i16, i32>::load_from(bytes).map(|db| db.len());
+    for len in (0..bytes.len()).step_by(7) {
+        assert!(load(&bytes[..len]).is_err(), "cut off at {}", len);
+    }
+    for at in (0..bytes.len()).step_by(5) {
+        let mut damaged = bytes.clone();
+        damaged[at] ^= 0x10;
+        assert!(load(&damaged).is_err(), "byte {} changed", at);
+    }
+    assert!(BlockDb::<i32, i32>::load_from(&bytes[..]).is_err());
+    assert!(BlockDb::<i16, i64>::load_from(&bytes[..]).is_err());
+    assert!(BlockDb::<i16, i32, 6>::load_from(&bytes[..]).is_err());
+}
+
+#[test]
+fn keys_of_another_type_are_not_loaded() {
+    let blkdb = BlockDb::from_keyed(vec![([1i32, 2, 3], 7i32), ([-4, 5, -6], 8)]);
+    let mut bytes = Vec::new();
+    blkdb.save_to(&mut bytes).unwrap();
+    assert_eq!(BlockDb::<i32, i32>::load_from(&bytes[..]).unwrap().len(), 2);
+    // the same size as i32, but not the same numbers
+    assert!(BlockDb::<f32, i32>::load_from(&bytes[..]).is_err());
+    assert!(BlockDb::<i32, f32>::load_from(&bytes[..]).is_err());
+}
+
+#[test]
+fn keyed_items_build_the_same_tree() {
+    let points: Vec<(i16, i16, i16)> = (0..50).map(|i| (i * 7 % 31, i * 3 % 17, i)).collect();
+    let by_fn = BlockDb::new(points.clone(), |x| [x.0, x.1, x.2]);
+    let keyed = BlockDb::from_keyed(points.iter().map(|&p| ([p.0, p.1, p.2], p)).collect());
+    for p in &points {
+        let pos = [p.0 + 1, p.1, p.2 - 1];
+        assert_eq!(by_fn.find_closest_pos(pos), keyed.find_closest_pos(pos));
+    }
+}
+
+#[quickcheck]
+fn approximate_matches_are_close_enough(
+    points: Vec<(i16, i16, i16)>,
+    pos: (i16, i16, i16),
+) -> bool {
+    let key = |x: &(i16, i16, i16)| [x.0, x.1, x.2];
+    let pos = key(&pos);
+    let dist = |p: &(i16, i16, i16)| (squared_dist(&key(p), &pos) as f64).sqrt();
+    let blkdb = BlockDb::new(points.clone(), key);
+    let closest = points.iter().map(dist).fold(f64::INFINITY, f64::min);
+    match blkdb.find_closest_approx(pos, 0.5) {
+        Some(found) => dist(found) <= closest * 1.5 + 1e-9,
+        None => points.is_empty(),
+    }
+}
+
+#[test]
+fn approximate_searches_look_at_fewer_nodes() {
+    // keys of four cells of tiles and blocks, the cells of each alike
+    let mut rng = StdRng::seed_from_u64(98);
+    let mut key = || -> [i16; 12] {
+        let base: [i16; 3] = std::array::from_fn(|_| rng.gen_range(0..256));
+        std::array::from_fn(|d| base[d % 3] + rng.gen_range(-20..20))
+    };
+    let blkdb = BlockDb::new((0..50_000).map(|_| key()).collect(), |p| *p);
+    let queries: Vec<[i16; 12]> = (0..500).map(|_| key()).collect();
+    let search = |epsilon: f64| {
+        VISITED.with(|visited| visited.set(0));
+        let error: f64 = queries
+            .iter()
+            .map(|&pos| {
+                (squared_dist(blkdb.find_closest_approx(pos, epsilon).unwrap(), &pos) as f64).sqrt()
+            })
+            .sum();
+        (VISITED.with(|visited| visited.get()), error)
+    };
+    let (exact_visits, exact_error) = search(0.0);
+    // about a third of the nodes, for hardly any more error
+    let (visits, error) = search(0.5);
+    assert!(
+        visits * 2 < exact_visits,
+        "{} of {} nodes",
+        visits,
+        exact_visits
+    );
+    assert!(
+        error < exact_error * 1.01,
+        "{} instead of {}",
+        error,
+        exact_error
+    );
+}
+
+#[test]
+fn long_keys_find_the_closest_item() {
+    let points: Vec<[i16; 27]> = (0..200)
+        .map(|i: i16| std::array::from_fn(|d| (i * 37 + d as i16 * 11) % 97))
+        .collect();
+    let blkdb = BlockDb::new(points.clone(), |p| *p);
+    for i in 0..20 {
+        let pos: [i16; 27] = std::array::from_fn(|d| (i * 13 + d as i16 * 7) % 97);
+        let closest = points.iter().map(|p| squared_dist(p, &pos)).min().unwrap();
+        assert_eq!(
+            squared_dist(blkdb.find_closest_pos(pos).unwrap(), &pos),
+            closest
+        );
+        assert_eq!(blkdb.find_k_closest(pos, 1)[0].1, closest);
+    }
+}
+
+#[test]
+fn k_closest_agrees_with_brute_force_on_large_trees() {
+    let mut rng = StdRng::seed_from_u64(79);
+    for size in [0, 1, 7, 3000] {
+        let points: Vec<[i16; 3]> = clustered_keys(&mut rng, size, -20..20);
+        let blkdb = BlockDb::new(points.clone(), |p| *p);
+        for _ in 0..20 {
+            let pos: [i16; 3] = std::array::from_fn(|_| rng.gen_range(-25..25));
+            let dist = |p: &[i16; 3]| squared_dist(p, &pos);
+            let mut expected: Vec<i64> = points.iter().map(dist).collect();
+            expected.sort_unstable();
+            for k in [0, 1, 10, 100, size + 5] {
+                let found = blkdb.find_k_closest(pos, k);
+                let dists: Vec<i64> = found.iter().map(|(_, d)| *d).collect();
+                assert_eq!(dists, expected[..k.min(size)], "k={} size={}", k, size);
+                assert!(found.iter().all(|(p, d)| dist(p) == *d));
+            }
+        }
+    }
+}
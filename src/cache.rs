@@ -0,0 +1,339 @@
+use crate::blockdb::{BlockDb, Record};
+use crate::color::Gains;
+use crate::input::stable_hash_bytes;
+use crate::tiles::{Edges, Histogram};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// File name of the manifest when it lives next to the inputs.
+pub const MANIFEST_NAME: &str = ".collagen-cache.json";
+
+/// Manifests written with another version are started over.
+const VERSION: u32 = 2;
+
+/// Remembers the tile keys of every input file, so unchanged files don't
+/// need to be decoded until one of their tiles is actually used. Files are
+/// recognized by path and modification time, or failing that by content,
+/// so copied and touched files are not decoded again either.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    version: u32,
+    /// everything besides the file itself that the tile keys depend on
+    settings: String,
+    entries: BTreeMap<String, Entry>,
+    /// path of an entry by the `(size, hash)` of its file
+    #[serde(skip)]
+    by_content: BTreeMap<(u64, u64), String>,
+}
+
+/// Just the version of a manifest, read before the rest, which may not fit
+/// the current format.
+#[derive(Deserialize)]
+struct Version {
+    #[serde(default)]
+    version: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    size: u64,
+    modified: (u64, u32),
+    /// `stable_hash_bytes` of the file
+    hash: u64,
+    /// tiles in cutting order, one list per animation frame
+    pub frames: Vec<Vec<CachedTile>>,
+}
+
+/// What the manifest remembers about a tile.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedTile {
+    /// as many components as the keys of `settings` have
+    pub key: Vec<i16>,
+    /// `(x, y, width, height)` of the tile in its source image
+    pub region: (u32, u32, u32, u32),
+    /// white balance of the source, which the key already has applied
+    pub gains: Gains,
+    pub histogram: Histogram,
+    pub edges: Edges,
+    /// only computed with --metric phash
+    pub phashes: Option<[u64; 8]>,
+}
+
+impl Manifest {
+    /// Reads the manifest at `path`. A missing file, or one written by
+    /// another version or with different `settings`, yields an empty
+    /// manifest, and so does a corrupt one after a warning.
+    pub fn load(path: &Path, settings: &str) -> Self {
+        let empty = Manifest {
+            version: VERSION,
+            settings: settings.to_string(),
+            ..Manifest::default()
+        };
+        let Ok(bytes) = fs::read(path) else {
+            return empty;
+        };
+        let parsed = serde_json::from_slice::<Version>(&bytes).and_then(|v| {
+            if v.version == VERSION {
+                serde_json::from_slice::<Manifest>(&bytes).map(Some)
+            } else {
+                Ok(None)
+            }
+        });
+        let mut manifest = match parsed {
+            Ok(Some(manifest)) if manifest.settings == settings => manifest,
+            Ok(_) => return empty,
+            Err(e) => {
+                eprintln!(
+                    "warning: ignoring corrupt tile manifest {}: {}",
+                    path.display(),
+                    e
+                );
+                return empty;
+            }
+        };
+        manifest.by_content = manifest
+            .entries
+            .iter()
+            .map(|(path, entry)| ((entry.size, entry.hash), path.clone()))
+            .collect();
+        manifest
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec(self).map_err(io::Error::other)?;
+        fs::write(path, json)
+    }
+
+    /// The entry for `path`, if the file still has the size and modification
+    /// time it had when the entry was written. Otherwise files of a size
+    /// some entry has are hashed, and an entry for the same content is
+    /// taken over for `path`.
+    pub fn lookup(&mut self, path: &Path) -> Option<&Entry> {
+        let name = path.display().to_string();
+        let (size, modified) = stamp(path).ok()?;
+        let unchanged = self
+            .entries
+            .get(&name)
+            .is_some_and(|e| e.size == size && e.modified == modified);
+        if !unchanged {
+            self.by_content.range((size, 0)..=(size, u64::MAX)).next()?;
+            let hash = stable_hash_bytes(&fs::read(path).ok()?);
+            let from = self.by_content.get(&(size, hash))?;
+            let entry = self.entries.get(from)?;
+            if (entry.size, entry.hash) != (size, hash) {
+                return None;
+            }
+            let entry = Entry {
+                modified,
+                ..entry.clone()
+            };
+            self.entries.insert(name.clone(), entry);
+            self.by_content.insert((size, hash), name.clone());
+        }
+        self.entries.get(&name)
+    }
+
+    pub fn insert(&mut self, path: &Path, frames: Vec<Vec<CachedTile>>) {
+        let (Ok((size, modified)), Ok(bytes)) = (stamp(path), fs::read(path)) else {
+            return;
+        };
+        let name = path.display().to_string();
+        let hash = stable_hash_bytes(&bytes);
+        let entry = Entry {
+            size,
+            modified,
+            hash,
+            frames,
+        };
+        self.entries.insert(name.clone(), entry);
+        self.by_content.insert((size, hash), name);
+    }
+
+    /// Forgets files that no longer exist.
+    pub fn prune(&mut self) {
+        self.entries.retain(|path, _| Path::new(path).exists());
+    }
+}
+
+/// What a database saved with --save-db was built from. `load_db` only
+/// hands out databases built from the same tiles the same way.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DbHeader {
+    pub tile_size: (u32, u32),
+    pub tiles: usize,
+    /// `stable_hash_bytes` of where the tiles came from
+    pub fingerprint: u64,
+    /// everything besides the tiles that the keys depend on
+    pub settings: String,
+}
+
+/// Writes `header` as a line of JSON, followed by `BlockDb::save_to`.
+pub fn save_db<I: Record, const N: usize>(
+    path: &Path,
+    header: &DbHeader,
+    bldb: &BlockDb<i16, I, N>,
+) -> io::Result<()> {
+    let mut out = io::BufWriter::new(fs::File::create(path)?);
+    serde_json::to_writer(&mut out, header).map_err(io::Error::other)?;
+    out.write_all(b"\n")?;
+    bldb.save_to(&mut out)?;
+    out.flush()
+}
+
+/// Reads a database written by `save_db` with a header equal to `expected`.
+pub fn load_db<I: Record, const N: usize>(
+    path: &Path,
+    expected: &DbHeader,
+) -> Result<BlockDb<i16, I, N>, String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    let line = bytes
+        .iter()
+        .position(|&b| b == b'\n')
+        .unwrap_or(bytes.len());
+    let header: DbHeader =
+        serde_json::from_slice(&bytes[..line]).map_err(|_| "not a tile database".to_string())?;
+    if header.tile_size != expected.tile_size {
+        let (width, height) = header.tile_size;
+        let (want_width, want_height) = expected.tile_size;
+        return Err(format!(
+            "saved for {}x{} tiles, not {}x{}",
+            width, height, want_width, want_height
+        ));
+    }
+    if header.settings != expected.settings {
+        return Err("saved with other tile or key settings".to_string());
+    }
+    if (header.tiles, header.fingerprint) != (expected.tiles, expected.fingerprint) {
+        return Err(format!(
+            "saved for {} other tiles, not these {}",
+            header.tiles, expected.tiles
+        ));
+    }
+    BlockDb::load_from(bytes.get(line + 1..).unwrap_or_default()).map_err(|e| e.to_string())
+}
+
+fn stamp(path: &Path) -> io::Result<(u64, (u64, u32))> {
+    let meta = fs::metadata(path)?;
+    let since_epoch = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok((
+        meta.len(),
+        (since_epoch.as_secs(), since_epoch.subsec_nanos()),
+    ))
+}
+
+#[test]
+fn entries_are_invalidated_by_changes() {
+    let dir = crate::input::scratch_dir("manifest");
+    let file = dir.join("a.png");
+    let manifest_path = dir.join(MANIFEST_NAME);
+    fs::write(&file, b"first").unwrap();
+
+    let tile = CachedTile {
+        key: vec![1, 2, 3],
+        region: (0, 32, 32, 32),
+        gains: Gains::NONE,
+        histogram: Histogram::of(&image::RgbImage::new(2, 2)),
+        edges: Edges::default(),
+        phashes: Some([7; 8]),
+    };
+    let mut manifest = Manifest::load(&manifest_path, "size=32");
+    manifest.insert(&file, vec![vec![tile.clone()]]);
+    manifest.save(&manifest_path).unwrap();
+
+    let mut manifest = Manifest::load(&manifest_path, "size=32");
+    assert_eq!(manifest.lookup(&file).unwrap().frames, vec![vec![tile]]);
+    assert!(Manifest::load(&manifest_path, "size=16")
+        .lookup(&file)
+        .is_none());
+    fs::write(&file, b"second!").unwrap();
+    assert!(manifest.lookup(&file).is_none());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn copies_are_found_by_content() {
+    let dir = crate::input::scratch_dir("content");
+    let (file, copy) = (dir.join("a.png"), dir.join("b.png"));
+    let manifest_path = dir.join(MANIFEST_NAME);
+    fs::write(&file, b"pixels").unwrap();
+
+    let tile = CachedTile {
+        key: vec![4, 5, 6],
+        region: (0, 0, 16, 16),
+        gains: Gains::NONE,
+        histogram: Histogram::of(&image::RgbImage::new(2, 2)),
+        edges: Edges::default(),
+        phashes: None,
+    };
+    let mut manifest = Manifest::load(&manifest_path, "size=16");
+    manifest.insert(&file, vec![vec![tile.clone()]]);
+    manifest.save(&manifest_path).unwrap();
+
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    fs::write(&copy, b"pixels").unwrap();
+    fs::write(&file, b"pixels").unwrap();
+    let mut manifest = Manifest::load(&manifest_path, "size=16");
+    assert_eq!(
+        manifest.lookup(&copy).unwrap().frames,
+        vec![vec![tile.clone()]]
+    );
+    assert_eq!(manifest.lookup(&file).unwrap().frames, vec![vec![tile]]);
+    fs::write(&copy, b"PIXELS").unwrap();
+    assert!(manifest.lookup(&copy).is_none());
+
+    fs::write(&manifest_path, b"{\"version\": 2, \"entr").unwrap();
+    assert!(Manifest::load(&manifest_path, "size=16")
+        .lookup(&file)
+        .is_none());
+    fs::write(&manifest_path, b"{\"version\": 1, \"entries\": []}").unwrap();
+    assert!(Manifest::load(&manifest_path, "size=16").entries.is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn databases_are_only_loaded_for_their_tiles() {
+    let dir = crate::input::scratch_dir("db");
+    let path = dir.join("tiles.db");
+    let header = DbHeader {
+        tile_size: (16, 8),
+        tiles: 2,
+        fingerprint: 42,
+        settings: "key=Average".to_string(),
+    };
+    let bldb = BlockDb::from_keyed(vec![([1i16, 2, 3], 0i32), ([9, 9, 9], 1)]);
+    save_db(&path, &header, &bldb).unwrap();
+
+    let loaded: BlockDb<i16, i32, 3> = load_db(&path, &header).unwrap();
+    assert_eq!(loaded.to_dot_str(), bldb.to_dot_str());
+    let load = |header: DbHeader| load_db::<i32, 3>(&path, &header).map(|_| ()).unwrap_err();
+    let other_size = DbHeader {
+        tile_size: (8, 8),
+        ..header.clone()
+    };
+    assert_eq!(load(other_size), "saved for 16x8 tiles, not 8x8");
+    let other_key = DbHeader {
+        settings: "key=Median".to_string(),
+        ..header.clone()
+    };
+    assert_eq!(load(other_key), "saved with other tile or key settings");
+    let other_tiles = DbHeader {
+        fingerprint: 43,
+        ..header.clone()
+    };
+    assert_eq!(load(other_tiles), "saved for 2 other tiles, not these 2");
+    assert!(load_db::<i32, 6>(&path, &header).is_err());
+
+    let bytes = fs::read(&path).unwrap();
+    fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+    assert!(load_db::<i32, 3>(&path, &header).is_err());
+    fs::write(&path, b"PNG").unwrap();
+    assert_eq!(load(header), "not a tile database");
+    fs::remove_dir_all(&dir).unwrap();
+}
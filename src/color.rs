@@ -0,0 +1,591 @@
+use image::Rgb;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// An sRGB color written as `#rrggbb` (the `#` is optional).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HexColor(pub Rgb<u8>);
+
+impl FromStr for HexColor {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let hex = value.trim_start_matches('#');
+        let invalid = || format!("invalid color \"{}\", expected #rrggbb", value);
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+        Ok(HexColor(Rgb([channel(0)?, channel(2)?, channel(4)?])))
+    }
+}
+
+/// Decodes an sRGB channel value into linear light, 0-1.
+pub fn srgb_to_linear(value: u8) -> f64 {
+    let v = f64::from(value) / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Encodes linear light, 0-1, as the nearest sRGB channel value.
+pub fn linear_to_srgb(linear: f64) -> u8 {
+    let v = if linear <= 0.0031308 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    };
+    (v * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+/// The color space average colors are compared in.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Metric {
+    #[default]
+    Rgb,
+    /// CIELAB, in tenths of a unit so keys stay integers
+    Lab,
+    /// CIELAB keys, with the closest candidates reranked by CIEDE2000
+    Ciede2000,
+    /// hue and saturation as a point on the color wheel, plus value at half
+    /// the weight
+    Hsv,
+    /// Rec. 709 luma only, for grayscale collages
+    Luma,
+    /// Oklab, in thousandths of a unit so keys stay integers
+    Oklab,
+    /// tiles whose 64-bit perceptual hash differs from the block's in the
+    /// fewest bits, with RGB keys to pick among equally close hashes
+    Phash,
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "rgb" => Ok(Metric::Rgb),
+            "lab" => Ok(Metric::Lab),
+            "ciede2000" => Ok(Metric::Ciede2000),
+            "hsv" => Ok(Metric::Hsv),
+            "luma" => Ok(Metric::Luma),
+            "oklab" => Ok(Metric::Oklab),
+            "phash" => Ok(Metric::Phash),
+            _ => Err(format!(
+                "unknown metric \"{}\", expected rgb, lab, ciede2000, hsv, luma, oklab or phash",
+                value
+            )),
+        }
+    }
+}
+
+impl Metric {
+    /// The key of an average sRGB color in this metric's space.
+    pub fn key(self, rgb: [u8; 3]) -> [i16; 3] {
+        match self {
+            Metric::Rgb | Metric::Phash => {
+                [i16::from(rgb[0]), i16::from(rgb[1]), i16::from(rgb[2])]
+            }
+            Metric::Lab | Metric::Ciede2000 => {
+                // every metric keys the same i16 trees and --save-db files,
+                // so Lab is rounded to tenths rather than kept as floats; a
+                // tenth is far below the one unit people can tell apart
+                let lab = srgb_to_lab(rgb);
+                let scaled = |v: f64| (v * LAB_SCALE).round() as i16;
+                [scaled(lab[0]), scaled(lab[1]), scaled(lab[2])]
+            }
+            Metric::Hsv => {
+                // hue is an angle, so it goes in as the direction of a point
+                // on the wheel rather than a number that jumps from 360 to 0
+                let (hue, saturation, value) = srgb_to_hsv(rgb);
+                let radius = saturation * 2.0 * 255.0;
+                let (sin, cos) = hue.to_radians().sin_cos();
+                [
+                    (radius * cos).round() as i16,
+                    (radius * sin).round() as i16,
+                    (value * 0.5 * 255.0).round() as i16,
+                ]
+            }
+            Metric::Luma => [luma(rgb).round() as i16, 0, 0],
+            // i16 like Lab, in thousandths, which are a twentieth of the
+            // smallest difference visible in Oklab
+            Metric::Oklab => srgb_to_oklab(rgb).map(|v| (v * OKLAB_SCALE).round() as i16),
+        }
+    }
+}
+
+/// Oklab keys hold this many steps per Oklab unit.
+const OKLAB_SCALE: f64 = 1000.0;
+
+/// Converts an sRGB color to Oklab, with lightness within 0-1.
+pub fn srgb_to_oklab(rgb: [u8; 3]) -> [f64; 3] {
+    let [r, g, b] = rgb.map(srgb_to_linear);
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
+
+/// Converts an Oklab color back to sRGB, clamping what lies outside.
+#[cfg(test)]
+pub fn oklab_to_srgb(lab: [f64; 3]) -> [u8; 3] {
+    let [lightness, a, b] = lab;
+    let l = (lightness + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+    let m = (lightness - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+    let s = (lightness - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+    [
+        4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+        -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+        -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s,
+    ]
+    .map(linear_to_srgb)
+}
+
+/// Brightness of an sRGB color with the Rec. 709 weights, 0-255.
+pub fn luma(rgb: [u8; 3]) -> f64 {
+    0.2126 * f64::from(rgb[0]) + 0.7152 * f64::from(rgb[1]) + 0.0722 * f64::from(rgb[2])
+}
+
+/// Hue in degrees, saturation and value of an sRGB color, the latter two
+/// within 0-1.
+pub fn srgb_to_hsv(rgb: [u8; 3]) -> (f64, f64, f64) {
+    let [r, g, b] = [
+        f64::from(rgb[0]) / 255.0,
+        f64::from(rgb[1]) / 255.0,
+        f64::from(rgb[2]) / 255.0,
+    ];
+    let max = r.max(g).max(b);
+    let spread = max - r.min(g).min(b);
+    if spread == 0.0 {
+        return (0.0, 0.0, max);
+    }
+    let sector = if max == r {
+        (g - b) / spread
+    } else if max == g {
+        (b - r) / spread + 2.0
+    } else {
+        (r - g) / spread + 4.0
+    };
+    ((sector * 60.0).rem_euclid(360.0), spread / max, max)
+}
+
+/// Lab keys hold this many steps per CIELAB unit.
+const LAB_SCALE: f64 = 10.0;
+
+/// The CIELAB color a Lab key stands for.
+pub fn key_to_lab(key: [i16; 3]) -> [f64; 3] {
+    [
+        f64::from(key[0]) / LAB_SCALE,
+        f64::from(key[1]) / LAB_SCALE,
+        f64::from(key[2]) / LAB_SCALE,
+    ]
+}
+
+/// The CIEDE2000 color difference between two CIELAB colors, following
+/// Sharma, Wu and Dalal (2005).
+pub fn ciede2000([l1, a1, b1]: [f64; 3], [l2, a2, b2]: [f64; 3]) -> f64 {
+    let pow7 = |v: f64| v.powi(7);
+    let c_mean = ((a1.hypot(b1) + a2.hypot(b2)) / 2.0).max(0.0);
+    let g = 0.5 * (1.0 - (pow7(c_mean) / (pow7(c_mean) + pow7(25.0))).sqrt());
+    let (a1, a2) = ((1.0 + g) * a1, (1.0 + g) * a2);
+    let (c1, c2) = (a1.hypot(b1), a2.hypot(b2));
+    let hue = |a: f64, b: f64| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            b.atan2(a).to_degrees().rem_euclid(360.0)
+        }
+    };
+    let (h1, h2) = (hue(a1, b1), hue(a2, b2));
+
+    let dl = l2 - l1;
+    let dc = c2 - c1;
+    let dh = if c1 * c2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 - h1 > 180.0 {
+        h2 - h1 - 360.0
+    } else {
+        h2 - h1 + 360.0
+    };
+    let dh = 2.0 * (c1 * c2).sqrt() * (dh / 2.0).to_radians().sin();
+
+    let l_mean = (l1 + l2) / 2.0;
+    let c_mean = (c1 + c2) / 2.0;
+    let h_mean = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+    let cos = |deg: f64| deg.to_radians().cos();
+    let t =
+        1.0 - 0.17 * cos(h_mean - 30.0) + 0.24 * cos(2.0 * h_mean) + 0.32 * cos(3.0 * h_mean + 6.0)
+            - 0.20 * cos(4.0 * h_mean - 63.0);
+    let d_theta = 30.0 * (-((h_mean - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (pow7(c_mean) / (pow7(c_mean) + pow7(25.0))).sqrt();
+    let s_l = 1.0 + 0.015 * (l_mean - 50.0).powi(2) / (20.0 + (l_mean - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_mean;
+    let s_h = 1.0 + 0.015 * c_mean * t;
+    let r_t = -(2.0 * d_theta).to_radians().sin() * r_c;
+
+    let (l, c, h) = (dl / s_l, dc / s_c, dh / s_h);
+    (l * l + c * c + h * h + r_t * c * h).sqrt()
+}
+
+/// Factors for the three components of a key, written as `r,g,b`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelWeights(pub [f32; 3]);
+
+impl Default for ChannelWeights {
+    fn default() -> Self {
+        ChannelWeights([1.0; 3])
+    }
+}
+
+impl FromStr for ChannelWeights {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid channel weights \"{}\", expected r,g,b", value);
+        let weights: Vec<f32> = value
+            .split(',')
+            .map(|w| w.trim().parse().map_err(|_| invalid()))
+            .collect::<Result<_, _>>()?;
+        if weights.len() != 3 {
+            return Err(invalid());
+        }
+        if !weights
+            .iter()
+            .all(|w| (f32::MIN_POSITIVE..=128.0).contains(w))
+        {
+            return Err(format!(
+                "channel weights must be greater than 0 and at most 128, got \"{}\"",
+                value
+            ));
+        }
+        Ok(ChannelWeights([weights[0], weights[1], weights[2]]))
+    }
+}
+
+impl ChannelWeights {
+    /// `key` with every component scaled by its weight, so the tree's
+    /// Euclidean distance becomes a weighted one.
+    pub fn apply(self, key: [i16; 3]) -> [i16; 3] {
+        if self == ChannelWeights::default() {
+            return key;
+        }
+        let scale = |c: usize| (f32::from(key[c]) * self.0[c]).round() as i16;
+        [scale(0), scale(1), scale(2)]
+    }
+}
+
+/// How the color cast of source images is taken out before they are keyed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WhiteBalance {
+    /// gray world: every image gets the gains that make the averages of its
+    /// three channels equal
+    Auto,
+    /// the same gains for every image
+    Manual(Gains),
+}
+
+impl FromStr for WhiteBalance {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == "auto" {
+            return Ok(WhiteBalance::Auto);
+        }
+        let invalid = || {
+            format!(
+                "invalid white balance \"{}\", expected auto or r,g,b gains",
+                value
+            )
+        };
+        let gains: Vec<f32> = value
+            .split(',')
+            .map(|g| g.trim().parse().map_err(|_| invalid()))
+            .collect::<Result<_, _>>()?;
+        if gains.len() != 3 {
+            return Err(invalid());
+        }
+        if !gains
+            .iter()
+            .all(|g| (1.0 / MAX_GAIN..=MAX_GAIN).contains(g))
+        {
+            return Err(format!(
+                "white balance gains must be within {}-{}, got \"{}\"",
+                1.0 / MAX_GAIN,
+                MAX_GAIN,
+                value
+            ));
+        }
+        Ok(WhiteBalance::Manual(Gains([gains[0], gains[1], gains[2]])))
+    }
+}
+
+/// Factors the red, green and blue values of an image are multiplied by.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Gains(pub [f32; 3]);
+
+/// Limit for white balance gains either way, so a nearly empty channel
+/// isn't blown up.
+pub const MAX_GAIN: f32 = 2.0;
+
+/// Largest shift `Gains::temperature` takes either way, which halves blue
+/// or red.
+pub const MAX_TEMPERATURE: f32 = 5000.0;
+
+impl Gains {
+    pub const NONE: Gains = Gains([1.0; 3]);
+
+    /// The gains that move the averages of the channels, `means`, to the
+    /// average of all three, each within `MAX_GAIN` of 1.
+    pub fn gray_world(means: [f64; 3]) -> Self {
+        let gray = means.iter().sum::<f64>() / 3.0;
+        Gains(means.map(|mean| {
+            if mean > 0.0 {
+                ((gray / mean) as f32).clamp(1.0 / MAX_GAIN, MAX_GAIN)
+            } else if gray > 0.0 {
+                MAX_GAIN
+            } else {
+                1.0
+            }
+        }))
+    }
+
+    /// A warm grade for a positive `shift`, a cool one for a negative: red
+    /// and blue move apart by a tenth per 1000, roughly like the light of a
+    /// lamp that much colder in kelvin. 0 is neutral.
+    pub fn temperature(shift: f32) -> Self {
+        let spread = shift / 10_000.0;
+        Gains([1.0 + spread, 1.0, 1.0 - spread])
+    }
+
+    /// The gains that undo these, up to rounding.
+    pub fn inverse(self) -> Self {
+        Gains(self.0.map(|gain| 1.0 / gain))
+    }
+
+    pub fn apply(self, rgb: [u8; 3]) -> [u8; 3] {
+        let scale = |c: usize| (f32::from(rgb[c]) * self.0[c]).round().clamp(0.0, 255.0) as u8;
+        [scale(0), scale(1), scale(2)]
+    }
+}
+
+impl Default for Gains {
+    fn default() -> Self {
+        Gains::NONE
+    }
+}
+
+/// Converts an sRGB color to CIELAB under a D65 white point.
+pub fn srgb_to_lab(rgb: [u8; 3]) -> [f64; 3] {
+    let [r, g, b] = [
+        srgb_to_linear(rgb[0]),
+        srgb_to_linear(rgb[1]),
+        srgb_to_linear(rgb[2]),
+    ];
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+    let f = |t: f64| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// Rounds a channel value to the center of its bucket, with `levels`
+/// equally wide buckets between 0 and 255.
+pub fn quantize(value: u8, levels: u32) -> u8 {
+    let bucket = u32::from(value) * levels / 256;
+    ((2 * bucket + 1) * 128 / levels) as u8
+}
+
+/// Maps a channel value through `(v / 255)^(1 / gamma) * 255`, which with a
+/// gamma above 1 spreads out dark values and packs bright ones together.
+pub fn warp(value: u8, gamma: f32) -> u8 {
+    ((f32::from(value) / 255.0).powf(1.0 / gamma) * 255.0).round() as u8
+}
+
+#[test]
+fn hex_colors_are_parsed() {
+    assert_eq!("#ff8000".parse(), Ok(HexColor(Rgb([255, 128, 0]))));
+    assert_eq!("00A0ff".parse(), Ok(HexColor(Rgb([0, 160, 255]))));
+    assert!("#fff".parse::<HexColor>().is_err());
+    assert!("#gg0000".parse::<HexColor>().is_err());
+}
+
+#[test]
+fn linear_light_round_trips() {
+    for v in 0..=255 {
+        assert_eq!(linear_to_srgb(srgb_to_linear(v)), v);
+    }
+    // half the light is much brighter than half the sRGB value
+    assert_eq!(linear_to_srgb(0.5), 188);
+}
+
+#[test]
+fn values_are_quantized_to_bucket_centers() {
+    let four: Vec<u8> = [0, 63, 64, 130, 255]
+        .iter()
+        .map(|&v| quantize(v, 4))
+        .collect();
+    assert_eq!(four, vec![32, 32, 96, 160, 224]);
+    assert_eq!(quantize(0, 2), 64);
+    assert_eq!(quantize(200, 256), 200);
+}
+
+#[test]
+fn srgb_is_converted_to_lab() {
+    let close = |a: [f64; 3], b: [f64; 3]| a.iter().zip(&b).all(|(a, b)| (a - b).abs() < 0.05);
+    assert!(close(srgb_to_lab([255, 255, 255]), [100.0, 0.0, 0.0]));
+    assert!(close(srgb_to_lab([0, 0, 0]), [0.0, 0.0, 0.0]));
+    assert!(close(srgb_to_lab([255, 0, 0]), [53.24, 80.09, 67.20]));
+    assert!(close(srgb_to_lab([0, 0, 255]), [32.30, 79.19, -107.86]));
+    assert_eq!(Metric::Lab.key([255, 255, 255]), [1000, 0, 0]);
+    assert_eq!(Metric::Rgb.key([1, 2, 3]), [1, 2, 3]);
+    assert!("xyz".parse::<Metric>().is_err());
+}
+
+#[test]
+fn ciede2000_matches_published_pairs() {
+    // from the test data of Sharma, Wu and Dalal
+    let pairs = [
+        ([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485], 2.0425),
+        ([50.0, 0.0, 0.0], [50.0, -1.0, 2.0], 2.3669),
+        ([50.0, 2.49, -0.001], [50.0, -2.49, 0.0009], 7.1792),
+        ([50.0, -0.001, 2.49], [50.0, 0.0009, -2.49], 4.8045),
+        ([50.0, 2.5, 0.0], [50.0, 3.2592, 0.335], 1.0),
+        ([50.0, 2.5, 0.0], [73.0, 25.0, -18.0], 27.1492),
+        (
+            [60.2574, -34.0099, 36.2677],
+            [60.4626, -34.1751, 39.4387],
+            1.2644,
+        ),
+        (
+            [22.7233, 20.0904, -46.694],
+            [23.0331, 14.973, -42.5619],
+            2.0373,
+        ),
+        ([2.0776, 0.0795, -1.135], [0.9033, -0.0636, -0.5514], 0.9082),
+    ];
+    for &(a, b, expected) in &pairs {
+        assert!((ciede2000(a, b) - expected).abs() < 1e-4, "{:?} {:?}", a, b);
+        assert!((ciede2000(b, a) - expected).abs() < 1e-4, "{:?} {:?}", b, a);
+    }
+    assert_eq!(ciede2000([40.0, 10.0, 10.0], [40.0, 10.0, 10.0]), 0.0);
+    assert_eq!(
+        key_to_lab(Metric::Lab.key([255, 255, 255])),
+        [100.0, 0.0, 0.0]
+    );
+}
+
+#[test]
+fn hsv_keys_have_no_seam_at_red() {
+    let (hue, saturation, value) = srgb_to_hsv([0, 255, 0]);
+    assert_eq!((hue, saturation, value), (120.0, 1.0, 1.0));
+    assert_eq!(srgb_to_hsv([255, 0, 1]).0.round(), 360.0);
+    let dist = |a: [u8; 3], b: [u8; 3]| {
+        let (a, b) = (Metric::Hsv.key(a), Metric::Hsv.key(b));
+        (0..3)
+            .map(|c| (i64::from(a[c]) - i64::from(b[c])).pow(2))
+            .sum::<i64>()
+    };
+    // just below and just above a hue of 0 are neighbors
+    assert!(dist([255, 0, 4], [255, 4, 0]) < dist([255, 0, 4], [255, 40, 0]));
+    assert_eq!(Metric::Hsv.key([90, 90, 90])[..2], [0, 0]);
+}
+
+#[test]
+fn channel_weights_scale_keys() {
+    let weights: ChannelWeights = "2,1,0.5".parse().unwrap();
+    assert_eq!(weights.apply([100, 100, 100]), [200, 100, 50]);
+    assert_eq!(ChannelWeights::default().apply([1, 2, 3]), [1, 2, 3]);
+    assert!("1,0,1".parse::<ChannelWeights>().is_err());
+    assert!("1,-1,1".parse::<ChannelWeights>().is_err());
+    assert!("1,1".parse::<ChannelWeights>().is_err());
+    assert!("1,1,NaN".parse::<ChannelWeights>().is_err());
+}
+
+#[test]
+fn gray_world_gains_are_clamped() {
+    assert_eq!(Gains::gray_world([100.0, 100.0, 100.0]), Gains::NONE);
+    let Gains([r, g, b]) = Gains::gray_world([150.0, 100.0, 50.0]);
+    assert!((r - 2.0 / 3.0).abs() < 1e-6 && g == 1.0 && b == 2.0);
+    assert_eq!(
+        Gains::gray_world([240.0, 30.0, 0.0]),
+        Gains([0.5, 2.0, 2.0])
+    );
+    assert_eq!(
+        Gains([0.5, 1.0, 2.0]).apply([200, 200, 200]),
+        [100, 200, 255]
+    );
+    assert_eq!(
+        "1.05,1,0.92".parse::<WhiteBalance>(),
+        Ok(WhiteBalance::Manual(Gains([1.05, 1.0, 0.92])))
+    );
+    assert_eq!("auto".parse::<WhiteBalance>(), Ok(WhiteBalance::Auto));
+    assert!("3,1,1".parse::<WhiteBalance>().is_err());
+    assert!("1,1".parse::<WhiteBalance>().is_err());
+}
+
+#[test]
+fn warping_spreads_out_dark_values() {
+    assert_eq!(warp(0, 2.2), 0);
+    assert_eq!(warp(255, 2.2), 255);
+    assert_eq!(warp(10, 2.2), 59);
+    assert_eq!(warp(11, 2.2), 61);
+    assert_eq!(warp(245, 2.2), 250);
+    assert!((0..=255).all(|v| warp(v, 1.0) == v));
+}
+
+#[test]
+fn oklab_matches_reference_values() {
+    // the sRGB primaries and white as given in CSS Color 4
+    let references = [
+        ([255, 255, 255], [1.0, 0.0, 0.0]),
+        ([255, 0, 0], [0.62796, 0.22486, 0.12585]),
+        ([0, 255, 0], [0.86644, -0.23389, 0.1795]),
+        ([0, 0, 255], [0.45201, -0.03246, -0.31153]),
+    ];
+    for (rgb, expected) in references {
+        let lab = srgb_to_oklab(rgb);
+        for (v, e) in lab.iter().zip(expected.iter()) {
+            assert!((v - e).abs() < 1e-4, "{:?}: {:?}", rgb, lab);
+        }
+    }
+    for rgb in [[0, 0, 0], [12, 200, 99], [250, 3, 180], [128, 128, 128]] {
+        assert_eq!(oklab_to_srgb(srgb_to_oklab(rgb)), rgb);
+    }
+    assert_eq!(Metric::Oklab.key([0, 0, 255]), [452, -32, -312]);
+}
+
+#[test]
+fn temperature_grades_warm_and_cool() {
+    assert_eq!(Gains::temperature(0.0), Gains::NONE);
+    assert_eq!(Gains::temperature(0.0).inverse(), Gains::NONE);
+    assert_eq!(Gains::temperature(1000.0).apply([200; 3]), [220, 200, 180]);
+    assert_eq!(Gains::temperature(-1000.0).apply([200; 3]), [180, 200, 220]);
+    // channels stay within 0-255
+    let warmest = Gains::temperature(MAX_TEMPERATURE);
+    assert_eq!(warmest.apply([250, 0, 255]), [255, 0, 128]);
+    let graded = warmest.apply([100, 100, 100]);
+    assert_eq!(warmest.inverse().apply(graded), [100, 100, 100]);
+}
@@ -0,0 +1,331 @@
+use image::codecs::hdr::HdrDecoder;
+use image::gif::GifDecoder;
+use image::{AnimationDecoder, DynamicImage, GrayImage, ImageFormat, Rgb, RgbImage};
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+/// Settings shared by every place an image gets decoded, so source tiles and
+/// the target go through the same conversions.
+#[derive(Debug, Clone)]
+pub struct DecodeOptions {
+    pub ignore_exif: bool,
+    /// how many frames of an animation to use, all of them if `None`
+    pub max_frames: Option<usize>,
+    /// color transparent pixels are composited over
+    pub background: Rgb<u8>,
+    /// stops high dynamic range images are brightened by before tone mapping
+    pub exposure: f32,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            ignore_exif: false,
+            max_frames: None,
+            background: Rgb([255, 255, 255]),
+            exposure: 0.0,
+        }
+    }
+}
+
+/// A decoded image, composited over the background if it had transparency.
+pub struct Decoded {
+    pub rgb: RgbImage,
+    /// the original alpha channel, `None` if the image was fully opaque
+    pub alpha: Option<GrayImage>,
+    /// whether 16-bit samples had to be rounded to 8 bits
+    pub precision_lost: bool,
+}
+
+pub fn decode_file(path: &Path, opts: &DecodeOptions) -> Result<Decoded, String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    decode_bytes(&bytes, opts)
+}
+
+pub fn decode_bytes(bytes: &[u8], opts: &DecodeOptions) -> Result<Decoded, String> {
+    if image::guess_format(bytes).ok() == Some(ImageFormat::Hdr) {
+        return decode_hdr(bytes, opts.exposure);
+    }
+    let img = image::load_from_memory(bytes).map_err(|e| e.to_string())?;
+    let img = if opts.ignore_exif {
+        img
+    } else {
+        apply_orientation(img, exif_orientation(bytes))
+    };
+    Ok(flatten(img, opts.background))
+}
+
+/// Decodes a Radiance HDR image and tone maps it to 8 bits, instead of
+/// clipping everything brighter than 1.0.
+fn decode_hdr(bytes: &[u8], exposure: f32) -> Result<Decoded, String> {
+    let decoder = HdrDecoder::new(Cursor::new(bytes)).map_err(|e| e.to_string())?;
+    let width = decoder.metadata().width;
+    let pixels = decoder.read_image_hdr().map_err(|e| e.to_string())?;
+    let height = pixels.len() as u32 / width.max(1);
+    let scale = exposure.exp2();
+    let rgb = RgbImage::from_fn(width, height, |x, y| {
+        tone_map(pixels[(y * width + x) as usize], scale)
+    });
+    Ok(Decoded {
+        rgb,
+        alpha: None,
+        precision_lost: false,
+    })
+}
+
+/// Reinhard's `v / (1 + v)` after multiplying by `scale`, then gamma 2.2.
+fn tone_map(p: Rgb<f32>, scale: f32) -> Rgb<u8> {
+    let map = |v: f32| {
+        let v = (v * scale).max(0.0);
+        ((v / (1.0 + v)).powf(1.0 / 2.2) * 255.0).round() as u8
+    };
+    Rgb([map(p[0]), map(p[1]), map(p[2])])
+}
+
+/// Composites images with an alpha channel over `background`, so the colors
+/// that get averaged are the ones that end up in the collage.
+fn flatten(img: DynamicImage, background: Rgb<u8>) -> Decoded {
+    let precision_lost = loses_precision(&img);
+    if !img.color().has_alpha() {
+        return Decoded {
+            rgb: to_rgb8(img),
+            alpha: None,
+            precision_lost,
+        };
+    }
+    let rgba = img.into_rgba8();
+    if rgba.pixels().all(|p| p[3] == 255) {
+        return Decoded {
+            rgb: DynamicImage::ImageRgba8(rgba).into_rgb8(),
+            alpha: None,
+            precision_lost,
+        };
+    }
+    let rgb = RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let p = rgba.get_pixel(x, y);
+        let a = u32::from(p[3]);
+        let mix =
+            |c: u8, bg: u8| ((u32::from(c) * a + u32::from(bg) * (255 - a) + 127) / 255) as u8;
+        Rgb([
+            mix(p[0], background[0]),
+            mix(p[1], background[1]),
+            mix(p[2], background[2]),
+        ])
+    });
+    let alpha = GrayImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        image::Luma([rgba.get_pixel(x, y)[3]])
+    });
+    Decoded {
+        rgb,
+        alpha: Some(alpha),
+        precision_lost,
+    }
+}
+
+/// Converts opaque images to 8-bit RGB, spreading gray over all three
+/// channels and rounding 16-bit samples to the nearest 8-bit value.
+fn to_rgb8(img: DynamicImage) -> RgbImage {
+    match img {
+        DynamicImage::ImageRgb8(rgb) => rgb,
+        DynamicImage::ImageLuma8(gray) => RgbImage::from_fn(gray.width(), gray.height(), |x, y| {
+            let l = gray.get_pixel(x, y)[0];
+            Rgb([l, l, l])
+        }),
+        DynamicImage::ImageLuma16(gray) => {
+            RgbImage::from_fn(gray.width(), gray.height(), |x, y| {
+                let l = scale_16_to_8(gray.get_pixel(x, y)[0]);
+                Rgb([l, l, l])
+            })
+        }
+        DynamicImage::ImageRgb16(rgb) => RgbImage::from_fn(rgb.width(), rgb.height(), |x, y| {
+            let p = rgb.get_pixel(x, y);
+            Rgb([
+                scale_16_to_8(p[0]),
+                scale_16_to_8(p[1]),
+                scale_16_to_8(p[2]),
+            ])
+        }),
+        other => other.into_rgb8(),
+    }
+}
+
+fn scale_16_to_8(v: u16) -> u8 {
+    ((u32::from(v) * 255 + 32767) / 65535) as u8
+}
+
+/// Whether `img` has 16-bit samples that don't map exactly onto 8 bits.
+fn loses_precision(img: &DynamicImage) -> bool {
+    let exact = |v: &u16| v % 257 == 0;
+    match img {
+        DynamicImage::ImageLuma16(i) => !i.as_raw().iter().all(exact),
+        DynamicImage::ImageLumaA16(i) => !i.as_raw().iter().all(exact),
+        DynamicImage::ImageRgb16(i) => !i.as_raw().iter().all(exact),
+        DynamicImage::ImageRgba16(i) => !i.as_raw().iter().all(exact),
+        _ => false,
+    }
+}
+
+/// Like `decode_file`, but yields every frame of an animated GIF.
+pub fn decode_file_frames(path: &Path, opts: &DecodeOptions) -> Result<Vec<Decoded>, String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    decode_frames(&bytes, opts)
+}
+
+/// Decodes all frames of an animated GIF, up to `opts.max_frames`. Other
+/// formats yield a single frame.
+pub fn decode_frames(bytes: &[u8], opts: &DecodeOptions) -> Result<Vec<Decoded>, String> {
+    if image::guess_format(bytes).ok() != Some(ImageFormat::Gif) {
+        return decode_bytes(bytes, opts).map(|img| vec![img]);
+    }
+    let decoder = GifDecoder::new(Cursor::new(bytes)).map_err(|e| e.to_string())?;
+    decoder
+        .into_frames()
+        .take(opts.max_frames.unwrap_or(usize::MAX))
+        .map(|frame| {
+            frame
+                .map(|f| flatten(DynamicImage::ImageRgba8(f.into_buffer()), opts.background))
+                .map_err(|e| e.to_string())
+        })
+        .collect()
+}
+
+/// Names the frames of an animation like `file.gif#frame12`.
+pub fn frame_label(source: &str, frame: usize, frames: usize) -> String {
+    if frames > 1 {
+        format!("{}#frame{}", source, frame)
+    } else {
+        source.to_string()
+    }
+}
+
+/// Reads the EXIF orientation tag, 1 (upright) if there is none.
+fn exif_orientation(bytes: &[u8]) -> u32 {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(bytes))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+                .and_then(|f| f.value.get_uint(0))
+        })
+        .unwrap_or(1)
+}
+
+/// Rotates and flips `img` so that it is upright for the given EXIF
+/// orientation value.
+fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+#[test]
+fn orientations_make_images_upright() {
+    // 2x1 image, red on the left and blue on the right
+    let mut img = RgbImage::new(2, 1);
+    img.put_pixel(0, 0, image::Rgb([255, 0, 0]));
+    img.put_pixel(1, 0, image::Rgb([0, 0, 255]));
+    let oriented = |o| apply_orientation(DynamicImage::ImageRgb8(img.clone()), o).into_rgb8();
+
+    assert_eq!(oriented(1), img);
+    assert_eq!(oriented(3).get_pixel(0, 0), &image::Rgb([0, 0, 255]));
+    let rotated = oriented(6);
+    assert_eq!(rotated.dimensions(), (1, 2));
+    assert_eq!(rotated.get_pixel(0, 0), &image::Rgb([255, 0, 0]));
+    let transposed = oriented(5);
+    assert_eq!(transposed.dimensions(), (1, 2));
+    assert_eq!(transposed.get_pixel(0, 1), &image::Rgb([0, 0, 255]));
+    assert_eq!(oriented(8).get_pixel(0, 0), &image::Rgb([0, 0, 255]));
+}
+
+#[test]
+fn images_without_exif_are_unchanged() {
+    let img = RgbImage::from_pixel(3, 2, image::Rgb([10, 20, 30]));
+    let mut png = Vec::new();
+    DynamicImage::ImageRgb8(img.clone())
+        .write_to(&mut png, image::ImageOutputFormat::Png)
+        .unwrap();
+    let decoded = decode_bytes(&png, &DecodeOptions::default()).unwrap();
+    assert_eq!(decoded.rgb, img);
+    assert!(decoded.alpha.is_none());
+}
+
+#[test]
+fn gif_frames_are_decoded_separately() {
+    let frames: Vec<image::Frame> = [[255, 0, 0, 255], [0, 0, 255, 255], [0, 255, 0, 255]]
+        .iter()
+        .map(|&c| image::Frame::new(image::RgbaImage::from_pixel(4, 4, image::Rgba(c))))
+        .collect();
+    let mut gif = Vec::new();
+    image::gif::GifEncoder::new(&mut gif)
+        .encode_frames(frames)
+        .unwrap();
+
+    let opts = DecodeOptions {
+        max_frames: Some(2),
+        ..DecodeOptions::default()
+    };
+    let decoded = decode_frames(&gif, &opts).unwrap();
+    assert_eq!(decoded.len(), 2);
+    assert_eq!(decoded[0].rgb.get_pixel(0, 0), &image::Rgb([255, 0, 0]));
+    assert_eq!(decoded[1].rgb.get_pixel(0, 0), &image::Rgb([0, 0, 255]));
+    assert_eq!(frame_label("a.gif", 1, 2), "a.gif#frame1");
+    assert_eq!(frame_label("a.gif", 0, 1), "a.gif");
+}
+
+#[test]
+fn transparency_is_composited_over_the_background() {
+    let mut rgba = image::RgbaImage::from_pixel(2, 1, image::Rgba([0, 0, 0, 0]));
+    rgba.put_pixel(1, 0, image::Rgba([200, 0, 0, 128]));
+    let decoded = flatten(DynamicImage::ImageRgba8(rgba), Rgb([0, 0, 255]));
+    assert_eq!(decoded.rgb.get_pixel(0, 0), &Rgb([0, 0, 255]));
+    assert_eq!(decoded.rgb.get_pixel(1, 0), &Rgb([100, 0, 127]));
+    assert_eq!(decoded.alpha.unwrap().get_pixel(1, 0), &image::Luma([128]));
+}
+
+#[test]
+fn sixteen_bit_samples_are_scaled() {
+    let img = image::ImageBuffer::from_fn(2, 1, |x, _| {
+        image::Rgb([if x == 0 { 65535 } else { 32900u16 }, 0, 257])
+    });
+    let decoded = flatten(DynamicImage::ImageRgb16(img), Rgb([255, 255, 255]));
+    assert_eq!(decoded.rgb.get_pixel(0, 0), &Rgb([255, 0, 1]));
+    assert_eq!(decoded.rgb.get_pixel(1, 0), &Rgb([128, 0, 1]));
+    assert!(decoded.precision_lost);
+
+    let exact = image::ImageBuffer::from_pixel(2, 1, image::Luma([257u16 * 7]));
+    let decoded = flatten(DynamicImage::ImageLuma16(exact), Rgb([255, 255, 255]));
+    assert_eq!(decoded.rgb.get_pixel(1, 0), &Rgb([7, 7, 7]));
+    assert!(!decoded.precision_lost);
+}
+
+#[test]
+fn hdr_images_are_tone_mapped() {
+    let pixels = [Rgb([0.0, 1.0, 50.0]), Rgb([0.25, 0.25, 0.25])];
+    let mut hdr = Vec::new();
+    image::codecs::hdr::HdrEncoder::new(&mut hdr)
+        .encode(&pixels, 2, 1)
+        .unwrap();
+    let decoded = decode_bytes(&hdr, &DecodeOptions::default()).unwrap();
+    assert_eq!(decoded.rgb.dimensions(), (2, 1));
+    let p = decoded.rgb.get_pixel(0, 0);
+    assert_eq!(p[0], 0);
+    assert!(p[1] > 150 && p[1] < 200);
+    assert!(p[2] > 250);
+
+    let brighter = DecodeOptions {
+        exposure: 2.0,
+        ..DecodeOptions::default()
+    };
+    let gray = decode_bytes(&hdr, &DecodeOptions::default()).unwrap().rgb[(1, 0)];
+    let lifted = decode_bytes(&hdr, &brighter).unwrap().rgb[(1, 0)];
+    assert_eq!(lifted, Rgb([p[1]; 3]));
+    assert!(gray[0] < lifted[0]);
+}
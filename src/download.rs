@@ -0,0 +1,115 @@
+use crate::input::stable_hash;
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+use std::fs;
+use std::io::{self, BufRead, Read};
+use std::path::{Path, PathBuf};
+
+/// Upper bound on requests in flight at the same time.
+const MAX_CONCURRENT_DOWNLOADS: usize = 8;
+
+/// URLs that could not be fetched, each with the last error.
+pub type Failures = Vec<(String, String)>;
+
+/// Reads one URL per line, ignoring blank lines and `#` comments.
+pub fn read_url_list(reader: impl BufRead) -> io::Result<Vec<String>> {
+    let mut urls = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            urls.push(line.to_string());
+        }
+    }
+    Ok(urls)
+}
+
+/// Downloads every URL, retrying each failure once. Bytes found in
+/// `cache_dir` are used instead of the network, and fresh downloads are
+/// written there. Returns the fetched bodies in `urls` order and the URLs
+/// that could not be fetched.
+pub fn fetch_all(urls: &[String], cache_dir: Option<&Path>) -> (Vec<(String, Vec<u8>)>, Failures) {
+    if let Some(dir) = cache_dir {
+        if let Err(e) = fs::create_dir_all(dir) {
+            eprintln!(
+                "warning: could not create cache dir {}: {}",
+                dir.display(),
+                e
+            );
+        }
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(MAX_CONCURRENT_DOWNLOADS)
+        .build()
+        .unwrap();
+    let bar = ProgressBar::new(urls.len() as u64);
+    let results: Vec<Result<Vec<u8>, String>> = pool.install(|| {
+        urls.par_iter()
+            .map(|url| {
+                let body = fetch_cached(url, cache_dir);
+                bar.inc(1);
+                body
+            })
+            .collect()
+    });
+    bar.finish_and_clear();
+
+    let mut fetched = Vec::new();
+    let mut failed = Vec::new();
+    for (url, result) in urls.iter().zip(results) {
+        match result {
+            Ok(bytes) => fetched.push((url.clone(), bytes)),
+            Err(e) => failed.push((url.clone(), e)),
+        }
+    }
+    (fetched, failed)
+}
+
+fn fetch_cached(url: &str, cache_dir: Option<&Path>) -> Result<Vec<u8>, String> {
+    let cached = cache_dir.map(|dir| cache_path(dir, url));
+    if let Some(bytes) = cached.as_ref().and_then(|p| fs::read(p).ok()) {
+        return Ok(bytes);
+    }
+    let bytes = fetch(url).or_else(|_| fetch(url))?;
+    if let Some(path) = cached {
+        if let Err(e) = fs::write(&path, &bytes) {
+            eprintln!("warning: could not cache {}: {}", url, e);
+        }
+    }
+    Ok(bytes)
+}
+
+fn fetch(url: &str) -> Result<Vec<u8>, String> {
+    let response = ureq::get(url).call().map_err(|e| e.to_string())?;
+    let mut bytes = Vec::new();
+    response
+        .into_reader()
+        .read_to_end(&mut bytes)
+        .map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+/// Cache file names are a hash of the URL.
+fn cache_path(dir: &Path, url: &str) -> PathBuf {
+    dir.join(format!("{:016x}", stable_hash(url)))
+}
+
+#[test]
+fn url_lists_skip_comments_and_blanks() {
+    let list = "https://a.example/1.jpg\n\n# comment\n  https://a.example/2.png  \n";
+    assert_eq!(
+        read_url_list(list.as_bytes()).unwrap(),
+        vec!["https://a.example/1.jpg", "https://a.example/2.png"]
+    );
+}
+
+#[test]
+fn cached_bytes_are_used_without_fetching() {
+    let dir = crate::input::scratch_dir("cache");
+    let url = "http://invalid.invalid/tile.png";
+    fs::write(cache_path(&dir, url), b"cached").unwrap();
+    let (fetched, failed) = fetch_all(&[url.to_string()], Some(&dir));
+    assert!(failed.is_empty());
+    assert_eq!(fetched, vec![(url.to_string(), b"cached".to_vec())]);
+    fs::remove_dir_all(&dir).unwrap();
+}
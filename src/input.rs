@@ -0,0 +1,509 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Extensions of the formats `image::open` can decode, in lowercase.
+pub const DEFAULT_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "webp", "bmp", "tif", "tiff", "gif", "hdr",
+];
+
+/// Set of lowercase file extensions an input file must have to be considered.
+#[derive(Debug, Clone)]
+pub struct Extensions(BTreeSet<String>);
+
+impl Default for Extensions {
+    fn default() -> Self {
+        Extensions(DEFAULT_EXTENSIONS.iter().map(|e| e.to_string()).collect())
+    }
+}
+
+impl Extensions {
+    pub fn matches(&self, path: &Path) -> bool {
+        path.extension()
+            .and_then(OsStr::to_str)
+            .is_some_and(|e| self.0.contains(&e.to_ascii_lowercase()))
+    }
+}
+
+/// Parses a comma separated list like `jpg,.PNG,webp` for `--extensions`.
+impl FromStr for Extensions {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut set = BTreeSet::new();
+        for part in value.split(',') {
+            let ext = part.trim().trim_start_matches('.');
+            if ext.is_empty() {
+                return Err(format!("empty extension in list \"{}\"", value));
+            }
+            if !ext.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(format!("invalid extension \"{}\"", part.trim()));
+            }
+            set.insert(ext.to_ascii_lowercase());
+        }
+        Ok(Extensions(set))
+    }
+}
+
+/// Controls how `find_input_images` walks the input directory.
+#[derive(Debug, Clone, Default)]
+pub struct DirOptions {
+    pub extensions: Extensions,
+    pub recursive: bool,
+    pub no_follow_symlinks: bool,
+}
+
+/// Lists the images in the directory `path`, sorted so the result does not
+/// depend on the order the filesystem returns entries in. If `path` is an
+/// image file itself it is the only input.
+pub fn find_input_images(path: &Path, opts: &DirOptions) -> io::Result<Vec<PathBuf>> {
+    if fs::metadata(path)?.is_file() {
+        if image::ImageFormat::from_path(path).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "neither a directory nor a decodable image",
+            ));
+        }
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut found = Vec::new();
+    let mut visited = HashSet::new();
+    visited.insert(fs::canonicalize(path)?);
+    visit_dir(path, opts, &mut visited, &mut found)?;
+    found.sort();
+    Ok(found)
+}
+
+/// `visited` holds the canonicalized directories seen so far, which stops
+/// symlinks pointing back up the tree from recursing forever.
+fn visit_dir(
+    dir: &Path,
+    opts: &DirOptions,
+    visited: &mut HashSet<PathBuf>,
+    found: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)?.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let is_link = entry.file_type().is_ok_and(|t| t.is_symlink());
+        if is_link && opts.no_follow_symlinks {
+            continue;
+        }
+        let meta = match fs::metadata(&path) {
+            Ok(meta) => meta,
+            Err(_) if is_link => {
+                eprintln!("warning: skipping dangling symlink {}", path.display());
+                continue;
+            }
+            Err(_) => continue,
+        };
+        if meta.is_dir() {
+            if !opts.recursive {
+                continue;
+            }
+            let real = match fs::canonicalize(&path) {
+                Ok(real) => real,
+                Err(e) => {
+                    eprintln!("warning: could not resolve {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if !visited.insert(real) {
+                eprintln!("warning: skipping symlink cycle at {}", path.display());
+                continue;
+            }
+            if let Err(e) = visit_dir(&path, opts, visited, found) {
+                eprintln!("warning: could not read {}: {}", path.display(), e);
+            }
+        } else if opts.extensions.matches(&path) {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Reads newline separated paths, as produced by `fd` or `find`, resolving
+/// relative ones against the current directory. Paths that don't exist are
+/// returned separately so the caller can report them instead of failing.
+pub fn read_input_list(reader: impl BufRead) -> io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let cwd = std::env::current_dir()?;
+    let mut found = Vec::new();
+    let mut missing = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+        let path = cwd.join(line);
+        if path.is_file() {
+            found.push(path);
+        } else {
+            missing.push(path);
+        }
+    }
+    Ok((found, missing))
+}
+
+/// Expands a glob like `photos/**/*.jpg`, keeping only files with one of the
+/// given extensions. The result is deduplicated and sorted so repeated runs
+/// see the same order.
+pub fn glob_input_images(pattern: &str, extensions: &Extensions) -> Result<Vec<PathBuf>, String> {
+    let paths = glob::glob(pattern).map_err(|e| format!("Invalid glob {}: {}", pattern, e))?;
+    let found: BTreeSet<PathBuf> = paths
+        .filter_map(|p| p.ok())
+        .filter(|p| p.is_file() && extensions.matches(p))
+        .collect();
+    Ok(found.into_iter().collect())
+}
+
+/// A `--exclude` glob, matched against the file name and against each
+/// component of the path, so both `*_thumb.jpg` and `.trashed-*` work.
+#[derive(Debug, Clone)]
+pub struct Exclude(glob::Pattern);
+
+impl FromStr for Exclude {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        glob::Pattern::new(value)
+            .map(Exclude)
+            .map_err(|e| format!("invalid exclude pattern \"{}\": {}", value, e))
+    }
+}
+
+impl Exclude {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        self.0.matches_path(path)
+            || path
+                .components()
+                .filter_map(|c| c.as_os_str().to_str())
+                .any(|c| self.0.matches(c))
+    }
+}
+
+/// Splits `paths` into the kept ones and the excluded ones, paired with the
+/// first pattern that excluded them.
+pub fn apply_excludes(
+    paths: Vec<PathBuf>,
+    excludes: &[Exclude],
+) -> (Vec<PathBuf>, Vec<(PathBuf, &Exclude)>) {
+    let mut kept = Vec::with_capacity(paths.len());
+    let mut excluded = Vec::new();
+    for path in paths {
+        match excludes.iter().find(|e| e.matches(&path)) {
+            Some(e) => excluded.push((path, e)),
+            None => kept.push(path),
+        }
+    }
+    (kept, excluded)
+}
+
+/// Drops paths that resolve to a file listed earlier, e.g. when the same
+/// directory is passed twice under different names.
+pub fn dedup_canonical(paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    paths
+        .into_iter()
+        .filter(|p| seen.insert(fs::canonicalize(p).unwrap_or_else(|_| p.clone())))
+        .collect()
+}
+
+/// Drops files whose contents are byte-identical to an earlier file in
+/// `paths`, returning the kept paths and the number dropped. Only files that
+/// share their size with another file are read and hashed, and only those
+/// with the hash of a kept file are compared with it.
+pub fn dedup_identical(paths: Vec<PathBuf>) -> (Vec<PathBuf>, usize) {
+    let sizes: Vec<Option<u64>> = paths
+        .iter()
+        .map(|p| fs::metadata(p).ok().map(|m| m.len()))
+        .collect();
+    let mut size_counts: HashMap<u64, usize> = HashMap::new();
+    for size in sizes.iter().flatten() {
+        *size_counts.entry(*size).or_default() += 1;
+    }
+
+    // indices into `kept` by length and hash of the contents
+    let mut seen: HashMap<(usize, u64), Vec<usize>> = HashMap::new();
+    let mut kept = Vec::with_capacity(paths.len());
+    let mut dropped = 0;
+    for (path, size) in paths.into_iter().zip(sizes) {
+        let shared = size.is_some_and(|s| size_counts[&s] > 1);
+        if shared {
+            if let Ok(bytes) = fs::read(&path) {
+                let mut hasher = DefaultHasher::new();
+                bytes.hash(&mut hasher);
+                let same_hash = seen.entry((bytes.len(), hasher.finish())).or_default();
+                if same_hash
+                    .iter()
+                    .any(|&i| fs::read(&kept[i]).is_ok_and(|other| other == bytes))
+                {
+                    dropped += 1;
+                    continue;
+                }
+                same_hash.push(kept.len());
+            }
+        }
+        kept.push(path);
+    }
+    (kept, dropped)
+}
+
+/// FNV-1a hash of `s`, which unlike `DefaultHasher` stays the same across
+/// builds.
+pub fn stable_hash(s: &str) -> u64 {
+    stable_hash_bytes(s.as_bytes())
+}
+
+/// `stable_hash` of arbitrary bytes, like the contents of a file.
+pub fn stable_hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Picks at most `max` of `items`, the same ones for the same `seed`. The
+/// picked items keep their order in `items`.
+pub fn sample<T>(items: Vec<T>, max: usize, seed: u64) -> Vec<T> {
+    if items.len() <= max {
+        return items;
+    }
+    let mut picked: Vec<usize> = (0..items.len()).collect();
+    picked.shuffle(&mut StdRng::seed_from_u64(seed));
+    let picked: HashSet<usize> = picked.into_iter().take(max).collect();
+    items
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| picked.contains(i))
+        .map(|(_, item)| item)
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("collagen-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn extensions_are_matched_case_insensitively() {
+    let dir = scratch_dir("extensions");
+    for name in &[
+        "IMG_0001.JPG",
+        "b.jpeg",
+        "c.Png",
+        "d.webp",
+        "e.txt",
+        "f",
+        "g.jpg.bak",
+    ] {
+        fs::write(dir.join(name), b"").unwrap();
+    }
+    let mut found: Vec<String> = find_input_images(&dir, &DirOptions::default())
+        .unwrap()
+        .iter()
+        .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+        .collect();
+    found.sort();
+    assert_eq!(found, vec!["IMG_0001.JPG", "b.jpeg", "c.Png", "d.webp"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn extension_lists_are_normalized() {
+    let exts = "JPG, .png,webp".parse::<Extensions>().unwrap();
+    assert!(exts.matches(Path::new("a.jpg")));
+    assert!(exts.matches(Path::new("b.PNG")));
+    assert!(!exts.matches(Path::new("c.tiff")));
+    assert!("".parse::<Extensions>().is_err());
+    assert!("jpg,,png".parse::<Extensions>().is_err());
+    assert!("jp g".parse::<Extensions>().is_err());
+}
+
+#[test]
+fn input_lists_report_missing_paths() {
+    let dir = scratch_dir("list");
+    fs::write(dir.join("a.jpg"), b"").unwrap();
+    let list = format!(
+        "{}\r\n\n{}\n",
+        dir.join("a.jpg").display(),
+        dir.join("gone.jpg").display()
+    );
+    let (found, missing) = read_input_list(list.as_bytes()).unwrap();
+    assert_eq!(found, vec![dir.join("a.jpg")]);
+    assert_eq!(missing, vec![dir.join("gone.jpg")]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn globs_are_filtered_and_sorted() {
+    let dir = scratch_dir("glob");
+    fs::create_dir_all(dir.join("nested/deeper")).unwrap();
+    for name in &[
+        "b.jpg",
+        "a.JPG",
+        "nested/c.png",
+        "nested/deeper/d.jpg",
+        "e.txt",
+    ] {
+        fs::write(dir.join(name), b"").unwrap();
+    }
+    let pattern = format!("{}/**/*", dir.display());
+    let exts: Extensions = "jpg".parse().unwrap();
+    let found = glob_input_images(&pattern, &exts).unwrap();
+    assert_eq!(
+        found,
+        vec![
+            dir.join("a.JPG"),
+            dir.join("b.jpg"),
+            dir.join("nested/deeper/d.jpg")
+        ]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn excludes_match_names_and_components() {
+    let excludes: Vec<Exclude> = vec![
+        "*_thumb.jpg".parse().unwrap(),
+        ".trashed-*".parse().unwrap(),
+    ];
+    let paths = vec![
+        PathBuf::from("input/a.jpg"),
+        PathBuf::from("input/a_thumb.jpg"),
+        PathBuf::from("input/.trashed-123/b.jpg"),
+        PathBuf::from("input/.trashed-c.jpg"),
+    ];
+    let (kept, excluded) = apply_excludes(paths, &excludes);
+    assert_eq!(kept, vec![PathBuf::from("input/a.jpg")]);
+    let excluded: Vec<(&Path, &str)> = excluded
+        .iter()
+        .map(|(p, e)| (p.as_path(), e.as_str()))
+        .collect();
+    assert_eq!(
+        excluded,
+        vec![
+            (Path::new("input/a_thumb.jpg"), "*_thumb.jpg"),
+            (Path::new("input/.trashed-123/b.jpg"), ".trashed-*"),
+            (Path::new("input/.trashed-c.jpg"), ".trashed-*"),
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks_are_followed_without_looping() {
+    use std::os::unix::fs::symlink;
+
+    let dir = scratch_dir("symlinks");
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("sub/a.jpg"), b"").unwrap();
+    symlink(dir.join("sub/a.jpg"), dir.join("link.jpg")).unwrap();
+    symlink(dir.join("nowhere.jpg"), dir.join("dangling.jpg")).unwrap();
+    symlink(&dir, dir.join("sub/loop")).unwrap();
+
+    let names = |opts: &DirOptions| -> Vec<PathBuf> {
+        let mut found: Vec<PathBuf> = find_input_images(&dir, opts)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.strip_prefix(&dir).unwrap().to_path_buf())
+            .collect();
+        found.sort();
+        found
+    };
+    let mut opts = DirOptions {
+        recursive: true,
+        ..DirOptions::default()
+    };
+    assert_eq!(
+        names(&opts),
+        vec![PathBuf::from("link.jpg"), PathBuf::from("sub/a.jpg")]
+    );
+    opts.no_follow_symlinks = true;
+    assert_eq!(names(&opts), vec![PathBuf::from("sub/a.jpg")]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn identical_files_are_dropped() {
+    let dir = scratch_dir("dedup");
+    fs::write(dir.join("a.jpg"), b"same").unwrap();
+    fs::write(dir.join("b.jpg"), b"diff").unwrap();
+    fs::write(dir.join("c.jpg"), b"same").unwrap();
+    fs::write(dir.join("d.jpg"), b"longer").unwrap();
+    let paths: Vec<PathBuf> = ["a.jpg", "b.jpg", "c.jpg", "d.jpg"]
+        .iter()
+        .map(|n| dir.join(n))
+        .collect();
+    let (kept, dropped) = dedup_identical(paths);
+    assert_eq!(dropped, 1);
+    assert_eq!(
+        kept,
+        vec![dir.join("a.jpg"), dir.join("b.jpg"), dir.join("d.jpg")]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn sampling_is_deterministic() {
+    let items: Vec<u32> = (0..100).collect();
+    let a = sample(items.clone(), 10, 7);
+    assert_eq!(a.len(), 10);
+    assert_eq!(a, sample(items.clone(), 10, 7));
+    assert_ne!(a, sample(items.clone(), 10, 8));
+    assert!(a.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(sample(items.clone(), 500, 7), items);
+}
+
+#[test]
+fn single_files_are_their_own_input() {
+    let dir = scratch_dir("single");
+    fs::write(dir.join("texture.png"), b"").unwrap();
+    fs::write(dir.join("notes.txt"), b"").unwrap();
+    let opts = DirOptions::default();
+    assert_eq!(
+        find_input_images(&dir.join("texture.png"), &opts).unwrap(),
+        vec![dir.join("texture.png")]
+    );
+    assert!(find_input_images(&dir.join("notes.txt"), &opts).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn listing_order_does_not_change_the_tree() {
+    use crate::blockdb::BlockDb;
+
+    let mut names: Vec<String> = (0..40).map(|i| format!("{:02}.jpg", i)).collect();
+    let mut dots = Vec::new();
+    for seed in &[1, 2] {
+        names.shuffle(&mut StdRng::seed_from_u64(*seed));
+        let dir = scratch_dir(&format!("order{}", seed));
+        for name in &names {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        let found = find_input_images(&dir, &DirOptions::default()).unwrap();
+        let db = BlockDb::new(found, |p| {
+            let hash = stable_hash(&p.file_name().unwrap().to_string_lossy());
+            [(hash % 3) as i16, (hash % 5) as i16, 0]
+        });
+        dots.push(db.to_dot_str());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    assert_eq!(dots[0], dots[1]);
+}
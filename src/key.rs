@@ -0,0 +1,537 @@
+use crate::color::{self, ChannelWeights, Metric};
+use crate::tiles::{self, KeyMode, Orientation};
+use image::imageops;
+use image::{GenericImageView, GrayImage, Rgb, RgbImage, SubImage};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// What tiles and target blocks are matched by, `N` components long.
+pub type Key<const N: usize> = [i16; N];
+
+#[derive(Debug)]
+pub struct Pos {
+    pub r: u64,
+    pub g: u64,
+    pub b: u64,
+}
+
+impl From<Pos> for [i16; 3] {
+    fn from(p: Pos) -> Self {
+        [p.r as i16, p.g as i16, p.b as i16]
+    }
+}
+
+/// How tiles and target blocks are turned into the keys they are matched by.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KeySpec {
+    /// average in linear light instead of on the sRGB values
+    pub linear: bool,
+    /// sigma of the blur source tiles get before their key is taken
+    pub blur: Option<f32>,
+    /// levels per channel keys are rounded to
+    pub levels: Option<u32>,
+    /// gamma the colors are warped with before rounding
+    pub gamma: Option<f32>,
+    pub metric: Metric,
+    pub weights: ChannelWeights,
+    pub mode: KeyMode,
+}
+
+impl KeySpec {
+    /// The key of a source tile, which unlike target blocks may be blurred
+    /// first and may have an `alpha` channel of the same size, which only
+    /// the visible pixels go into the key by.
+    pub fn tile_key<const N: usize>(self, tile: &RgbImage, alpha: Option<&GrayImage>) -> Key<N> {
+        match self.blur {
+            Some(sigma) => alpha_weighted_key(&imageops::blur(tile, sigma), alpha, &self),
+            None => alpha_weighted_key(tile, alpha, &self),
+        }
+    }
+
+    /// `tile_key` for the manifest, which holds keys of any length.
+    pub fn tile_key_vec(self, tile: &RgbImage, alpha: Option<&GrayImage>) -> Vec<i16> {
+        match self.mode.len() {
+            3 => self.tile_key::<3>(tile, alpha).to_vec(),
+            6 => self.tile_key::<6>(tile, alpha).to_vec(),
+            12 => self.tile_key::<12>(tile, alpha).to_vec(),
+            27 => self.tile_key::<27>(tile, alpha).to_vec(),
+            len => unreachable!("no keys with {} components", len),
+        }
+    }
+
+    /// The color of a whole tile or one of its cells, and with
+    /// `KeyMode::MeanStd` the standard deviation of its sRGB values. Averages
+    /// weigh pixels by their `alpha`, the dominant color and the median
+    /// leave out the fully transparent ones.
+    fn color<I: GenericImageView<Pixel = Rgb<u8>>>(
+        self,
+        img: &I,
+        alpha: Option<&Alpha>,
+    ) -> ([u8; 3], Option<[i16; 3]>) {
+        let (avg, deviation) = match self.mode {
+            KeyMode::Dominant | KeyMode::Median if alpha.is_some() => {
+                let visible: Vec<_> = weighted_pixels(img, alpha).map(|(p, _)| p).collect();
+                let row = RgbImage::from_fn(visible.len() as u32, 1, |x, _| visible[x as usize]);
+                return self.color(&row, None);
+            }
+            KeyMode::CenterWeighted => (center_weighted_color(img, alpha, self.linear), None),
+            KeyMode::Dominant => (tiles::dominant_color(img), None),
+            KeyMode::Median => (tiles::median_color(img), None),
+            KeyMode::MeanStd if !self.linear => {
+                let (avg, deviation) = avg_and_deviation(img, alpha);
+                (<[i16; 3]>::from(avg).map(|c| c as u8), Some(deviation))
+            }
+            KeyMode::MeanStd => {
+                let deviation = avg_and_deviation(img, alpha).1;
+                (
+                    linear_avg_color(img, alpha).map(|c| c as u8),
+                    Some(deviation),
+                )
+            }
+            _ if self.linear => (linear_avg_color(img, alpha).map(|c| c as u8), None),
+            _ => (
+                <[i16; 3]>::from(avg_color(img, alpha)).map(|c| c as u8),
+                None,
+            ),
+        };
+        let avg = match self.gamma {
+            Some(gamma) => avg.map(|c| color::warp(c, gamma)),
+            None => avg,
+        };
+        match self.levels {
+            Some(levels) => (avg.map(|c| color::quantize(c, levels)), deviation),
+            None => (avg, deviation),
+        }
+    }
+
+    /// The key of a tile turned by `orientation`, from the key of the tile
+    /// as it was cut.
+    pub fn orient<const N: usize>(self, key: Key<N>, orientation: Orientation) -> Key<N> {
+        let side = self.mode.cells_per_side();
+        if side == 1 {
+            return key;
+        }
+        let mut turned = [0; N];
+        for (i, from) in orientation.permute_cells(side).into_iter().enumerate() {
+            turned[3 * i..3 * i + 3].copy_from_slice(&key[3 * from..3 * from + 3]);
+        }
+        turned
+    }
+}
+
+/// The key of a tile or target block: the colors of the cells `spec.mode`
+/// splits `view` into, one after another, then the deviations of
+/// `KeyMode::MeanStd`. Tiles and blocks with the same pixels get the same
+/// key, whether they are images of their own or views into a larger one.
+pub fn compute_key<const N: usize, I: GenericImageView<Pixel = Rgb<u8>>>(
+    view: &I,
+    spec: &KeySpec,
+) -> Key<N> {
+    alpha_weighted_key(view, None, spec)
+}
+
+/// The alpha of the pixels of a tile cell.
+pub type Alpha<'a> = SubImage<&'a GrayImage>;
+
+/// `compute_key` for a view that may have an `alpha` channel.
+fn alpha_weighted_key<const N: usize, I: GenericImageView<Pixel = Rgb<u8>>>(
+    view: &I,
+    alpha: Option<&GrayImage>,
+    spec: &KeySpec,
+) -> Key<N> {
+    debug_assert_eq!(N, spec.mode.len());
+    let side = spec.mode.cells_per_side();
+    let mut key = [0; N];
+    let cells = tiles::cell_regions(view.width(), view.height(), side);
+    for (i, &(x, y, w, h)) in cells.iter().enumerate() {
+        let alpha = alpha.map(|alpha| alpha.view(x, y, w, h));
+        let (color, deviation) = spec.color(&view.view(x, y, w, h), alpha.as_ref());
+        let color = spec.metric.key(color);
+        key[3 * i..3 * i + 3].copy_from_slice(&spec.weights.apply(color));
+        if let Some(deviation) = deviation {
+            key[N - 3..].copy_from_slice(&spec.weights.apply(deviation));
+        }
+    }
+    key
+}
+
+/// The mean of each channel, rounded half up, with each pixel weighted by
+/// its `alpha`. Black for an empty or fully transparent view.
+pub fn avg_color<I: GenericImageView<Pixel = Rgb<u8>>>(img: &I, alpha: Option<&Alpha>) -> Pos {
+    let mut out = Pos { r: 0, g: 0, b: 0 };
+
+    let mut count = 0;
+    for (p, weight) in weighted_pixels(img, alpha) {
+        count += weight;
+        let (r, g, b) = (p[0], p[1], p[2]);
+        out.r += weight * r as u64;
+        out.g += weight * g as u64;
+        out.b += weight * b as u64;
+    }
+
+    out.r = rounded_mean(out.r, count);
+    out.g = rounded_mean(out.g, count);
+    out.b = rounded_mean(out.b, count);
+
+    out
+}
+
+/// The average color of `img` with each pixel weighted by a Gaussian around
+/// the center, with a sigma of a third of the size, and by its `alpha`.
+fn center_weighted_color<I: GenericImageView<Pixel = Rgb<u8>>>(
+    img: &I,
+    alpha: Option<&Alpha>,
+    linear: bool,
+) -> [u8; 3] {
+    let (across, down) = (center_weights(img.width()), center_weights(img.height()));
+    let value = |v: u8| {
+        if linear {
+            color::srgb_to_linear(v)
+        } else {
+            f64::from(v)
+        }
+    };
+    // rows are summed on their own first, so large tiles don't add small
+    // weighted pixels to one ever growing sum
+    let mut sum = [0.0; 3];
+    let mut total = 0.0;
+    for (y, &row_weight) in down.iter().enumerate() {
+        let mut row = [0.0; 3];
+        let mut row_total = 0.0;
+        for (x, &column_weight) in across.iter().enumerate() {
+            let (x, y) = (x as u32, y as u32);
+            let weight = column_weight * alpha.map_or(1.0, |a| f64::from(a.get_pixel(x, y)[0]));
+            let p = img.get_pixel(x, y);
+            for c in 0..3 {
+                row[c] += weight * value(p[c]);
+            }
+            row_total += weight;
+        }
+        for c in 0..3 {
+            sum[c] += row_weight * row[c];
+        }
+        total += row_weight * row_total;
+    }
+    if total == 0.0 {
+        return [0; 3];
+    }
+    sum.map(|s| {
+        if linear {
+            color::linear_to_srgb(s / total)
+        } else {
+            (s / total).round().clamp(0.0, 255.0) as u8
+        }
+    })
+}
+
+/// The weights `center_weighted_color` gives the columns or rows of a view
+/// `len` pixels wide or high, computed once per length and thread.
+fn center_weights(len: u32) -> Rc<[f64]> {
+    thread_local! {
+        static WEIGHTS: RefCell<HashMap<u32, Rc<[f64]>>> = RefCell::default();
+    }
+    WEIGHTS.with(|weights| {
+        let mut weights = weights.borrow_mut();
+        let weights = weights.entry(len).or_insert_with(|| {
+            let sigma = f64::from(len) / 3.0;
+            let center = (f64::from(len) - 1.0) / 2.0;
+            (0..len)
+                .map(|i| (-(f64::from(i) - center).powi(2) / (2.0 * sigma * sigma)).exp())
+                .collect()
+        });
+        Rc::clone(weights)
+    })
+}
+
+/// `sum / count` rounded half up, 0 if there is nothing to average.
+fn rounded_mean(sum: u64, count: u64) -> u64 {
+    if count == 0 {
+        return 0;
+    }
+    (sum + count / 2) / count
+}
+
+/// The pixels of `img` and how much each counts: its alpha, or 1 for all
+/// of them without one. Fully transparent pixels are left out.
+fn weighted_pixels<'a, I: GenericImageView<Pixel = Rgb<u8>>>(
+    img: &'a I,
+    alpha: Option<&'a Alpha>,
+) -> impl Iterator<Item = (Rgb<u8>, u64)> + 'a {
+    img.pixels()
+        .map(move |(x, y, p)| (p, alpha.map_or(1, |a| u64::from(a.get_pixel(x, y)[0]))))
+        .filter(|&(_, weight)| weight > 0)
+}
+
+/// `avg_color` and the standard deviation of each channel, in one pass,
+/// with each pixel weighted by its `alpha`.
+fn avg_and_deviation<I: GenericImageView<Pixel = Rgb<u8>>>(
+    img: &I,
+    alpha: Option<&Alpha>,
+) -> (Pos, [i16; 3]) {
+    let mut sum = [0u64; 3];
+    let mut squares = [0u64; 3];
+    let mut count = 0;
+    for (p, weight) in weighted_pixels(img, alpha) {
+        count += weight;
+        for c in 0..3 {
+            let v = u64::from(p[c]);
+            sum[c] += weight * v;
+            squares[c] += weight * v * v;
+        }
+    }
+    let deviation = |c: usize| {
+        if count == 0 {
+            return 0;
+        }
+        let mean = sum[c] as f64 / count as f64;
+        let variance = squares[c] as f64 / count as f64 - mean * mean;
+        variance.max(0.0).sqrt().round() as i16
+    };
+    let avg = Pos {
+        r: rounded_mean(sum[0], count),
+        g: rounded_mean(sum[1], count),
+        b: rounded_mean(sum[2], count),
+    };
+    (avg, [deviation(0), deviation(1), deviation(2)])
+}
+
+/// Like `avg_color`, but averages the light the pixels emit rather than
+/// their sRGB values, so bright parts of high-contrast blocks count fully.
+/// Pixels are weighted by their `alpha`.
+fn linear_avg_color<I: GenericImageView<Pixel = Rgb<u8>>>(
+    img: &I,
+    alpha: Option<&Alpha>,
+) -> [i16; 3] {
+    let mut linear = [0.0; 256];
+    for (v, l) in linear.iter_mut().enumerate() {
+        *l = color::srgb_to_linear(v as u8);
+    }
+    let mut sum = [0.0; 3];
+    let mut count = 0.0;
+    for (p, weight) in weighted_pixels(img, alpha) {
+        count += weight as f64;
+        for c in 0..3 {
+            sum[c] += weight as f64 * linear[usize::from(p[c])];
+        }
+    }
+    if count == 0.0 {
+        return [0; 3];
+    }
+    let channel = |c: usize| i16::from(color::linear_to_srgb(sum[c] / count));
+    [channel(0), channel(1), channel(2)]
+}
+
+#[test]
+fn averages_round_half_up() {
+    let pixels = |values: &[u8]| {
+        RgbImage::from_fn(values.len() as u32, 1, |x, _| Rgb([values[x as usize]; 3]))
+    };
+    let avg = |values: &[u8]| <[i16; 3]>::from(avg_color(&pixels(values), None))[0];
+    assert_eq!(avg(&[0, 1]), 1);
+    assert_eq!(avg(&[0, 0, 1]), 0);
+    assert_eq!(avg(&[0, 1, 1]), 1);
+    assert_eq!(avg(&[10, 11, 11, 11]), 11);
+    assert_eq!(avg(&[254, 255]), 255);
+    assert_eq!(avg_and_deviation(&pixels(&[0, 1]), None).0.r, 1);
+    let empty = RgbImage::new(4, 4);
+    assert_eq!(
+        <[i16; 3]>::from(avg_color(&empty.view(2, 2, 0, 0), None)),
+        [0, 0, 0]
+    );
+    assert_eq!(
+        avg_and_deviation(&empty.view(0, 0, 0, 3), None).1,
+        [0, 0, 0]
+    );
+}
+
+#[test]
+fn linear_averages_weigh_bright_pixels_fully() {
+    let block = RgbImage::from_fn(2, 2, |x, _| Rgb([if x == 0 { 0 } else { 255 }; 3]));
+    assert_eq!(compute_key(&block, &KeySpec::default()), [128, 128, 128]);
+    let linear = KeySpec {
+        linear: true,
+        ..KeySpec::default()
+    };
+    assert_eq!(compute_key(&block, &linear), [188, 188, 188]);
+}
+
+#[test]
+fn cell_keys_turn_with_their_tile() {
+    fn check<const N: usize>(mode: KeyMode) {
+        let tile = RgbImage::from_fn(6, 6, |x, y| {
+            Rgb([(x * 40) as u8, (y * 40) as u8, (x * y * 7) as u8])
+        });
+        let keys = KeySpec {
+            mode,
+            ..KeySpec::default()
+        };
+        for orientation in
+            Orientation::variants(&[tiles::Augment::Flips, tiles::Augment::Rotations])
+        {
+            let turned = orientation.apply(&tile);
+            assert_eq!(
+                keys.orient::<N>(keys.tile_key(&tile, None), orientation),
+                keys.tile_key(&turned, None),
+                "{:?} {:?}",
+                mode,
+                orientation
+            );
+        }
+    }
+    check::<12>(KeyMode::Quadrants);
+    check::<27>(KeyMode::Grid3);
+}
+
+#[test]
+fn tiles_and_blocks_with_the_same_pixels_share_keys() {
+    fn check<const N: usize>(mode: KeyMode) {
+        let target = RgbImage::from_fn(20, 14, |x, y| {
+            Rgb([
+                (x * 13 + y * 7) as u8,
+                (x * y * 3) as u8,
+                (255 - x * 9) as u8,
+            ])
+        });
+        let block = target.view(7, 4, 9, 6);
+        let tile = block.to_image();
+        let metrics = [
+            Metric::Rgb,
+            Metric::Lab,
+            Metric::Ciede2000,
+            Metric::Hsv,
+            Metric::Luma,
+        ];
+        for metric in metrics {
+            for linear in [false, true] {
+                let spec = KeySpec {
+                    linear,
+                    levels: Some(8),
+                    metric,
+                    mode,
+                    ..KeySpec::default()
+                };
+                assert_eq!(
+                    spec.tile_key::<N>(&tile, None),
+                    compute_key(&block, &spec),
+                    "{:?} {:?} linear={}",
+                    mode,
+                    metric,
+                    linear
+                );
+            }
+        }
+    }
+    check::<3>(KeyMode::Mean);
+    check::<3>(KeyMode::Dominant);
+    check::<3>(KeyMode::Median);
+    check::<6>(KeyMode::MeanStd);
+    check::<12>(KeyMode::Quadrants);
+    check::<27>(KeyMode::Grid3);
+}
+
+#[test]
+fn key_gamma_tells_shadows_apart() {
+    let flat = |v: u8| RgbImage::from_pixel(2, 2, Rgb([v; 3]));
+    let closer = |spec: &KeySpec| {
+        let key = |v| compute_key::<3, _>(&flat(v), spec)[0];
+        let target = key(20);
+        if (key(0) - target).abs() <= (key(45) - target).abs() {
+            0
+        } else {
+            45
+        }
+    };
+    assert_eq!(closer(&KeySpec::default()), 0);
+    let warped = KeySpec {
+        gamma: Some(2.2),
+        ..KeySpec::default()
+    };
+    assert_eq!(closer(&warped), 45);
+    assert_eq!(compute_key::<3, _>(&flat(255), &warped), [255; 3]);
+}
+
+#[test]
+fn transparent_pixels_stay_out_of_tile_keys() {
+    // a red circle composited over a white background
+    let inside = |x: u32, y: u32| (x as i32 - 8).pow(2) + (y as i32 - 8).pow(2) < 25;
+    let tile = RgbImage::from_fn(16, 16, |x, y| {
+        Rgb(if inside(x, y) { [255, 0, 0] } else { [255; 3] })
+    });
+    let alpha = GrayImage::from_fn(16, 16, |x, y| {
+        image::Luma([if inside(x, y) { 255 } else { 0 }])
+    });
+    for (mode, linear) in [
+        (KeyMode::Mean, false),
+        (KeyMode::Mean, true),
+        (KeyMode::Median, false),
+        (KeyMode::Dominant, false),
+    ] {
+        let spec = KeySpec {
+            mode,
+            linear,
+            ..KeySpec::default()
+        };
+        assert_eq!(
+            spec.tile_key::<3>(&tile, Some(&alpha)),
+            [255, 0, 0],
+            "{:?}",
+            mode
+        );
+    }
+    let spec = KeySpec::default();
+    assert_ne!(spec.tile_key::<3>(&tile, None), [255, 0, 0]);
+    let quadrants = KeySpec {
+        mode: KeyMode::Quadrants,
+        ..KeySpec::default()
+    };
+    assert_eq!(
+        quadrants.tile_key::<12>(&tile, Some(&alpha)),
+        [255, 0, 0].repeat(4)[..]
+    );
+
+    // half covered pixels count half
+    let halves = RgbImage::from_fn(2, 1, |x, _| Rgb([if x == 0 { 200 } else { 50 }; 3]));
+    let alpha = GrayImage::from_fn(2, 1, |x, _| image::Luma([if x == 0 { 255 } else { 85 }]));
+    assert_eq!(spec.tile_key::<3>(&halves, Some(&alpha)), [163; 3]);
+    let transparent = GrayImage::new(2, 1);
+    for linear in [false, true] {
+        let spec = KeySpec {
+            linear,
+            ..KeySpec::default()
+        };
+        assert_eq!(spec.tile_key::<3>(&halves, Some(&transparent)), [0; 3]);
+    }
+}
+
+#[test]
+fn center_weighted_keys_favor_the_middle() {
+    // a red center a quarter of the size, in a blue border
+    let tile = RgbImage::from_fn(16, 16, |x, y| {
+        let inside = (4..12).contains(&x) && (4..12).contains(&y);
+        Rgb(if inside { [255, 0, 0] } else { [0, 0, 255] })
+    });
+    let centered = KeySpec {
+        mode: KeyMode::CenterWeighted,
+        ..KeySpec::default()
+    };
+    let mean: Key<3> = compute_key(&tile, &KeySpec::default());
+    let weighted: Key<3> = compute_key(&tile, &centered);
+    assert_eq!(mean, [64, 0, 191]);
+    assert!(
+        weighted[0] > 100 && weighted[0] + weighted[2] == 255,
+        "{:?}",
+        weighted
+    );
+
+    // flat tiles keep their color, however large
+    let flat = RgbImage::from_pixel(300, 200, Rgb([10, 128, 255]));
+    assert_eq!(compute_key(&flat, &centered), [10, 128, 255]);
+    let linear = KeySpec {
+        linear: true,
+        ..centered
+    };
+    assert_eq!(compute_key(&flat, &linear), [10, 128, 255]);
+    assert_eq!(center_weights(5)[2], 1.0);
+    assert_eq!(center_weights(5)[0], center_weights(5)[4]);
+}
@@ -1,118 +1,2814 @@
+use image::imageops::FilterType;
 use image::GenericImageView;
+mod archive;
 mod blockdb;
-use blockdb::BlockDb;
-use std::fs::{self, DirEntry};
-use indicatif::{ProgressBar};
-use std::convert::TryInto;
-use rayon::prelude::*;
+mod cache;
+mod color;
+mod decode;
+mod download;
+mod input;
+mod key;
+mod phash;
+mod similar;
+mod tiles;
+mod watch;
+mod weights;
+use archive::ArchiveReport;
 use argh::FromArgs;
+use blockdb::{BlockDb, Record};
+use cache::{CachedTile, Manifest};
+use color::{ChannelWeights, Gains, HexColor, Metric, WhiteBalance, MAX_TEMPERATURE};
+use decode::{
+    decode_bytes, decode_file, decode_file_frames, decode_frames, frame_label, DecodeOptions,
+    Decoded,
+};
+use indicatif::ProgressBar;
+use input::{
+    apply_excludes, dedup_canonical, dedup_identical, find_input_images, glob_input_images,
+    read_input_list, sample, stable_hash, stable_hash_bytes, DirOptions, Exclude, Extensions,
+};
+use key::{compute_key, Key, KeySpec};
+use phash::Spectrum;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use similar::NearDuplicates;
+#[cfg(test)]
+use std::borrow::Borrow;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tiles::{
+    Augment, Edges, Filter, Gravity, Histogram, KeyMode, Normalization, Orientation, Padding,
+    Region, Rerank, Sizes, Stretch, TileMode,
+};
+use weights::Weights;
 
 #[derive(FromArgs)]
 /// Builds a collage with images from "./input/*"
 struct Args {
+    /// image to recreate, "-" reads it from stdin
     #[argh(positional)]
     target: String,
 
-    /// size of collage snippets
+    /// width and height of collage snippets
     #[argh(option, default = "32")]
     size: u32,
+
+    /// width of collage snippets (default: --size)
+    #[argh(option)]
+    tile_width: Option<u32>,
+
+    /// height of collage snippets (default: --size)
+    #[argh(option)]
+    tile_height: Option<u32>,
+
+    /// render one collage per tile size in this comma separated list, like
+    /// "64,32,16", from a single pass over the inputs, saved as
+    /// out-<size>.png
+    #[argh(option)]
+    sizes: Option<Sizes>,
+
+    /// how tiles are taken from the inputs: "grid" cuts every input into
+    /// tiles, "whole-image" shrinks each input into one tile, "cover" crops
+    /// each input to the shape of a tile first, "random-crops" cuts tiles
+    /// at random places
+    #[argh(option, default = "TileMode::Grid")]
+    tile_mode: TileMode,
+
+    /// how many tiles --tile-mode random-crops cuts from each input
+    #[argh(option, default = "200")]
+    crops_per_image: usize,
+
+    /// distance in pixels between the grid tiles cut from an input, less
+    /// than the tile size gives overlapping tiles (default: the tile width
+    /// across and the tile height down)
+    #[argh(option)]
+    source_stride: Option<u32>,
+
+    /// which part of inputs taller than a tile --tile-mode cover keeps:
+    /// center, top or bottom
+    #[argh(option, default = "Gravity::Center")]
+    crop_gravity: Gravity,
+
+    /// filter for shrinking inputs into whole-image or cover tiles, same
+    /// names as --filter (default: --filter)
+    #[argh(option)]
+    tile_filter: Option<Filter>,
+
+    /// directory, .zip or .tar(.gz) archive with the source images, can be
+    /// repeated (default: ./input)
+    #[argh(option)]
+    input: Vec<PathBuf>,
+
+    /// comma separated list of input file extensions to consider
+    #[argh(option, default = "Extensions::default()")]
+    extensions: Extensions,
+
+    /// file with one input image path per line, "-" reads from stdin
+    #[argh(option)]
+    list_file: Option<PathBuf>,
+
+    /// glob selecting the input images, e.g. "photos/**/*.jpg"
+    #[argh(option)]
+    input_glob: Option<String>,
+
+    /// file with one http(s) url of a source image per line
+    #[argh(option)]
+    url_list: Option<PathBuf>,
+
+    /// directory to keep downloaded images in for later runs
+    #[argh(option)]
+    cache_dir: Option<PathBuf>,
+
+    /// also search subdirectories of the input directory
+    #[argh(switch, short = 'r')]
+    recursive: bool,
+
+    /// ignore symlinks in the input directory instead of following them
+    #[argh(switch)]
+    no_follow_symlinks: bool,
+
+    /// skip input files whose name or a path component matches this glob
+    #[argh(option)]
+    exclude: Vec<Exclude>,
+
+    /// use byte-identical input files more than once
+    #[argh(switch)]
+    keep_duplicates: bool,
+
+    /// drop input images whose 8x8 thumbnails differ from an earlier one by
+    /// less than this mean per-channel value (0-255)
+    #[argh(option)]
+    dedupe_similar: Option<f64>,
+
+    /// downscale input images larger than this before cutting tiles, 0 keeps
+    /// the original resolution
+    #[argh(option, default = "2048")]
+    max_input_dimension: u32,
+
+    /// filter for every resize: nearest, triangle, catmullrom, gaussian or
+    /// lanczos3 (default)
+    #[argh(option, default = "Filter(FilterType::Lanczos3)")]
+    filter: Filter,
+
+    /// use at most this many input images, picked at random
+    #[argh(option)]
+    max_images: Option<usize>,
+
+    /// take at most this many tiles from each input image, picked at random
+    #[argh(option)]
+    max_tiles_per_image: Option<usize>,
+
+    /// keep at most this many tiles in total, an unbiased random pick of
+    /// the tiles left after the other filters; 0 keeps every tile
+    #[argh(option, default = "0")]
+    max_total_tiles: usize,
+
+    /// how much farther in color than the closest tile a tile may be and
+    /// still win because its source has a higher weight in weights.toml
+    #[argh(option, default = "8.0")]
+    weight_epsilon: f64,
+
+    /// average the colors of tiles and target blocks in linear light, which
+    /// keeps high-contrast blocks from matching too dark
+    #[argh(switch)]
+    linear_average: bool,
+
+    /// color space tiles are matched in: "rgb"; "lab" for CIELAB, which is
+    /// closer to how different colors look; "ciede2000", which picks among
+    /// the closest tiles in CIELAB by the CIEDE2000 difference; "hsv",
+    /// which cares more about hue than brightness; "luma", which only
+    /// compares brightness; or "oklab", which is cheaper than CIELAB and
+    /// keeps hues more even; or "phash", which matches the structure of
+    /// tiles and blocks by a DCT-based perceptual hash rather than their
+    /// color. With lab and ciede2000 --weight-epsilon is in tenths of a
+    /// CIELAB unit, with oklab in thousandths of an Oklab unit
+    #[argh(option, default = "Metric::Rgb")]
+    metric: Metric,
+
+    /// which color tiles and target blocks are matched by: "mean" for the
+    /// average color; "dominant" for the most common one, which keeps a
+    /// half blue, half orange tile from looking brown; "median" for the
+    /// middle value of each channel, which a speck or watermark doesn't
+    /// move; "quadrants" for the average colors of the four quarters, which
+    /// keeps edges and gradients running the right way; "grid3" for a 3×3
+    /// grid, which also keeps diagonals and suits large tiles;
+    /// "mean-std" for the average color and how much the pixels stray from
+    /// it, which keeps busy tiles out of smooth regions and doesn't work
+    /// with --metric ciede2000; or "center-weighted" for an average that
+    /// counts the middle most and what spills in at the borders least
+    #[argh(option, default = "KeyMode::Mean")]
+    key: KeyMode,
+
+    /// settle for a tile up to this fraction farther than the closest one,
+    /// like 0.2, to search faster with the long keys of --key quadrants and
+    /// grid3; not with --metric ciede2000 or weights.toml
+    #[argh(option, default = "0.0")]
+    search_epsilon: f64,
+
+    /// the same as --search-epsilon
+    #[argh(option)]
+    approx: Option<f64>,
+
+    /// compare every block with every tile instead of searching the tile
+    /// database, which is slow but rules the search out when a collage
+    /// looks wrong; not with --search-epsilon, --rerank, --metric
+    /// ciede2000 and phash or weights.toml
+    #[argh(switch)]
+    brute_force: bool,
+
+    /// how the closest tiles are compared before one is placed: "none" or
+    /// "histogram" for the one whose colors are spread most like the
+    /// block's, or "edges" for the one whose edges are as strong and run the
+    /// same way; not with weights.toml
+    #[argh(option, default = "Rerank::None")]
+    rerank: Rerank,
+
+    /// how many of the closest tiles --rerank compares
+    #[argh(option, default = "16")]
+    rerank_candidates: usize,
+
+    /// match blocks one after another and pass what each pick is off by on
+    /// to the next ones, so smooth gradients get a mix of tiles instead of
+    /// bands of the same one; slower, as blocks aren't matched in parallel
+    #[argh(switch)]
+    dither: bool,
+
+    /// weights for the red, green and blue distance, like "1,2,1" to match
+    /// green more closely; only with --metric rgb
+    #[argh(option, default = "ChannelWeights::default()")]
+    channel_weights: ChannelWeights,
+
+    /// match on colors rounded to this many levels per channel, for bold
+    /// flat regions; the tiles are placed unchanged
+    #[argh(option)]
+    quantize: Option<u32>,
+
+    /// match on average colors mapped through (v/255)^(1/g)*255, so a
+    /// gamma like 2.2 tells dark colors apart more finely than bright ones;
+    /// the tiles are placed unchanged
+    #[argh(option, default = "1.0")]
+    key_gamma: f32,
+
+    /// blur source tiles by this sigma before taking their average color,
+    /// the placed tiles stay sharp
+    #[argh(option)]
+    tile_blur: Option<f32>,
+
+    /// sharpen placed tiles by this amount, 1 doubles the detail
+    #[argh(option)]
+    tile_sharpen: Option<f32>,
+
+    /// multiply the saturation of placed tiles by this factor, 1 leaves them
+    /// alone; matching still uses the original colors
+    #[argh(option)]
+    saturation: Option<f32>,
+
+    /// place the tiles in shades of gray, for use with --metric luma
+    #[argh(switch)]
+    desaturate_output: bool,
+
+    /// grade placed tiles warmer by this kelvin-ish shift, or cooler if
+    /// negative, within ±5000; 0 leaves them alone
+    #[argh(option, default = "0.0")]
+    temperature: f32,
+
+    /// stretch the contrast of the target so its 1st and 99th percentile
+    /// of brightness become black and white before matching, which lets
+    /// foggy targets use more of the tiles
+    #[argh(switch)]
+    stretch_target: bool,
+
+    /// squeeze placed tiles back into the brightness range the target had
+    /// before --stretch-target
+    #[argh(switch)]
+    restore_range: bool,
+
+    /// match tiles against a target graded the opposite way, so the tiles
+    /// are picked for how they look after --temperature
+    #[argh(switch)]
+    temperature_target: bool,
+
+    /// also put variants of every tile into the database, "flips" adds the
+    /// mirror images and "rotations" the turned tiles, can be repeated;
+    /// variants don't count towards --max-tiles-per-image
+    #[argh(option)]
+    augment: Vec<Augment>,
+
+    /// seed for all random choices, equal seeds give equal collages
+    #[argh(option, default = "0")]
+    seed: u64,
+
+    /// use at most this many frames of each animated GIF
+    #[argh(option)]
+    max_frames_per_animation: Option<usize>,
+
+    /// don't rotate images according to their EXIF orientation tag
+    #[argh(switch)]
+    ignore_exif: bool,
+
+    /// color that transparent parts of inputs are composited over, as
+    /// #rrggbb
+    #[argh(option, default = "HexColor(image::Rgb([255, 255, 255]))")]
+    alpha_background: HexColor,
+
+    /// stops to brighten (or with a negative value darken) high dynamic
+    /// range .hdr inputs and targets by before tone mapping them
+    #[argh(option, default = "0.0")]
+    exposure: f32,
+
+    /// pad inputs smaller than a tile up to the tile size instead of skipping
+    /// them, with "mirror" or a #rrggbb color
+    #[argh(option)]
+    pad_small_inputs: Option<Padding>,
+
+    /// leave out tiles that are fully transparent
+    #[argh(switch)]
+    reject_transparent_tiles: bool,
+
+    /// leave out tiles whose pixel variance, averaged over the channels, is
+    /// below this
+    #[argh(option, default = "0.0")]
+    min_tile_variance: f64,
+
+    /// leave out tiles where more than this fraction of the pixels falls into
+    /// the same coarse color bucket, like borders and letterboxes
+    #[argh(option)]
+    max_dominant_fraction: Option<f64>,
+
+    /// even out the brightness and contrast of every tile before matching
+    /// and placing it
+    #[argh(switch)]
+    normalize_tiles: bool,
+
+    /// take the color cast out of source images before they are matched:
+    /// "auto" for gray world gains per image, or gains like "1.05,1,0.92"
+    /// for every image; tiles are placed unchanged unless
+    /// --apply-wb-to-output
+    #[argh(option)]
+    white_balance: Option<WhiteBalance>,
+
+    /// also place tiles with the color cast of their source taken out
+    #[argh(switch)]
+    apply_wb_to_output: bool,
+
+    /// take the color cast out of the target with gray world gains before
+    /// matching
+    #[argh(switch)]
+    white_balance_target: bool,
+
+    /// mean luminance (0-255) that --normalize-tiles moves tiles to
+    #[argh(option, default = "128.0")]
+    normalize_mean: f64,
+
+    /// standard deviation of luminance that --normalize-tiles moves tiles to
+    #[argh(option, default = "48.0")]
+    normalize_stddev: f64,
+
+    /// how many times --normalize-tiles may stretch or squeeze the contrast
+    /// of a tile
+    #[argh(option, default = "4.0")]
+    normalize_max_gain: f64,
+
+    /// tile manifest that lets unchanged inputs skip decoding, also when
+    /// they were only copied or touched (default: .collagen-cache.json in
+    /// the first input directory), not used with --dedupe-similar
+    #[argh(option)]
+    cache: Option<PathBuf>,
+
+    /// neither read nor write the tile manifest
+    #[argh(switch)]
+    no_cache: bool,
+
+    /// write the tile database to this file once it is built, for
+    /// --load-db; with --watch again after every render, with --sizes to
+    /// one file per size, named like out-<size>.png
+    #[argh(option)]
+    save_db: Option<PathBuf>,
+
+    /// read the tile database from a file written by --save-db instead of
+    /// building it, refused unless the inputs and settings are the same;
+    /// the file only holds the keys, so the inputs are still decoded and
+    /// cut, or restored from the tile manifest, to draw the tiles with;
+    /// with --watch only for the first render, with --sizes from one file
+    /// per size
+    #[argh(option)]
+    load_db: Option<PathBuf>,
+
+    /// keep running and render again whenever input files are added or
+    /// removed
+    #[argh(switch)]
+    watch: bool,
+
+    /// exit with an error if any input fails to load
+    #[argh(switch)]
+    strict: bool,
+
+    /// print details about skipped inputs and which part of which source
+    /// every placed tile came from
+    #[argh(switch, short = 'v')]
+    verbose: bool,
 }
 
 fn main() {
-    let args: Args = argh::from_env();
-    let size = args.size;
-    let input = find_input_images();
+    let args = parse_args();
+    if args.target == "-" && args.list_file.as_deref() == Some(Path::new("-")) {
+        exit_with("The target and --list-file can't both be read from stdin");
+    }
+    let (tile_width, tile_height) = tile_size(&args);
+    if tile_width == 0 || tile_height == 0 {
+        exit_with("Tiles must be at least 1 pixel wide and high");
+    }
+    if args.sizes.is_some() && (args.tile_width.is_some() || args.tile_height.is_some()) {
+        exit_with("--sizes only makes square tiles, drop --tile-width and --tile-height");
+    }
+    if args.sizes.is_some() && args.watch {
+        exit_with("--sizes can't be combined with --watch");
+    }
+    if tile_width != tile_height && args.augment.contains(&Augment::Rotations) {
+        exit_with(&format!(
+            "--augment rotations needs square tiles, not {}x{}; --augment flips \
+             includes the upside-down variant",
+            tile_width, tile_height
+        ));
+    }
+    if args.source_stride == Some(0) {
+        exit_with("--source-stride must be at least 1");
+    }
+    if args.metric != Metric::Rgb && args.channel_weights != ChannelWeights::default() {
+        exit_with("--channel-weights only works with --metric rgb");
+    }
+    if !(0.0..).contains(&args.search_epsilon) {
+        exit_with("--search-epsilon must be at least 0");
+    }
+    if args.metric == Metric::Ciede2000 && args.key == KeyMode::MeanStd {
+        exit_with("--key mean-std doesn't work with --metric ciede2000");
+    }
+    let searches_tree = args.rerank == Rerank::None
+        && args.search_epsilon == 0.0
+        && !matches!(args.metric, Metric::Ciede2000 | Metric::Phash);
+    if args.brute_force && !searches_tree {
+        exit_with("--brute-force doesn't work with --search-epsilon, --rerank or --metric ciede2000 and phash");
+    }
+    if args.metric == Metric::Ciede2000 && args.search_epsilon > 0.0 {
+        exit_with("--search-epsilon doesn't work with --metric ciede2000");
+    }
+    if args.metric == Metric::Phash && args.rerank != Rerank::None {
+        exit_with("--metric phash doesn't work with --rerank");
+    }
+    if args.apply_wb_to_output && args.white_balance.is_none() {
+        exit_with("--apply-wb-to-output needs --white-balance");
+    }
+    if args.rerank_candidates == 0 {
+        exit_with("--rerank-candidates must be at least 1");
+    }
+    if args.crops_per_image == 0 {
+        exit_with("--crops-per-image must be at least 1");
+    }
+    if !(0.0..=255.0).contains(&args.normalize_mean) || !(0.0..).contains(&args.normalize_stddev) {
+        exit_with("--normalize-mean must be within 0-255 and --normalize-stddev at least 0");
+    }
+    if !(1.0..).contains(&args.normalize_max_gain) {
+        exit_with("--normalize-max-gain must be at least 1");
+    }
+    if args
+        .quantize
+        .is_some_and(|levels| !(2..=256).contains(&levels))
+    {
+        exit_with("--quantize must be between 2 and 256 levels");
+    }
+    if !(args.key_gamma > 0.0 && args.key_gamma.is_finite()) {
+        exit_with("--key-gamma must be positive");
+    }
+    if args
+        .tile_blur
+        .is_some_and(|sigma| sigma.is_nan() || sigma <= 0.0)
+    {
+        exit_with("--tile-blur must be positive");
+    }
+    if args
+        .tile_sharpen
+        .is_some_and(|amount| !(0.0..).contains(&amount))
+    {
+        exit_with("--tile-sharpen must be at least 0");
+    }
+    if args
+        .saturation
+        .is_some_and(|factor| !(0.0..).contains(&factor))
+    {
+        exit_with("--saturation must be at least 0");
+    }
+    if !(-MAX_TEMPERATURE..=MAX_TEMPERATURE).contains(&args.temperature) {
+        exit_with("--temperature must be within -5000 to 5000");
+    }
+    if args.restore_range && !args.stretch_target {
+        exit_with("--restore-range needs --stretch-target");
+    }
+    if args.temperature_target && args.temperature == 0.0 {
+        exit_with("--temperature-target needs --temperature");
+    }
+    if args
+        .max_dominant_fraction
+        .is_some_and(|f| !(0.0..=1.0).contains(&f))
+    {
+        exit_with("--max-dominant-fraction must be within 0-1");
+    }
+    let decode_opts = DecodeOptions {
+        ignore_exif: args.ignore_exif,
+        max_frames: args.max_frames_per_animation,
+        background: args.alpha_background.0,
+        exposure: args.exposure,
+    };
+    if args.watch {
+        watch_inputs(&args, &decode_opts);
+    }
+    if let Some(sizes) = &args.sizes {
+        render_sizes(&args, &decode_opts, &sizes.0);
+        return;
+    }
+    let (set, missing) = load_tiles(&args, &decode_opts);
 
-    if input.len() < 1 {
+    if set.tiles.is_empty() && args.min_tile_variance > 0.0 {
+        exit_with(&format!(
+            "--min-tile-variance {} left out every tile",
+            args.min_tile_variance
+        ));
+    }
+    if set.tiles.is_empty() && args.max_dominant_fraction.is_some() {
+        exit_with("--max-dominant-fraction left out every tile");
+    }
+    if set.tiles.is_empty() {
         eprintln!("No input images");
         return;
     }
+    let (target, stretch) = load_target(&args, &decode_opts).unwrap_or_else(|e| exit_with(&e));
+    let canvas = Canvas {
+        target: &target,
+        stretch,
+        tile: tile_size(&args),
+        out: PathBuf::from("out.png"),
+        save_db: args.save_db.clone(),
+        load_db: args.load_db.clone(),
+    };
+    draw(&args, &decode_opts, set, &canvas);
+    if missing > 0 {
+        eprintln!("{} listed input files do not exist", missing);
+    }
+}
 
-    let bar = ProgressBar::new(input.len() as u64);
-    let imgs: Vec<image::RgbImage> = input.iter().filter_map(|p| {
-        let i = image::open(p).map(|i| i.into_rgb8()).ok();
-        bar.inc(1);
-        i
-    }).collect();
-    bar.finish_and_clear();
-    let sub_imgs = imgs.iter().flat_map(
-        |img| {
-            let (width, height) = img.dimensions();
-            let mut imgs = Vec::new();
-            for x in (0..width - size).step_by(size.try_into().unwrap()) {
-                for y in (0..height - size).step_by(size.try_into().unwrap()) {
-                    imgs.push(img.view(x, y, size, size));
+/// What `collage` matches the tiles to and the files it writes and reads.
+struct Canvas<'a> {
+    target: &'a image::RgbImage,
+    /// how `target` was stretched, for --restore-range
+    stretch: Stretch,
+    /// size of the blocks the target is cut into
+    tile: (u32, u32),
+    out: PathBuf,
+    save_db: Option<PathBuf>,
+    load_db: Option<PathBuf>,
+}
+
+/// `collage` with keys of as many components as --key asks for.
+fn draw(args: &Args, decode_opts: &DecodeOptions, set: TileSet, canvas: &Canvas) {
+    if args.brute_force && set.weighted {
+        exit_with("--brute-force doesn't work with weights.toml");
+    }
+    match args.key.len() {
+        3 => collage::<3>(args, decode_opts, set, canvas),
+        6 => collage::<6>(args, decode_opts, set, canvas),
+        12 => collage::<12>(args, decode_opts, set, canvas),
+        27 => collage::<27>(args, decode_opts, set, canvas),
+        len => unreachable!("no keys with {} components", len),
+    }
+}
+
+/// Matches the tiles of `set` to the target of `canvas` and writes the
+/// collage to its `out`, with keys of `N` components.
+fn collage<const N: usize>(
+    args: &Args,
+    decode_opts: &DecodeOptions,
+    set: TileSet,
+    canvas: &Canvas,
+) {
+    let tiles = set.tiles;
+    let orientations = Orientation::variants(&args.augment);
+    if args.max_tiles_per_image.is_some() || args.source_stride.is_some() || args.verbose {
+        eprintln!("{} tiles", tiles.len());
+    }
+    if args.verbose {
+        eprintln!(
+            "tile histograms take {} KiB",
+            tiles.len() * std::mem::size_of::<Histogram>() / 1024
+        );
+    }
+    if orientations.len() > 1 {
+        eprintln!(
+            "{} tiles, {} variants generated",
+            tiles.len(),
+            tiles.len() * (orientations.len() - 1)
+        );
+    }
+    let keys = key_spec(args);
+    let header = db_header(args, canvas.tile, &tiles, &set.sources);
+    let bldb: BlockDb<i16, Variant, N> = match &canvas.load_db {
+        Some(path) => {
+            let fail = |e: String| exit_with(&format!("--load-db {}: {}", path.display(), e));
+            let bldb: BlockDb<i16, Variant, N> = cache::load_db(path, &header).unwrap_or_else(fail);
+            if let Some((_, v)) = bldb.iter().find(|(_, v)| v.tile >= tiles.len()) {
+                fail(format!("there is no tile {}", v.tile));
+            }
+            bldb
+        }
+        None => {
+            let keyed: Vec<_> = (0..tiles.len())
+                .into_par_iter()
+                .flat_map_iter(|tile| {
+                    let tiles = &tiles;
+                    let key = tile_key::<N>(&tiles[tile], keys);
+                    orientations.iter().map(move |&orientation| {
+                        let variant = Variant { tile, orientation };
+                        (keys.orient(key, orientation), variant)
+                    })
+                })
+                .collect();
+            BlockDb::from_keyed(keyed)
+        }
+    };
+    let done = if canvas.load_db.is_some() {
+        "loaded"
+    } else {
+        "built"
+    };
+    if args.verbose {
+        eprintln!(
+            "{} database with {} tiles, {} levels deep",
+            done,
+            group_thousands(bldb.len()),
+            bldb.depth()
+        );
+    } else {
+        eprintln!(
+            "{} database with {} tiles",
+            done,
+            group_thousands(bldb.len())
+        );
+    }
+    if let Some(path) = &canvas.save_db {
+        if let Err(e) = cache::save_db(path, &header, &bldb) {
+            exit_with(&format!(
+                "could not write --save-db {}: {}",
+                path.display(),
+                e
+            ));
+        }
+    }
+    // phash compares every variant, the keys only break ties
+    let hashed: Option<Vec<_>> = (args.metric == Metric::Phash).then(|| {
+        bldb.iter()
+            .map(|(&key, &Variant { tile, orientation })| {
+                let phashes = tiles[tile]
+                    .phashes
+                    .expect("tiles are hashed with --metric phash");
+                (
+                    phashes[orientation.index()],
+                    key,
+                    Variant { tile, orientation },
+                )
+            })
+            .collect()
+    });
+
+    let target = canvas.target;
+    let (sources, weighted) = (&set.sources, set.weighted);
+    // summed over threads, long keys slow the tree down noticeably
+    let searching = AtomicU64::new(0);
+    let choose = |key, block: &image::SubImage<&image::RgbImage>| {
+        let start = Instant::now();
+        let pick = if let Some(hashed) = &hashed {
+            closest_hash(hashed, Spectrum::of(block).hash(), key)
+        } else if weighted {
+            pick_weighted(&bldb, &tiles, sources, key, args.weight_epsilon)
+        } else {
+            match args.rerank {
+                Rerank::None if args.brute_force => bldb.find_closest_brute(key).unwrap().0,
+                Rerank::None => find_closest(&bldb, key, args.metric, args.search_epsilon),
+                Rerank::Histogram => {
+                    let histogram = Histogram::of(block);
+                    rerank(&bldb, &tiles, key, args.rerank_candidates, |tile, _| {
+                        tile.histogram.chi_square(&histogram)
+                    })
+                }
+                Rerank::Edges => {
+                    let edges = Edges::of(block);
+                    rerank(
+                        &bldb,
+                        &tiles,
+                        key,
+                        args.rerank_candidates,
+                        |tile, orientation| tile.edges.oriented(orientation).distance(&edges),
+                    )
                 }
             }
-            return imgs;
-        }).collect();
+        };
+        searching.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        pick
+    };
+    let picks = if args.dither {
+        dither_blocks(target, canvas.tile, keys, choose, |v: &Variant| {
+            keys.orient(tile_key::<N>(&tiles[v.tile], keys), v.orientation)
+        })
+    } else {
+        match_blocks(target, canvas.tile, keys, choose)
+    };
+    if args.verbose || N > 3 || args.rerank != Rerank::None {
+        let searching = Duration::from_nanos(searching.into_inner());
+        eprintln!(
+            "searched {} keys of {} components for {} blocks in {:.1?}, {:.1?} per block",
+            bldb.len(),
+            N,
+            picks.len(),
+            searching,
+            searching / picks.len().max(1) as u32
+        );
+    }
+    let picked = picks.iter().map(|(_, _, v)| &tiles[v.tile]);
+    let restored = restore_cached(args, decode_opts, sources, picked);
+    let place = PlaceOptions::new(args, canvas.stretch);
+    let blocks: Vec<(u32, u32, Cow<image::RgbImage>)> = picks
+        .par_iter()
+        .map(|&(x, y, variant)| {
+            let tile = &tiles[variant.tile];
+            let img = match &tile.pixels {
+                Pixels::Loaded(img) => img,
+                Pixels::Cached { index, .. } => &restored[&(tile.source, *index)],
+            };
+            let img = if args.apply_wb_to_output {
+                tiles::white_balance(img, tile.gains)
+            } else {
+                Cow::Borrowed(img)
+            };
+            let img = match variant.orientation.apply(&img) {
+                Cow::Borrowed(_) => img,
+                Cow::Owned(turned) => Cow::Owned(turned),
+            };
+            (x, y, place.finish(img))
+        })
+        .collect();
+    if orientations.len() > 1 {
+        let turned = picks
+            .iter()
+            .filter(|(_, _, v)| v.orientation != Orientation::ORIGINAL)
+            .count();
+        eprintln!("{} of {} placed tiles are variants", turned, picks.len());
+    }
+    if args.verbose {
+        for p in placements(&picks, &tiles, sources) {
+            let (x, y, w, h) = p.region;
+            let turned = if p.orientation == Orientation::ORIGINAL {
+                String::new()
+            } else {
+                format!(" {:?}", p.orientation)
+            };
+            eprintln!(
+                "{},{}: {} {}x{}+{}+{}{}",
+                p.x, p.y, p.source, w, h, x, y, turned
+            );
+        }
+    }
+    let blocks = blocks.iter().map(|(x, y, img)| (*x, *y, img.as_ref()));
+    compose(target.dimensions(), blocks)
+        .save(&canvas.out)
+        .unwrap();
+}
 
-    let bldb = BlockDb::new(sub_imgs, |img| avg_color(img).into());
+/// Replaces every tile-sized block of `target` with the closest tile,
+/// finished with `place`.
+#[cfg(test)]
+fn render<I, const N: usize>(
+    target: &image::RgbImage,
+    bldb: &BlockDb<i16, I, N>,
+    tile: (u32, u32),
+    keys: KeySpec,
+    place: PlaceOptions,
+) -> image::RgbImage
+where
+    I: Borrow<image::RgbImage> + Sync,
+{
+    let picks = match_blocks(target, tile, keys, |key, _| {
+        find_closest(bldb, key, keys.metric, 0.0)
+    });
+    let blocks: Vec<(u32, u32, Cow<image::RgbImage>)> = picks
+        .into_par_iter()
+        .map(|(x, y, blk)| (x, y, place.finish(Cow::Borrowed(blk.borrow()))))
+        .collect();
+    compose(
+        target.dimensions(),
+        blocks.iter().map(|(x, y, blk)| (*x, *y, blk.as_ref())),
+    )
+}
 
-    let img2 = image::open(args.target.clone())
-        .unwrap()
-        .into_rgb8();
-    let (width, height) = img2.dimensions();
-    let mut out_img: image::RgbImage = image::ImageBuffer::new(width, height);
+/// `render` with a tree of `tiles` keyed the way `keys` asks for, by the
+/// visible pixels of those with an alpha channel.
+#[cfg(test)]
+fn render_tiles<I>(
+    target: &image::RgbImage,
+    tiles: Vec<(I, Option<&image::GrayImage>)>,
+    tile: (u32, u32),
+    keys: KeySpec,
+    place: PlaceOptions,
+) -> image::RgbImage
+where
+    I: Borrow<image::RgbImage> + Send + Sync,
+{
+    match keys.mode.len() {
+        3 => {
+            let bldb = BlockDb::<_, _, 3>::from_keyed(keyed_tiles(tiles, keys));
+            render(target, &bldb, tile, keys, place)
+        }
+        6 => {
+            let bldb = BlockDb::<_, _, 6>::from_keyed(keyed_tiles(tiles, keys));
+            render(target, &bldb, tile, keys, place)
+        }
+        12 => {
+            let bldb = BlockDb::<_, _, 12>::from_keyed(keyed_tiles(tiles, keys));
+            render(target, &bldb, tile, keys, place)
+        }
+        27 => {
+            let bldb = BlockDb::<_, _, 27>::from_keyed(keyed_tiles(tiles, keys));
+            render(target, &bldb, tile, keys, place)
+        }
+        len => unreachable!("no keys with {} components", len),
+    }
+}
+
+/// `tiles` and their keys, for `BlockDb::from_keyed`.
+#[cfg(test)]
+fn keyed_tiles<I, const N: usize>(
+    tiles: Vec<(I, Option<&image::GrayImage>)>,
+    keys: KeySpec,
+) -> Vec<(Key<N>, I)>
+where
+    I: Borrow<image::RgbImage>,
+{
+    tiles
+        .into_iter()
+        .map(|(img, alpha)| (keys.tile_key(img.borrow(), alpha), img))
+        .collect()
+}
 
-    let coords: Vec<(u32, u32)> = (0..width - size).step_by(size.try_into().unwrap()).flat_map( |x| {
-        (0..height - size).step_by(size.try_into().unwrap()).map(move |y| (x,y))
-    }).collect();
+/// What happens to tiles as they get placed, after matching.
+#[derive(Debug, Default, Clone, Copy)]
+struct PlaceOptions {
+    /// amount for `tiles::sharpen`
+    sharpen: Option<f32>,
+    /// factor for `tiles::saturate`
+    saturation: Option<f32>,
+    /// whether tiles go through `tiles::desaturate`
+    desaturate: bool,
+    /// grade from --temperature
+    temperature: Gains,
+    /// undone for --restore-range
+    stretch: Stretch,
+}
+
+impl PlaceOptions {
+    /// The options for tiles placed on a target stretched by `stretch`.
+    fn new(args: &Args, stretch: Stretch) -> Self {
+        PlaceOptions {
+            sharpen: args.tile_sharpen,
+            saturation: args.saturation,
+            desaturate: args.desaturate_output,
+            temperature: Gains::temperature(args.temperature),
+            stretch: if args.restore_range {
+                stretch
+            } else {
+                Stretch::NONE
+            },
+        }
+    }
+
+    /// `tile` as it gets placed.
+    fn finish(self, tile: Cow<image::RgbImage>) -> Cow<image::RgbImage> {
+        let tile = match self.saturation {
+            Some(factor) => Cow::Owned(tiles::saturate(&tile, factor)),
+            None => tile,
+        };
+        let tile = if self.desaturate {
+            Cow::Owned(tiles::desaturate(&tile))
+        } else {
+            tile
+        };
+        let tile = match tiles::white_balance(&tile, self.temperature) {
+            Cow::Borrowed(_) => tile,
+            Cow::Owned(graded) => Cow::Owned(graded),
+        };
+        let tile = match self.stretch.undo(&tile) {
+            Cow::Borrowed(_) => tile,
+            Cow::Owned(squeezed) => Cow::Owned(squeezed),
+        };
+        match self.sharpen {
+            Some(amount) => Cow::Owned(tiles::sharpen(&tile, amount)),
+            None => tile,
+        }
+    }
+}
+
+/// Picks a tile for every tile-sized block of `target` by its average
+/// color. Blocks along the right and bottom edges may be smaller, they are
+/// matched by the part inside the target and get a cropped tile.
+fn match_blocks<'a, I: Sync, const N: usize>(
+    target: &image::RgbImage,
+    (tile_width, tile_height): (u32, u32),
+    keys: KeySpec,
+    choose: impl Fn(Key<N>, &image::SubImage<&image::RgbImage>) -> &'a I + Sync,
+) -> Vec<(u32, u32, &'a I)> {
+    let (width, height) = target.dimensions();
+
+    let coords: Vec<(u32, u32)> = (0..width)
+        .step_by(tile_width.try_into().unwrap())
+        .flat_map(|x| {
+            (0..height)
+                .step_by(tile_height.try_into().unwrap())
+                .map(move |y| (x, y))
+        })
+        .collect();
 
     let bar = ProgressBar::new(coords.len().try_into().unwrap());
 
-    let replacements: Vec<(u32, u32, &image::SubImage<&image::RgbImage>)> = coords.into_par_iter().map(|(x,y)| {
-        let avg = avg_color(&img2.view(x, y, size, size));
-        let new_block = bldb.find_closest_pos(avg.into()).unwrap();
-        bar.inc(1);
-        (x,y, new_block)
-    }).collect();
+    let replacements: Vec<(u32, u32, &I)> = coords
+        .into_par_iter()
+        .map(|(x, y)| {
+            let block = target.view(x, y, tile_width.min(width - x), tile_height.min(height - y));
+            let new_block = choose(compute_key(&block, &keys), &block);
+            bar.inc(1);
+            (x, y, new_block)
+        })
+        .collect();
     bar.finish_and_clear();
-    for (x,y, blk) in replacements {
-        image::imageops::replace(&mut out_img, blk, x, y);
+    replacements
+}
+
+/// `match_blocks` with Floyd–Steinberg error diffusion: blocks are matched
+/// one at a time, row by row in alternating directions, and how far the key
+/// of each pick is from the block's is passed on to the blocks right of and
+/// below it. Smooth gradients get a mix of tiles instead of bands of one.
+fn dither_blocks<'a, I, const N: usize>(
+    target: &image::RgbImage,
+    (tile_width, tile_height): (u32, u32),
+    keys: KeySpec,
+    choose: impl Fn(Key<N>, &image::SubImage<&image::RgbImage>) -> &'a I,
+    key_of: impl Fn(&I) -> Key<N>,
+) -> Vec<(u32, u32, &'a I)> {
+    let (width, height) = target.dimensions();
+    let columns = width.div_ceil(tile_width) as usize;
+    let rows = height.div_ceil(tile_height) as usize;
+    let mut errors = vec![[0.0; N]; columns * rows];
+    let bar = ProgressBar::new((columns * rows) as u64);
+
+    let mut replacements = Vec::with_capacity(columns * rows);
+    for row in 0..rows {
+        let ahead: isize = if row % 2 == 0 { 1 } else { -1 };
+        for step in 0..columns {
+            let column = if ahead > 0 { step } else { columns - 1 - step };
+            let (x, y) = (column as u32 * tile_width, row as u32 * tile_height);
+            let block = target.view(x, y, tile_width.min(width - x), tile_height.min(height - y));
+            let mut wanted: Key<N> = compute_key(&block, &keys);
+            for (c, error) in wanted.iter_mut().zip(errors[row * columns + column]) {
+                *c = (f64::from(*c) + error)
+                    .round()
+                    .clamp(f64::from(i16::MIN), f64::from(i16::MAX)) as i16;
+            }
+            let pick = choose(wanted, &block);
+            let got = key_of(pick);
+            for (dx, dy, share) in [
+                (ahead, 0, 7.0),
+                (-ahead, 1, 3.0),
+                (0, 1, 5.0),
+                (ahead, 1, 1.0),
+            ] {
+                let (x, y) = (column as isize + dx, row + dy);
+                if !(0..columns as isize).contains(&x) || y >= rows {
+                    continue;
+                }
+                let error = &mut errors[y * columns + x as usize];
+                for c in 0..N {
+                    error[c] += (f64::from(wanted[c]) - f64::from(got[c])) * share / 16.0;
+                }
+            }
+            bar.inc(1);
+            replacements.push((x, y, pick));
+        }
     }
+    bar.finish_and_clear();
+    // in the order `match_blocks` has them
+    replacements.sort_unstable_by_key(|&(x, y, _)| (x, y));
+    replacements
+}
 
-    out_img.save("out.png").unwrap();
+/// The tile closest to `key`, or one at most `1 + epsilon` times as far.
+/// With `Metric::Ciede2000` the closest few in CIELAB are compared by their
+/// CIEDE2000 difference, which the tree can't search by itself.
+fn find_closest<I, const N: usize>(
+    bldb: &BlockDb<i16, I, N>,
+    key: Key<N>,
+    metric: Metric,
+    epsilon: f64,
+) -> &I {
+    if metric != Metric::Ciede2000 && epsilon > 0.0 {
+        return bldb.find_closest_approx(key, epsilon).unwrap();
+    }
+    if metric != Metric::Ciede2000 {
+        let (closest, _, _) = bldb.find_closest_with_dist(key).unwrap();
+        return closest;
+    }
+    // keys of several cells add up the differences of the cells
+    let lab = |cell: &[i16]| color::key_to_lab([cell[0], cell[1], cell[2]]);
+    let difference = |k: &Key<N>| -> f64 {
+        key.chunks(3)
+            .zip(k.chunks(3))
+            .map(|(a, b)| color::ciede2000(lab(a), lab(b)))
+            .sum()
+    };
+    bldb.find_k_closest_keyed(key, RERANKED_CANDIDATES)
+        .into_iter()
+        .map(|(k, item)| (difference(k), item))
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .unwrap()
+        .1
 }
 
-fn find_input_images() -> Vec<std::path::PathBuf>
-{
- fs::read_dir("input")
+/// Of the `candidates` tiles closest to `key`, the one with the smallest
+/// `distance` to the block, which gets the tile and how the variant turns it.
+fn rerank<'a, const N: usize>(
+    bldb: &'a BlockDb<i16, Variant, N>,
+    tiles: &[Tile],
+    key: Key<N>,
+    candidates: usize,
+    distance: impl Fn(&Tile, Orientation) -> f64,
+) -> &'a Variant {
+    bldb.find_k_closest(key, candidates)
+        .into_iter()
+        .map(|(v, _)| (distance(&tiles[v.tile], v.orientation), v))
+        .min_by(|a, b| a.0.total_cmp(&b.0))
         .unwrap()
-        .filter_map(|p| p.ok())
-        .map(|p| p.path())
-        .filter(|p| p.extension().map_or(false, |e| e ==  "jpg"))
+        .1
+}
+
+/// Of the tiles about as close to `key` as the closest one, picks the one
+/// whose source weighs the most.
+fn pick_weighted<'a, const N: usize>(
+    bldb: &'a BlockDb<i16, Variant, N>,
+    tiles: &[Tile],
+    sources: &[Source],
+    key: Key<N>,
+    epsilon: f64,
+) -> &'a Variant {
+    let weight = |v: &Variant| sources[tiles[v.tile].source].weight;
+    let candidates = bldb.find_k_closest(key, WEIGHED_CANDIDATES);
+    let closest = (candidates[0].1 as f64).sqrt();
+    let mut best = candidates[0].0;
+    for &(variant, dist) in &candidates[1..] {
+        if (dist as f64).sqrt() - closest > epsilon {
+            break;
+        }
+        if weight(variant) > weight(best) {
+            best = variant;
+        }
+    }
+    best
+}
+
+/// `n` with its digits in groups of three, like `182,344`.
+fn group_thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::with_capacity(digits.len() * 4 / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+fn compose<'a>(
+    (width, height): (u32, u32),
+    blocks: impl Iterator<Item = (u32, u32, &'a image::RgbImage)>,
+) -> image::RgbImage {
+    let mut out_img: image::RgbImage = image::ImageBuffer::new(width, height);
+    for (x, y, blk) in blocks {
+        image::imageops::replace(&mut out_img, blk, x, y);
+    }
+    out_img
+}
+
+/// Renders once, then polls the inputs and renders again whenever files are
+/// added or removed. Only the added files get decoded, --load-db is only
+/// read for the first render and --save-db is written after every one.
+fn watch_inputs(args: &Args, decode_opts: &DecodeOptions) -> ! {
+    let roots = input_roots(args);
+    if args.url_list.is_some()
+        || args.list_file.as_deref() == Some(Path::new("-"))
+        || roots
+            .iter()
+            .any(|r| archive::is_tar(r) || archive::is_zip(r))
+    {
+        exit_with("--watch only works with input directories, list files and globs");
+    }
+    let (target, stretch) = load_target(args, decode_opts).unwrap_or_else(|e| exit_with(&e));
+    let dirs: Vec<&PathBuf> = roots.iter().collect();
+    let weights = Weights::load(&dirs).unwrap_or_else(|e| exit_with(&e));
+    let list = || {
+        let (found, _) = gather_inputs(args, &dirs).unwrap_or_else(|e| exit_with(&e));
+        apply_excludes(found, &args.exclude)
+            .0
+            .into_iter()
+            .collect::<BTreeSet<PathBuf>>()
+    };
+    let mut cutter = TileCutter::new(args);
+    // the tiles of every frame of every file
+    let mut sources: BTreeMap<PathBuf, Vec<Vec<CutTile>>> = BTreeMap::new();
+    let mut known = BTreeSet::new();
+    let mut changes = watch::diff(&known, &list());
+    let mut first = true;
+    loop {
+        let start = Instant::now();
+        for path in &changes.removed {
+            sources.remove(path);
+            known.remove(path);
+        }
+        known.extend(changes.added.iter().cloned());
+        let added = select_inputs(args, changes.added.clone());
+        decode_files(args, &added, decode_opts, &mut |path, frames| {
+            sources.insert(path.to_path_buf(), cutter.cut_frames(path, frames));
+        });
+        let mut set = TileSet::capped(args.max_total_tiles, args.seed);
+        set.weighted = !weights.is_empty();
+        for (path, frames) in &sources {
+            let name = path.display().to_string();
+            for (frame, tiles) in frames.iter().enumerate() {
+                let label = frame_label(&name, frame, frames.len());
+                set.add(&label, weights.get(path), tiles.clone());
+            }
+        }
+        if set.tiles.is_empty() {
+            eprintln!("No input images");
+        } else {
+            let count = set.tiles.len();
+            let canvas = Canvas {
+                target: &target,
+                stretch,
+                tile: tile_size(args),
+                out: PathBuf::from("out.png"),
+                save_db: args.save_db.clone(),
+                load_db: args.load_db.clone().filter(|_| first),
+            };
+            draw(args, decode_opts, set, &canvas);
+            eprintln!(
+                "rendered out.png from {} tiles (+{} -{} files) in {:.1?}",
+                count,
+                changes.added.len(),
+                changes.removed.len(),
+                start.elapsed()
+            );
+        }
+        first = false;
+        changes = watch::wait_for_changes(&known, &list);
+    }
+}
+
+/// Cuts the inputs into tiles of every one of `sizes` while decoding each
+/// of them once, and renders out-<size>.png for each size.
+fn render_sizes(args: &Args, decode_opts: &DecodeOptions, sizes: &[u32]) {
+    let roots = input_roots(args);
+    if args.url_list.is_some()
+        || roots
+            .iter()
+            .any(|r| archive::is_tar(r) || archive::is_zip(r))
+    {
+        exit_with("--sizes only works with input directories, list files and globs");
+    }
+    let dirs: Vec<&PathBuf> = roots.iter().collect();
+    let weights = Weights::load(&dirs).unwrap_or_else(|e| exit_with(&e));
+    let (input, missing) = gather_inputs(args, &dirs).unwrap_or_else(|e| exit_with(&e));
+    let input = select_inputs(args, input);
+    let tile_sizes: Vec<(u32, u32)> = sizes.iter().map(|&s| (s, s)).collect();
+    let mut cutter = TileCutter::new(args);
+    let mut sets: Vec<TileSet> = sizes
+        .iter()
+        .map(|_| TileSet {
+            weighted: !weights.is_empty(),
+            ..TileSet::capped(args.max_total_tiles, args.seed)
+        })
+        .collect();
+    decode_files(args, &input, decode_opts, &mut |path, frames| {
+        let name = path.display().to_string();
+        let count = frames.len();
+        for (i, frame) in frames.into_iter().enumerate() {
+            let label = frame_label(&name, i, count);
+            let cut = cutter.cut_sizes(&label, frame, &tile_sizes);
+            for (set, tiles) in sets.iter_mut().zip(cut) {
+                set.add(&label, weights.get(path), tiles);
+            }
+        }
+    });
+    cutter.report();
+    if sets.iter().all(|set| set.tiles.is_empty()) {
+        eprintln!("No input images");
+        return;
+    }
+
+    let (target, stretch) = load_target(args, decode_opts).unwrap_or_else(|e| exit_with(&e));
+    for (&size, set) in sizes.iter().zip(sets) {
+        if set.max_tiles > 0 {
+            eprintln!(
+                "{}x{}: kept {} of {} tiles (--max-total-tiles {})",
+                size,
+                size,
+                set.tiles.len(),
+                set.considered,
+                set.max_tiles
+            );
+        } else {
+            eprintln!("{}x{}: {} tiles", size, size, set.tiles.len());
+        }
+        if set.tiles.is_empty() {
+            continue;
+        }
+        let canvas = Canvas {
+            target: &target,
+            stretch,
+            tile: (size, size),
+            out: PathBuf::from(format!("out-{}.png", size)),
+            save_db: args.save_db.as_deref().map(|p| sized_path(p, size)),
+            load_db: args.load_db.as_deref().map(|p| sized_path(p, size)),
+        };
+        draw(args, decode_opts, set, &canvas);
+    }
+    if !missing.is_empty() {
+        eprintln!("{} listed input files do not exist", missing.len());
+    }
+}
+
+/// `path` with `-<size>` added to the file name before the extension, like
+/// out-<size>.png.
+fn sized_path(path: &Path, size: u32) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!("-{}", size));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// The variant whose hash differs from `hash` in the fewest bits, the one
+/// with the closest key among equally close hashes.
+fn closest_hash<const N: usize>(
+    hashed: &[(u64, Key<N>, Variant)],
+    hash: u64,
+    key: Key<N>,
+) -> &Variant {
+    hashed
+        .iter()
+        .min_by_key(|(h, k, _)| {
+            let squared: i64 = (0..N)
+                .map(|i| (i64::from(k[i]) - i64::from(key[i])).pow(2))
+                .sum();
+            (phash::distance(*h, hash), squared)
+        })
+        .map(|(_, _, variant)| variant)
+        .expect("tile set is not empty")
+}
+
+/// How many of the closest tiles are compared by weight.
+const WEIGHED_CANDIDATES: usize = 8;
+
+/// How many of the closest tiles in CIELAB are compared by CIEDE2000.
+const RERANKED_CANDIDATES: usize = 8;
+
+/// A tile of one of the sources, and where in the source it came from.
+struct Tile {
+    /// index into `TileSet::sources`
+    source: usize,
+    /// the part of the source image the tile shows, in the pixels of the
+    /// image before --max-input-dimension
+    region: Region,
+    pixels: Pixels,
+    /// white balance of the source, applied before keying
+    gains: Gains,
+    /// for weighting the key of loaded tiles, see `CutTile::alpha`
+    alpha: Option<image::GrayImage>,
+    /// for reranking matches by more than the average color
+    histogram: Histogram,
+    edges: Edges,
+    phashes: Option<[u64; 8]>,
+}
+
+/// Tiles of files found in the manifest are only known by their key until
+/// they get picked.
+enum Pixels {
+    Loaded(image::RgbImage),
+    Cached {
+        key: Vec<i16>,
+        /// position of the tile in its source's grid
+        index: usize,
+    },
+}
+
+/// A tile as it goes into the database, possibly flipped.
+struct Variant {
+    /// index into `TileSet::tiles`
+    tile: usize,
+    orientation: Orientation,
+}
+
+/// The tile index and the `Orientation::index`, for --save-db.
+impl Record for Variant {
+    const BYTES: usize = 9;
+    const TAG: u8 = 16;
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.tile as u64).to_le_bytes());
+        out.push(self.orientation.index() as u8);
+    }
+
+    fn read(bytes: &[u8]) -> Option<Self> {
+        let (tile, orientation) = bytes.split_at(8);
+        Some(Variant {
+            tile: u64::from_le_bytes(tile.try_into().ok()?).try_into().ok()?,
+            orientation: Orientation::from_index(usize::from(*orientation.first()?))?,
+        })
+    }
+}
+
+/// What --save-db records and --load-db checks: the tiles, where they came
+/// from and everything that goes into their keys.
+fn db_header(args: &Args, tile: (u32, u32), tiles: &[Tile], sources: &[Source]) -> cache::DbHeader {
+    let mut origins = Vec::new();
+    for tile in tiles {
+        origins.extend_from_slice(sources[tile.source].name.as_bytes());
+        origins.extend_from_slice(format!(" {:?}\n", tile.region).as_bytes());
+    }
+    cache::DbHeader {
+        tile_size: tile,
+        tiles: tiles.len(),
+        fingerprint: stable_hash_bytes(&origins),
+        settings: format!("{} augment={:?}", cache_settings(args), args.augment),
+    }
+}
+
+/// The key of a tile as it was cut, `KeySpec::orient` turns it into the
+/// keys of its variants.
+fn tile_key<const N: usize>(tile: &Tile, keys: KeySpec) -> Key<N> {
+    match &tile.pixels {
+        Pixels::Loaded(img) => {
+            keys.tile_key(&tiles::white_balance(img, tile.gains), tile.alpha.as_ref())
+        }
+        Pixels::Cached { key, .. } => key
+            .as_slice()
+            .try_into()
+            .expect("the manifest was written with the same --key"),
+    }
+}
+
+/// Which tile went where in the collage.
+struct Placement<'a> {
+    /// top left corner in the collage
+    x: u32,
+    y: u32,
+    /// name of the source image, like `dir/file.gif#frame2`
+    source: &'a str,
+    region: Region,
+    orientation: Orientation,
+}
+
+fn placements<'a>(
+    picks: &[(u32, u32, &Variant)],
+    tiles: &[Tile],
+    sources: &'a [Source],
+) -> Vec<Placement<'a>> {
+    picks
+        .iter()
+        .map(|&(x, y, variant)| {
+            let tile = &tiles[variant.tile];
+            Placement {
+                x,
+                y,
+                source: &sources[tile.source].name,
+                region: tile.region,
+                orientation: variant.orientation,
+            }
+        })
         .collect()
 }
 
-#[derive(Debug)]
-struct Pos {
-    r: u64,
-    g: u64,
-    b: u64,
+/// An image or animation frame that tiles were cut from.
+struct Source {
+    /// path or URL, with the frame for animations
+    name: String,
+    weight: f64,
+    /// file and frame to restore the tiles from if they came from the
+    /// manifest
+    cached: Option<(PathBuf, usize)>,
+}
+
+#[derive(Default)]
+struct TileSet {
+    tiles: Vec<Tile>,
+    sources: Vec<Source>,
+    /// whether any source has a weight from a `weights.toml`
+    weighted: bool,
+    /// size of the reservoir `tiles` is sampled into, 0 keeps every tile
+    max_tiles: usize,
+    /// how many tiles were offered to the set
+    considered: usize,
+    rng: Option<StdRng>,
+}
+
+impl TileSet {
+    /// A set that keeps a random sample of at most `max_tiles` of the tiles
+    /// added to it, the same sample for the same `seed`.
+    fn capped(max_tiles: usize, seed: u64) -> Self {
+        TileSet {
+            max_tiles,
+            rng: Some(StdRng::seed_from_u64(seed)),
+            ..TileSet::default()
+        }
+    }
+
+    /// Reservoir sampling: once the set is full, the n-th tile replaces a
+    /// random one with probability `max_tiles / n`.
+    fn push(&mut self, tile: Tile) {
+        self.considered += 1;
+        if self.max_tiles == 0 || self.tiles.len() < self.max_tiles {
+            self.tiles.push(tile);
+            return;
+        }
+        let rng = self.rng.as_mut().expect("capped sets have a rng");
+        let slot = rng.gen_range(0..self.considered);
+        if slot < self.max_tiles {
+            self.tiles[slot] = tile;
+        }
+    }
+
+    fn add(&mut self, name: &str, weight: f64, tiles: Vec<CutTile>) {
+        let source = self.sources.len();
+        self.sources.push(Source {
+            name: name.to_string(),
+            weight,
+            cached: None,
+        });
+        for tile in tiles {
+            let balanced = tiles::white_balance(&tile.img, tile.gains);
+            let (histogram, edges) = (
+                Histogram::of(balanced.as_ref()),
+                Edges::of(balanced.as_ref()),
+            );
+            self.push(Tile {
+                source,
+                region: tile.region,
+                gains: tile.gains,
+                alpha: tile.alpha,
+                histogram,
+                edges,
+                phashes: tile.phashes,
+                pixels: Pixels::Loaded(tile.img),
+            });
+        }
+    }
+
+    fn add_cached(
+        &mut self,
+        name: &str,
+        weight: f64,
+        file: &Path,
+        frame: usize,
+        tiles: &[CachedTile],
+    ) {
+        let source = self.sources.len();
+        self.sources.push(Source {
+            name: name.to_string(),
+            weight,
+            cached: Some((file.to_path_buf(), frame)),
+        });
+        for (index, tile) in tiles.iter().enumerate() {
+            self.push(Tile {
+                source,
+                region: tile.region,
+                gains: tile.gains,
+                alpha: None,
+                histogram: tile.histogram,
+                edges: tile.edges,
+                phashes: tile.phashes,
+                pixels: Pixels::Cached {
+                    key: tile.key.clone(),
+                    index,
+                },
+            });
+        }
+    }
 }
 
-impl From<Pos> for [i16; 3] {
-    fn from(p: Pos) -> Self {
-        [p.r as i16, p.g as i16, p.b as i16]
+/// Decodes every source image and cuts it into tiles. Also returns how many
+/// paths from `--list-file` did not exist.
+fn load_tiles(args: &Args, decode_opts: &DecodeOptions) -> (TileSet, usize) {
+    let roots = input_roots(args);
+    let mut missing = Vec::new();
+    let mut set = TileSet::capped(args.max_total_tiles, args.seed);
+    let mut cutter = TileCutter::new(args);
+    // every decoded image is cut into owned tiles right away and then
+    // dropped, so memory scales with the tile count, not input megapixels
+    if let Some(list) = &args.url_list {
+        download_images(args, list, decode_opts, &mut |name, decoded| {
+            set.add(name, 1.0, cutter.cut(name, decoded))
+        });
+    } else {
+        let (archives, dirs): (Vec<&PathBuf>, Vec<&PathBuf>) = roots
+            .iter()
+            .partition(|r| archive::is_tar(r) || archive::is_zip(r));
+        if !dirs.is_empty() || args.list_file.is_some() || args.input_glob.is_some() {
+            let (input, not_found) = gather_inputs(args, &dirs).unwrap_or_else(|e| exit_with(&e));
+            if let Some(pattern) = &args.input_glob {
+                eprintln!("{} files matched {}", input.len(), pattern);
+            }
+            missing = not_found;
+            let weights = Weights::load(&dirs).unwrap_or_else(|e| exit_with(&e));
+            set.weighted = !weights.is_empty();
+            let input = select_inputs(args, input);
+            load_files(args, input, decode_opts, &weights, &mut cutter, &mut set);
+        }
+        for root in archives {
+            let visit = |name: &str, bytes: &[u8]| {
+                let name = root.join(name).display().to_string();
+                let frames = decode_frames(bytes, decode_opts)?;
+                add_frames(&name, frames, &mut |name, decoded| {
+                    set.add(name, 1.0, cutter.cut(name, decoded))
+                });
+                Ok(())
+            };
+            let report = if archive::is_tar(root) {
+                archive::for_each_tar_entry(root, &args.extensions, visit)
+            } else {
+                archive::for_each_zip_entry(root, &args.extensions, visit)
+            };
+            report_archive(args, &report.unwrap_or_else(|e| exit_with(&e)));
+        }
+    }
+    cutter.report();
+    if set.max_tiles > 0 {
+        eprintln!(
+            "kept {} of {} tiles (--max-total-tiles {})",
+            set.tiles.len(),
+            set.considered,
+            set.max_tiles
+        );
     }
+    (set, missing.len())
 }
 
-fn avg_color(img: &image::SubImage<&image::RgbImage>) -> Pos {
-    let mut out = Pos { r: 0, g: 0, b: 0 };
+/// Cuts the tiles of local input files. Files with a valid manifest entry
+/// are not decoded, their tiles are restored only if they get picked.
+fn load_files(
+    args: &Args,
+    input: Vec<PathBuf>,
+    opts: &DecodeOptions,
+    weights: &Weights,
+    cutter: &mut TileCutter,
+    set: &mut TileSet,
+) {
+    let path = manifest_path(args);
+    let mut manifest = path
+        .as_ref()
+        .map(|p| Manifest::load(p, &cache_settings(args)));
+    let uncached: Vec<PathBuf> = input
+        .iter()
+        .filter(|p| manifest.as_mut().map_or(true, |m| m.lookup(p).is_none()))
+        .cloned()
+        .collect();
+    let mut fresh = HashMap::new();
+    decode_files(args, &uncached, opts, &mut |path, frames| {
+        fresh.insert(path.to_path_buf(), cutter.cut_frames(path, frames));
+    });
+    // tiles are added in `input` order either way, so a warm manifest yields
+    // the same tree as a cold one
+    for file in input {
+        let label =
+            |frame: usize, count: usize| frame_label(&file.display().to_string(), frame, count);
+        if let Some(frames) = fresh.remove(&file) {
+            if let Some(manifest) = &mut manifest {
+                let cached = frames
+                    .iter()
+                    .map(|tiles| {
+                        tiles
+                            .iter()
+                            .map(|t| {
+                                let balanced = tiles::white_balance(&t.img, t.gains);
+                                CachedTile {
+                                    key: key_spec(args).tile_key_vec(&balanced, t.alpha.as_ref()),
+                                    region: t.region,
+                                    gains: t.gains,
+                                    histogram: Histogram::of(balanced.as_ref()),
+                                    edges: Edges::of(balanced.as_ref()),
+                                    phashes: t.phashes,
+                                }
+                            })
+                            .collect()
+                    })
+                    .collect();
+                manifest.insert(&file, cached);
+            }
+            let count = frames.len();
+            for (frame, tiles) in frames.into_iter().enumerate() {
+                set.add(&label(frame, count), weights.get(&file), tiles);
+            }
+        } else if let Some(entry) = manifest.as_mut().and_then(|m| m.lookup(&file)) {
+            let count = entry.frames.len();
+            for (frame, tiles) in entry.frames.iter().enumerate() {
+                let name = label(frame, count);
+                set.add_cached(&name, weights.get(&file), &file, frame, tiles);
+                cutter.count(&file, tiles.len());
+            }
+        }
+    }
+    if let (Some(manifest), Some(path)) = (&mut manifest, &path) {
+        manifest.prune();
+        if let Err(e) = manifest.save(path) {
+            eprintln!("warning: could not write {}: {}", path.display(), e);
+        }
+    }
+}
 
-    let mut count = 0;
-    for p in img.pixels().map(|(_, _, p)| p) {
-        count += 1;
-        let (r, g, b) = (p[0], p[1], p[2]);
-        out.r += r as u64;
-        out.g += g as u64;
-        out.b += b as u64;
+/// Where the tile manifest is kept, `None` if it is not used.
+fn manifest_path(args: &Args) -> Option<PathBuf> {
+    if args.no_cache || args.dedupe_similar.is_some() {
+        return None;
     }
+    if let Some(path) = &args.cache {
+        return Some(path.clone());
+    }
+    let dir = input_roots(args).into_iter().find(|r| r.is_dir());
+    Some(dir.unwrap_or_default().join(cache::MANIFEST_NAME))
+}
 
-    out.r /= count;
-    out.g /= count;
-    out.b /= count;
+/// Every option that changes which tiles a file yields.
+fn cache_settings(args: &Args) -> String {
+    format!(
+        "tile-size={:?} source-stride={:?} tile-mode={:?} crops-per-image={} crop-gravity={:?} tile-filter={:?} max-input-dimension={} filter={:?} alpha-background={:?} exposure={} reject-transparent-tiles={} \
+         ignore-exif={} max-frames-per-animation={:?} max-tiles-per-image={:?} min-tile-variance={} max-dominant-fraction={:?} normalize={:?} white-balance={:?} linear-average={} key={:?} metric={:?} channel-weights={:?} tile-blur={:?} quantize={:?} key-gamma={} pad-small-inputs={:?} seed={}",
+        tile_size(args),
+        args.source_stride,
+        args.tile_mode,
+        args.crops_per_image,
+        args.crop_gravity,
+        tile_filter(args).0,
+        args.max_input_dimension,
+        args.filter.0,
+        args.alpha_background.0,
+        args.exposure,
+        args.reject_transparent_tiles,
+        args.ignore_exif,
+        args.max_frames_per_animation,
+        args.max_tiles_per_image,
+        args.min_tile_variance,
+        args.max_dominant_fraction,
+        normalization(args),
+        args.white_balance,
+        args.linear_average,
+        args.key,
+        args.metric,
+        args.channel_weights.0,
+        args.tile_blur,
+        args.quantize,
+        args.key_gamma,
+        args.pad_small_inputs,
+        args.seed
+    )
+}
+
+/// Decodes the files behind picked tiles that so far were only known from
+/// the manifest, and cuts those tiles again. Keyed by (source, index).
+fn restore_cached<'a>(
+    args: &Args,
+    opts: &DecodeOptions,
+    sources: &[Source],
+    picked: impl Iterator<Item = &'a Tile>,
+) -> HashMap<(usize, usize), image::RgbImage> {
+    let mut wanted: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+    for tile in picked {
+        if let Pixels::Cached { index, .. } = tile.pixels {
+            wanted.entry(tile.source).or_default().insert(index);
+        }
+    }
+    let restored: Result<Vec<Vec<_>>, String> = wanted
+        .par_iter()
+        .map(|(&source, indices)| {
+            let (path, frame) = sources[source]
+                .cached
+                .as_ref()
+                .expect("cached tiles know their file");
+            let fail = |e: &str| format!("Could not restore tiles of {}: {}", path.display(), e);
+            let mut frames = decode_file_frames(path, opts).map_err(|e| fail(&e))?;
+            if *frame >= frames.len() {
+                return Err(fail("frame is missing"));
+            }
+            let name = frame_label(&path.display().to_string(), *frame, frames.len());
+            let decoded = frames.swap_remove(*frame);
+            let img = tiles::limit_dimension(decoded.rgb, args.max_input_dimension, args.filter);
+            let mut tiles = cut_image(args, tile_size(args), &name, &img, decoded.alpha).0;
+            indices
+                .iter()
+                .map(|&i| match tiles.get_mut(i) {
+                    Some(tile) => Ok(((source, i), std::mem::take(&mut tile.img))),
+                    None => Err(fail("the file changed, run again")),
+                })
+                .collect()
+        })
+        .collect();
+    let restored = restored.unwrap_or_else(|e| exit_with(&e));
+    restored.into_iter().flatten().collect()
+}
+
+/// Turns decoded source images into tiles, applying the per-image filters
+/// and keeping the counts that get reported once loading is done.
+struct TileCutter<'a> {
+    args: &'a Args,
+    roots: Vec<PathBuf>,
+    root_tiles: Vec<usize>,
+    similar: Option<NearDuplicates>,
+    too_small: usize,
+    padded: usize,
+    rejected: Rejected,
+}
+
+/// A freshly cut tile and the part of its source image it shows.
+#[derive(Clone)]
+struct CutTile {
+    img: image::RgbImage,
+    region: Region,
+    /// white balance of the source the tile was cut from
+    gains: Gains,
+    /// the part of the source's alpha channel the tile shows, `None` for
+    /// opaque sources
+    alpha: Option<image::GrayImage>,
+    /// perceptual hashes of every orientation, with --metric phash
+    phashes: Option<[u64; 8]>,
+}
+
+/// How many tiles the tile filters left out, by filter.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Rejected {
+    transparent: usize,
+    flat: usize,
+    dominated: usize,
+}
+
+impl<'a> TileCutter<'a> {
+    fn new(args: &'a Args) -> Self {
+        let roots = input_roots(args);
+        TileCutter {
+            args,
+            root_tiles: vec![0; roots.len()],
+            roots,
+            similar: args.dedupe_similar.map(NearDuplicates::new),
+            too_small: 0,
+            padded: 0,
+            rejected: Rejected::default(),
+        }
+    }
+
+    fn cut(&mut self, name: &str, decoded: Decoded) -> Vec<CutTile> {
+        let size = tile_size(self.args);
+        self.cut_sizes(name, decoded, &[size]).swap_remove(0)
+    }
+
+    /// Cuts an image into tiles of each of `sizes`, one list per size.
+    fn cut_sizes(
+        &mut self,
+        name: &str,
+        decoded: Decoded,
+        sizes: &[(u32, u32)],
+    ) -> Vec<Vec<CutTile>> {
+        let args = self.args;
+        let none = || sizes.iter().map(|_| Vec::new()).collect();
+        if decoded.precision_lost {
+            eprintln!("warning: {}: 16-bit samples rounded to 8 bits", name);
+        }
+        let original = decoded.rgb.dimensions();
+        let img = tiles::limit_dimension(decoded.rgb, args.max_input_dimension, args.filter);
+        let fits = |&size: &(u32, u32)| !args.tile_mode.cuts_cells() || tiles::fits(&img, size);
+        if args.pad_small_inputs.is_none() && !sizes.iter().any(fits) {
+            self.too_small += 1;
+            return none();
+        }
+        if self.similar.as_mut().is_some_and(|s| !s.admit(&img)) {
+            return none();
+        }
+        if args.pad_small_inputs.is_some() && !sizes.iter().all(fits) {
+            self.padded += 1;
+        }
+        let mut cut = Vec::with_capacity(sizes.len());
+        for &size in sizes {
+            let (mut tiles, rejected) = cut_image(args, size, name, &img, decoded.alpha.clone());
+            for tile in &mut tiles {
+                tile.region = tiles::scale_region(tile.region, img.dimensions(), original);
+            }
+            self.rejected.transparent += rejected.transparent;
+            self.rejected.flat += rejected.flat;
+            self.rejected.dominated += rejected.dominated;
+            if args.verbose && sizes.len() > 1 {
+                eprintln!("{}: {} {}x{} tiles", name, tiles.len(), size.0, size.1);
+            } else if args.verbose {
+                eprintln!("{}: {} tiles", name, tiles.len());
+            }
+            self.count(Path::new(name), tiles.len());
+            cut.push(tiles);
+        }
+        cut
+    }
+
+    /// Cuts every frame of a file, labelled like `file.gif#frame12`.
+    fn cut_frames(&mut self, path: &Path, frames: Vec<Decoded>) -> Vec<Vec<CutTile>> {
+        let name = path.display().to_string();
+        let count = frames.len();
+        frames
+            .into_iter()
+            .enumerate()
+            .map(|(i, frame)| self.cut(&frame_label(&name, i, count), frame))
+            .collect()
+    }
+
+    /// Adds `tiles` to the count of the input root `source` is in.
+    fn count(&mut self, source: &Path, tiles: usize) {
+        if let Some(i) = self.roots.iter().position(|r| source.starts_with(r)) {
+            self.root_tiles[i] += tiles;
+        }
+    }
+
+    fn report(&self) {
+        let args = self.args;
+        if let Some(similar) = &self.similar {
+            eprintln!(
+                "collapsed {} near-duplicate input images",
+                similar.collapsed
+            );
+        }
+        if self.rejected.transparent > 0 {
+            eprintln!(
+                "left out {} fully transparent tiles",
+                self.rejected.transparent
+            );
+        }
+        if self.rejected.flat > 0 {
+            eprintln!(
+                "left out {} tiles with a variance below {}",
+                self.rejected.flat, args.min_tile_variance
+            );
+        }
+        if let Some(fraction) = args
+            .max_dominant_fraction
+            .filter(|_| self.rejected.dominated > 0)
+        {
+            eprintln!(
+                "left out {} tiles with more than {} of a single color",
+                self.rejected.dominated, fraction
+            );
+        }
+        if self.padded > 0 {
+            eprintln!("padded {} inputs smaller than a tile", self.padded);
+        }
+        if self.too_small > 0 {
+            eprintln!(
+                "warning: {} inputs too small for {}x{} tiles",
+                self.too_small,
+                tile_size(args).0,
+                tile_size(args).1
+            );
+        }
+        if self.roots.len() > 1 && args.url_list.is_none() {
+            for (root, count) in self.roots.iter().zip(&self.root_tiles) {
+                eprintln!("{}: {} tiles", root.display(), count);
+            }
+        }
+    }
+}
+
+/// Cuts an image, already limited to `--max-input-dimension`, into tiles of
+/// `size`, normalized with --normalize-tiles and knowing the white balance
+/// of `img`. Also returns how many tiles the tile filters left out.
+/// `--max-tiles-per-image` picks the same cells for the same `name` and
+/// `--seed`, so tiles restored from the manifest line up with their keys.
+fn cut_image(
+    args: &Args,
+    size: (u32, u32),
+    name: &str,
+    img: &image::RgbImage,
+    alpha: Option<image::GrayImage>,
+) -> (Vec<CutTile>, Rejected) {
+    let (mut tiles, transparent) = cut_tiles(args, size, name, img, alpha);
+    let mut rejected = Rejected {
+        transparent,
+        ..Rejected::default()
+    };
+    if args.min_tile_variance > 0.0 {
+        let count = tiles.len();
+        tiles = tiles
+            .into_par_iter()
+            .filter(|tile| tiles::variance(&tile.img) >= args.min_tile_variance)
+            .collect();
+        rejected.flat = count - tiles.len();
+    }
+    if let Some(fraction) = args.max_dominant_fraction {
+        let count = tiles.len();
+        tiles = tiles
+            .into_par_iter()
+            .filter(|tile| tiles::dominant_fraction(&tile.img) <= fraction)
+            .collect();
+        rejected.dominated = count - tiles.len();
+    }
+    if let Some(target) = normalization(args) {
+        tiles
+            .par_iter_mut()
+            .for_each(|tile| tiles::normalize(&mut tile.img, target));
+    }
+    let gains = match args.white_balance {
+        Some(WhiteBalance::Auto) => tiles::gray_world_gains(img),
+        Some(WhiteBalance::Manual(gains)) => gains,
+        None => Gains::NONE,
+    };
+    tiles.par_iter_mut().for_each(|tile| {
+        tile.gains = gains;
+        if args.metric == Metric::Phash {
+            let balanced = tiles::white_balance(&tile.img, gains);
+            tile.phashes = Some(phash::oriented_hashes(&balanced));
+        }
+    });
+    (tiles, rejected)
+}
+
+/// The `--normalize-*` settings, `None` without --normalize-tiles.
+fn normalization(args: &Args) -> Option<Normalization> {
+    Some(Normalization {
+        mean: args.normalize_mean,
+        stddev: args.normalize_stddev,
+        max_gain: args.normalize_max_gain,
+    })
+    .filter(|_| args.normalize_tiles)
+}
+
+/// `cut_image` without the variance and dominant color filters and the
+/// normalization. Also returns how many fully transparent tiles were left
+/// out.
+fn cut_tiles(
+    args: &Args,
+    size: (u32, u32),
+    name: &str,
+    img: &image::RgbImage,
+    alpha: Option<image::GrayImage>,
+) -> (Vec<CutTile>, usize) {
+    let mut alpha =
+        alpha.map(|alpha| tiles::limit_dimension(alpha, args.max_input_dimension, args.filter));
+    if !args.tile_mode.cuts_cells() {
+        let (width, height) = img.dimensions();
+        let region =
+            tiles::single_tile_region(args.tile_mode, width, height, size, args.crop_gravity);
+        let (x, y, w, h) = region;
+        let visible = alpha
+            .as_ref()
+            .filter(|_| args.reject_transparent_tiles)
+            .map_or(true, |alpha| {
+                alpha.view(x, y, w, h).pixels().any(|(_, _, p)| p[0] != 0)
+            });
+        if !visible {
+            return (Vec::new(), 1);
+        }
+        let filter = tile_filter(args);
+        let tile = CutTile {
+            img: tiles::shrink_to_tile(img, region, size, filter),
+            region,
+            gains: Gains::NONE,
+            alpha: alpha.map(|alpha| tiles::shrink_to_tile(&alpha, region, size, filter)),
+            phashes: None,
+        };
+        return (vec![tile], 0);
+    }
+    let original = img.dimensions();
+    let mut offset = (0, 0);
+    let padded;
+    let img = match args.pad_small_inputs {
+        Some(padding) if !tiles::fits(img, size) => {
+            let (fill, alpha_fill) = match padding {
+                Padding::Color(color) => (Some(color), Some(image::Luma([255]))),
+                Padding::Mirror => (None, None),
+            };
+            let (rgb, at) = tiles::pad(img, size, fill);
+            alpha = alpha.map(|alpha| tiles::pad(&alpha, size, alpha_fill).0);
+            offset = at;
+            padded = rgb;
+            &padded
+        }
+        _ => img,
+    };
+    let (width, height) = img.dimensions();
+    let mut cells = if args.tile_mode == TileMode::RandomCrops {
+        let seed = args.seed ^ stable_hash(name);
+        tiles::random_cells(width, height, size, args.crops_per_image, seed)
+    } else {
+        let stride = args.source_stride.map_or(size, |s| (s, s));
+        tiles::grid(width, height, size, stride)
+    };
+    let mut rejected = 0;
+    if let Some(alpha) = alpha.as_ref().filter(|_| args.reject_transparent_tiles) {
+        let (visible, transparent) = tiles::visible_cells(alpha, cells, size);
+        cells = visible;
+        rejected = transparent;
+    }
+    if let Some(max) = args.max_tiles_per_image {
+        cells = sample(cells, max, args.seed ^ stable_hash(name));
+        cells.sort_unstable();
+    }
+    let alphas: Vec<_> = match &alpha {
+        Some(alpha) => tiles::cut_cells(alpha, &cells, size)
+            .into_iter()
+            .map(Some)
+            .collect(),
+        None => vec![None; cells.len()],
+    };
+    let tiles = tiles::cut_cells(img, &cells, size)
+        .into_iter()
+        .zip(cells)
+        .zip(alphas)
+        .map(|((img, (x, y)), alpha)| CutTile {
+            img,
+            region: tiles::unpad_region((x, y, size.0, size.1), offset, original),
+            gains: Gains::NONE,
+            alpha,
+            phashes: None,
+        })
+        .collect();
+    (tiles, rejected)
+}
+
+/// How the options ask for tiles and target blocks to be keyed.
+fn key_spec(args: &Args) -> KeySpec {
+    KeySpec {
+        linear: args.linear_average,
+        blur: args.tile_blur,
+        levels: args.quantize,
+        gamma: Some(args.key_gamma).filter(|&g| g != 1.0),
+        metric: args.metric,
+        weights: args.channel_weights,
+        mode: args.key,
+    }
+}
+
+/// `(width, height)` of the tiles, from --tile-width and --tile-height or
+/// else --size.
+fn tile_size(args: &Args) -> (u32, u32) {
+    (
+        args.tile_width.unwrap_or(args.size),
+        args.tile_height.unwrap_or(args.size),
+    )
+}
+
+/// The filter whole-image and cover tiles are shrunk with.
+fn tile_filter(args: &Args) -> Filter {
+    args.tile_filter.unwrap_or(args.filter)
+}
+
+/// The `--input` roots, `./input` if none were given.
+fn input_roots(args: &Args) -> Vec<PathBuf> {
+    if args.input.is_empty() {
+        vec![PathBuf::from("input")]
+    } else {
+        args.input.clone()
+    }
+}
+
+/// Like `argh::from_env`, but also accepts "-" as the target. argh takes a
+/// lone "-" for an unknown flag, so the parse is retried with each "-" moved
+/// behind a "--", where it counts as a positional argument.
+fn parse_args() -> Args {
+    let strings: Vec<String> = std::env::args().collect();
+    let cmd = Path::new(&strings[0])
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or(&strings[0]);
+    let strs: Vec<&str> = strings[1..].iter().map(|s| s.as_str()).collect();
+    let parsed = Args::from_args(&[cmd], &strs).or_else(|early_exit| {
+        (0..strs.len())
+            .filter(|&i| strs[i] == "-")
+            .find_map(|i| {
+                let mut moved = strs.clone();
+                moved.remove(i);
+                moved.extend(&["--", "-"]);
+                Args::from_args(&[cmd], &moved).ok()
+            })
+            .ok_or(early_exit)
+    });
+    let mut args = parsed.unwrap_or_else(|early_exit| {
+        println!("{}", early_exit.output);
+        std::process::exit(if early_exit.status.is_ok() { 0 } else { 1 })
+    });
+    if let Some(epsilon) = args.approx.take() {
+        if args.search_epsilon != 0.0 {
+            exit_with("--approx is another name for --search-epsilon, give only one");
+        }
+        args.search_epsilon = epsilon;
+    }
+    args
+}
+
+/// The target as blocks are matched against it: with its color cast taken
+/// out for --white-balance-target, its contrast stretched for
+/// --stretch-target and graded against --temperature-target. Also returns
+/// the stretch, for --restore-range.
+fn load_target(args: &Args, opts: &DecodeOptions) -> Result<(image::RgbImage, Stretch), String> {
+    let mut target = decode_target(args, opts)?;
+    if args.white_balance_target {
+        let gains = tiles::gray_world_gains(&target);
+        target = tiles::white_balance(&target, gains).into_owned();
+    }
+    let stretch = if args.stretch_target {
+        Stretch::of(&target)
+    } else {
+        Stretch::NONE
+    };
+    target = stretch.apply(&target).into_owned();
+    if args.temperature_target {
+        let gains = Gains::temperature(args.temperature).inverse();
+        target = tiles::white_balance(&target, gains).into_owned();
+    }
+    Ok((target, stretch))
+}
+
+/// Decodes the target image, reading it from stdin if it is given as "-".
+fn decode_target(args: &Args, opts: &DecodeOptions) -> Result<image::RgbImage, String> {
+    if args.target != "-" {
+        return decode_file(Path::new(&args.target), opts)
+            .map(|d| d.rgb)
+            .map_err(|e| format!("Could not open target {}: {}", args.target, e));
+    }
+    let mut bytes = Vec::new();
+    io::stdin()
+        .lock()
+        .read_to_end(&mut bytes)
+        .map_err(|e| format!("Could not read target from stdin: {}", e))?;
+    decode_bytes(&bytes, opts)
+        .map(|d| d.rgb)
+        .map_err(|e| format!("Could not decode target image from stdin: {}", e))
+}
+
+fn exit_with(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+/// Applies `--exclude`, duplicate removal and `--max-images` to the local
+/// input files.
+fn select_inputs(args: &Args, input: Vec<PathBuf>) -> Vec<PathBuf> {
+    let (input, excluded) = apply_excludes(input, &args.exclude);
+    if args.verbose {
+        for (path, pattern) in &excluded {
+            eprintln!("excluded {} (matched {})", path.display(), pattern.as_str());
+        }
+    }
+    let input = if args.keep_duplicates {
+        input
+    } else {
+        let (input, dropped) = dedup_identical(input);
+        if dropped > 0 {
+            eprintln!("dropped {} duplicate input files", dropped);
+        }
+        input
+    };
+    limit_inputs(args, input)
+}
+
+/// Decodes `input` and hands the frames of every file to `add_file` in input
+/// order. Only one batch of full-resolution images is held in memory at a
+/// time.
+fn decode_files(
+    args: &Args,
+    input: &[PathBuf],
+    opts: &DecodeOptions,
+    add_file: &mut dyn FnMut(&Path, Vec<Decoded>),
+) {
+    let bar = ProgressBar::new(input.len() as u64);
+    let mut failed = Vec::new();
+    for batch in input.chunks(rayon::current_num_threads().max(1)) {
+        // collecting keeps the order of `input`, so tiles enter the tree in
+        // the same order no matter which thread finished first
+        let decoded: Vec<Result<Vec<Decoded>, String>> = batch
+            .par_iter()
+            .map(|p| {
+                let i = decode_file_frames(p, opts);
+                bar.inc(1);
+                i
+            })
+            .collect();
+        for (p, i) in batch.iter().zip(decoded) {
+            match i {
+                Ok(frames) => add_file(p, frames),
+                Err(e) => failed.push((p.display().to_string(), e)),
+            }
+        }
+    }
+    bar.finish_and_clear();
+    report_failures(args, "inputs failed to decode", input.len(), &failed);
+}
+
+fn download_images(
+    args: &Args,
+    list: &Path,
+    opts: &DecodeOptions,
+    add_image: &mut dyn FnMut(&str, Decoded),
+) {
+    let urls = File::open(list)
+        .and_then(|f| download::read_url_list(BufReader::new(f)))
+        .unwrap_or_else(|e| {
+            exit_with(&format!(
+                "Could not read url list {}: {}",
+                list.display(),
+                e
+            ))
+        });
+    let urls = limit_inputs(args, urls);
+    let (fetched, mut failed) = download::fetch_all(&urls, args.cache_dir.as_deref());
+    for (url, bytes) in fetched {
+        match decode_frames(&bytes, opts) {
+            Ok(frames) => add_frames(&url, frames, add_image),
+            Err(e) => failed.push((url, e)),
+        }
+    }
+    report_failures(args, "urls could not be used", urls.len(), &failed);
+}
+
+/// Passes each frame of a source on, labelled like `file.gif#frame12`.
+fn add_frames(name: &str, frames: Vec<Decoded>, add_image: &mut dyn FnMut(&str, Decoded)) {
+    let count = frames.len();
+    for (i, frame) in frames.into_iter().enumerate() {
+        add_image(&frame_label(name, i, count), frame);
+    }
+}
+
+/// Applies `--max-images`, telling the user when inputs were left out.
+fn limit_inputs<T>(args: &Args, input: Vec<T>) -> Vec<T> {
+    let max = match args.max_images {
+        Some(max) if max < input.len() => max,
+        _ => return input,
+    };
+    eprintln!("using {} of {} input images", max, input.len());
+    sample(input, max, args.seed)
+}
+
+fn report_archive(args: &Args, report: &ArchiveReport) {
+    if report.skipped > 0 {
+        eprintln!("{} archive entries are not images", report.skipped);
+    }
+    report_failures(
+        args,
+        "archive entries failed to decode",
+        report.visited,
+        &report.failed,
+    );
+}
+
+/// Prints a summary like "3 of 1200 inputs failed to decode", listing the
+/// failures with `--verbose`, and aborts the run with `--strict`.
+fn report_failures(args: &Args, what: &str, total: usize, failed: &[(String, String)]) {
+    if failed.is_empty() {
+        return;
+    }
+    eprintln!("{} of {} {}", failed.len(), total, what);
+    if args.verbose {
+        for (name, e) in failed {
+            eprintln!("  {}: {}", name, e);
+        }
+    }
+    if args.strict {
+        std::process::exit(1);
+    }
+}
+
+fn gather_inputs(args: &Args, dirs: &[&PathBuf]) -> Result<(Vec<PathBuf>, Vec<PathBuf>), String> {
+    if let Some(list) = &args.list_file {
+        if list.as_os_str() == "-" {
+            return read_input_list(io::stdin().lock())
+                .map_err(|e| format!("Could not read input list from stdin: {}", e));
+        }
+        return File::open(list)
+            .and_then(|f| read_input_list(BufReader::new(f)))
+            .map_err(|e| format!("Could not read input list {}: {}", list.display(), e));
+    }
+    if let Some(pattern) = &args.input_glob {
+        return Ok((glob_input_images(pattern, &args.extensions)?, Vec::new()));
+    }
+    let opts = DirOptions {
+        extensions: args.extensions.clone(),
+        recursive: args.recursive,
+        no_follow_symlinks: args.no_follow_symlinks,
+    };
+    let mut found = Vec::new();
+    for dir in dirs {
+        let files = find_input_images(dir, &opts)
+            .map_err(|e| format!("Could not read input {}: {}", dir.display(), e))?;
+        found.extend(files);
+    }
+    Ok((dedup_canonical(found), Vec::new()))
+}
+
+#[test]
+fn gray_and_16_bit_inputs_are_matched() {
+    let encode = |img: image::DynamicImage| {
+        let mut png = Vec::new();
+        img.write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        png
+    };
+    let gray = encode(image::DynamicImage::ImageLuma8(
+        image::GrayImage::from_pixel(70, 70, image::Luma([200])),
+    ));
+    let deep = encode(image::DynamicImage::ImageRgb16(
+        image::ImageBuffer::from_pixel(70, 70, image::Rgb([65535u16, 0, 0])),
+    ));
+
+    let opts = DecodeOptions::default();
+    let mut tiles = Vec::new();
+    for png in &[gray, deep] {
+        tiles.extend(tiles::extract_tiles(
+            &decode_bytes(png, &opts).unwrap().rgb,
+            (32, 32),
+        ));
+    }
+    assert_eq!(tiles.len(), 2 * 2 * 2);
+    let db = BlockDb::new(tiles, |img| key::avg_color(img, None).into());
+    let light = db.find_closest_pos([190, 190, 190]).unwrap();
+    assert_eq!(light.get_pixel(0, 0), &image::Rgb([200, 200, 200]));
+    let red = db.find_closest_pos([250, 10, 10]).unwrap();
+    assert_eq!(red.get_pixel(0, 0), &image::Rgb([255, 0, 0]));
+}
+
+#[test]
+fn heavier_sources_win_near_ties() {
+    let mut set = TileSet::default();
+    let tile = |v| {
+        vec![CutTile {
+            img: image::RgbImage::from_pixel(4, 4, image::Rgb([v, 100, 100])),
+            region: (0, 0, 4, 4),
+            gains: Gains::NONE,
+            alpha: None,
+            phashes: None,
+        }]
+    };
+    set.add("a", 1.0, tile(100));
+    set.add("b", 5.0, tile(104));
+    set.add("c", 9.0, tile(200));
+    let sources = set.sources;
+    let tiles = set.tiles;
+    let bldb = variant_tree(&tiles, &[Orientation::ORIGINAL]);
+    let picked = |epsilon| pick_weighted(&bldb, &tiles, &sources, [100, 100, 100], epsilon).tile;
+    assert_eq!(picked(8.0), 1);
+    assert_eq!(picked(1.0), 0);
+}
+
+#[test]
+fn total_tiles_are_sampled_evenly() {
+    let cut = |i: u32| {
+        (0..10)
+            .map(|j| CutTile {
+                img: image::RgbImage::new(2, 2),
+                region: (i, j, 2, 2),
+                gains: Gains::NONE,
+                alpha: None,
+                phashes: None,
+            })
+            .collect()
+    };
+    let sample = |seed| {
+        let mut set = TileSet::capped(25, seed);
+        for i in 0..50 {
+            set.add(&i.to_string(), 1.0, cut(i));
+        }
+        assert_eq!(set.considered, 500);
+        set.tiles.iter().map(|t| t.region).collect::<Vec<_>>()
+    };
+    let kept = sample(3);
+    assert_eq!(kept.len(), 25);
+    assert_eq!(kept, sample(3));
+    assert_ne!(kept, sample(4));
+    assert!(kept.iter().any(|&(i, ..)| i >= 25));
+
+    let mut unlimited = TileSet::capped(0, 3);
+    unlimited.add("a", 1.0, cut(0));
+    assert_eq!(unlimited.tiles.len(), 10);
+}
+
+#[test]
+fn lab_keys_prefer_neutral_tiles() {
+    let tiles = [
+        image::RgbImage::from_pixel(4, 4, image::Rgb([30, 30, 60])),
+        image::RgbImage::from_pixel(4, 4, image::Rgb([50, 50, 50])),
+    ];
+    let block = image::RgbImage::from_pixel(4, 4, image::Rgb([30, 30, 30]));
+    let pick = |metric| {
+        let keys = KeySpec {
+            metric,
+            ..KeySpec::default()
+        };
+        let bldb = BlockDb::new(tiles.iter().collect(), move |img| {
+            keys.tile_key::<3>(img, None)
+        });
+        *bldb
+            .find_closest_pos(compute_key(&block, &keys))
+            .unwrap()
+            .get_pixel(0, 0)
+    };
+    assert_eq!(pick(Metric::Rgb), image::Rgb([30, 30, 60]));
+    assert_eq!(pick(Metric::Lab), image::Rgb([50, 50, 50]));
+}
+
+#[test]
+fn ciede2000_reranks_the_closest_tiles() {
+    let colors = vec![[129, 68, 133], [166, 36, 124]];
+    let bldb = BlockDb::new(colors, |&c| Metric::Lab.key(c));
+    let target = Metric::Lab.key([150, 64, 105]);
+    assert_eq!(
+        find_closest(&bldb, target, Metric::Lab, 0.0),
+        &[129, 68, 133]
+    );
+    assert_eq!(
+        find_closest(&bldb, target, Metric::Ciede2000, 0.0),
+        &[166, 36, 124]
+    );
+}
+
+#[test]
+fn hsv_keys_prefer_matching_hues() {
+    let colors = vec![[200, 120, 40], [120, 24, 24]];
+    let target = [200, 40, 40];
+    let pick = |metric: Metric| {
+        let bldb = BlockDb::new(colors.clone(), move |&c| metric.key(c));
+        *find_closest(&bldb, metric.key(target), metric, 0.0)
+    };
+    assert_eq!(pick(Metric::Rgb), [200, 120, 40]);
+    assert_eq!(pick(Metric::Hsv), [120, 24, 24]);
+}
+
+#[test]
+fn luma_keys_match_gray_targets_by_brightness() {
+    let colors = vec![[100, 100, 100], [60, 160, 130]];
+    let target = [128, 128, 128];
+    let pick = |metric: Metric| {
+        let bldb = BlockDb::new(colors.clone(), move |&c| metric.key(c));
+        *find_closest(&bldb, metric.key(target), metric, 0.0)
+    };
+    assert_eq!(pick(Metric::Rgb), [100, 100, 100]);
+    assert_eq!(pick(Metric::Luma), [60, 160, 130]);
+    assert_eq!(Metric::Luma.key([60, 160, 130]), [137, 0, 0]);
+}
+
+#[test]
+fn edges_of_the_target_are_covered() {
+    let target = image::RgbImage::from_fn(100, 70, |x, y| image::Rgb([x as u8, y as u8, 50]));
+    let tiles = vec![
+        image::RgbImage::from_pixel(32, 32, image::Rgb([20, 20, 50])),
+        image::RgbImage::from_pixel(32, 32, image::Rgb([90, 60, 50])),
+    ];
+    let bldb = BlockDb::new(tiles, |img| key::avg_color(img, None).into());
+    let out = render(
+        &target,
+        &bldb,
+        (32, 32),
+        KeySpec::default(),
+        PlaceOptions::default(),
+    );
+    assert_eq!(out.dimensions(), (100, 70));
+    assert!(out.pixels().all(|p| p[2] == 50));
+    assert_eq!(out.get_pixel(99, 69), &image::Rgb([90, 60, 50]));
+
+    let tall = vec![image::RgbImage::from_pixel(16, 40, image::Rgb([1, 2, 3]))];
+    let bldb = BlockDb::new(tall, |img| key::avg_color(img, None).into());
+    let out = render(
+        &target,
+        &bldb,
+        (16, 40),
+        KeySpec::default(),
+        PlaceOptions::default(),
+    );
+    assert!(out.pixels().all(|p| *p == image::Rgb([1, 2, 3])));
+}
+
+#[test]
+fn placements_know_their_source() {
+    let mut set = TileSet::default();
+    let tile = |x| CutTile {
+        img: image::RgbImage::new(4, 4),
+        region: (x, 8, 4, 4),
+        gains: Gains::NONE,
+        alpha: None,
+        phashes: None,
+    };
+    set.add("a.png", 1.0, vec![tile(0)]);
+    set.add("b.gif#frame1", 1.0, vec![tile(4), tile(12)]);
+    let flipped = Variant {
+        tile: 2,
+        orientation: Orientation::variants(&[Augment::Flips])[1],
+    };
+    let picks = [(0, 0, &flipped)];
+    let placed = placements(&picks, &set.tiles, &set.sources);
+    assert_eq!(placed[0].source, "b.gif#frame1");
+    assert_eq!(placed[0].region, (12, 8, 4, 4));
+    assert_eq!(placed[0].orientation, flipped.orientation);
+}
+
+#[test]
+fn quadrant_keys_follow_gradients() {
+    // the mean of the backwards tile is the mean of the target, the other
+    // one only gets its darker half a little too dark
+    let gradient = |dark_left: bool, dark: u8| {
+        image::RgbImage::from_fn(4, 4, |x, _| {
+            image::Rgb([if (x < 2) == dark_left { dark } else { 200 }; 3])
+        })
+    };
+    let tiles = [gradient(false, 40), gradient(true, 20)];
+    let target = gradient(true, 40);
+    let render_with = |mode| {
+        let keys = KeySpec {
+            mode,
+            ..KeySpec::default()
+        };
+        render_tiles(
+            &target,
+            tiles.iter().map(|t| (t, None)).collect(),
+            (4, 4),
+            keys,
+            PlaceOptions::default(),
+        )
+    };
+    assert_eq!(render_with(KeyMode::Mean), tiles[0]);
+    assert_eq!(render_with(KeyMode::Quadrants), tiles[1]);
+}
+
+#[test]
+fn mean_std_keys_match_texture() {
+    let noise = |dark: u8| {
+        image::RgbImage::from_fn(8, 8, move |x, y| {
+            image::Rgb(
+                [if (x * 3 + y * 5) % 7 < 3 {
+                    dark
+                } else {
+                    dark + 140
+                }; 3],
+            )
+        })
+    };
+    // the flat tile has exactly the average of the noisy target, the noisy
+    // tile is a little brighter
+    let target = noise(40);
+    let gray = compute_key::<3, _>(&target, &KeySpec::default()).map(|c| c as u8);
+    let flat = image::RgbImage::from_pixel(8, 8, image::Rgb(gray));
+    let tiles = [flat.clone(), noise(46)];
+    let render_with = |target: &image::RgbImage, mode| {
+        let keys = KeySpec {
+            mode,
+            ..KeySpec::default()
+        };
+        let tiles = tiles.iter().map(|t| (t, None)).collect();
+        render_tiles(target, tiles, (8, 8), keys, PlaceOptions::default())
+    };
+    assert_eq!(render_with(&target, KeyMode::Mean), flat);
+    assert_eq!(render_with(&target, KeyMode::MeanStd), tiles[1]);
+    assert_eq!(render_with(&flat, KeyMode::MeanStd), flat);
+}
+
+/// A source named `name` with `img` as its only tile, for each pair, and the
+/// tile's `phash::oriented_hashes` if `phash` is set.
+#[cfg(test)]
+fn whole_tiles<'a>(
+    images: impl IntoIterator<Item = (&'a str, image::RgbImage)>,
+    phash: bool,
+) -> TileSet {
+    let mut set = TileSet::default();
+    for (name, img) in images {
+        let (width, height) = img.dimensions();
+        let phashes = phash.then(|| phash::oriented_hashes(&img));
+        set.add(
+            name,
+            1.0,
+            vec![CutTile {
+                img,
+                region: (0, 0, width, height),
+                gains: Gains::NONE,
+                alpha: None,
+                phashes,
+            }],
+        );
+    }
+    set
+}
+
+/// The database `collage` would build for `tiles` with default keys.
+#[cfg(test)]
+fn variant_tree<const N: usize>(
+    tiles: &[Tile],
+    orientations: &[Orientation],
+) -> BlockDb<i16, Variant, N> {
+    let keys = KeySpec::default();
+    let variants = (0..tiles.len())
+        .flat_map(|tile| {
+            let key = tile_key::<N>(&tiles[tile], keys);
+            orientations.iter().map(move |&orientation| {
+                (keys.orient(key, orientation), Variant { tile, orientation })
+            })
+        })
+        .collect();
+    BlockDb::from_keyed(variants)
+}
+
+#[test]
+fn histograms_rerank_close_tiles() {
+    let stripes = |dark: u8| {
+        image::RgbImage::from_fn(4, 4, move |x, _| {
+            image::Rgb([if x % 2 == 0 { dark } else { 200 }; 3])
+        })
+    };
+    let gray = image::RgbImage::from_pixel(4, 4, image::Rgb([120; 3]));
+    let tiles = whole_tiles([("gray", gray), ("striped", stripes(30))], false).tiles;
+    let bldb = variant_tree(&tiles, &[Orientation::ORIGINAL]);
+    let block = stripes(40);
+    let key: Key<3> = compute_key(&block, &KeySpec::default());
+    assert_eq!(find_closest(&bldb, key, Metric::Rgb, 0.0).tile, 0);
+    let histogram = Histogram::of(&block);
+    let reranked = |candidates| {
+        rerank(&bldb, &tiles, key, candidates, |tile, _| {
+            tile.histogram.chi_square(&histogram)
+        })
+        .tile
+    };
+    assert_eq!(reranked(1), 0);
+    assert_eq!(reranked(16), 1);
+}
+
+#[test]
+fn edges_follow_a_diagonal_line() {
+    let line = |on: fn(u32, u32) -> bool, size| {
+        image::RgbImage::from_fn(size, size, move |x, y| {
+            image::Rgb([if on(x, y) { 20 } else { 220 }; 3])
+        })
+    };
+    let rising = line(|x, y| x + y == 3, 4);
+    let flat = image::RgbImage::from_pixel(4, 4, image::Rgb([220; 3]));
+    let tiles = whole_tiles([("rising", rising), ("flat", flat)], false).tiles;
+    let bldb = variant_tree::<3>(&tiles, &Orientation::variants(&[Augment::Flips]));
+    let target = line(|x, y| x == y, 16);
+    let picks = match_blocks(&target, (4, 4), KeySpec::default(), |key, block| {
+        let edges = Edges::of(block);
+        rerank(&bldb, &tiles, key, 16, |tile, orientation| {
+            tile.edges.oriented(orientation).distance(&edges)
+        })
+    });
+    for (x, y, variant) in picks {
+        let tile = match &tiles[variant.tile].pixels {
+            Pixels::Loaded(img) => variant.orientation.apply(img).into_owned(),
+            Pixels::Cached { .. } => unreachable!(),
+        };
+        assert_eq!(tile, target.view(x, y, 4, 4).to_image(), "{},{}", x, y);
+    }
+}
+
+#[test]
+fn white_balanced_tiles_keep_their_pixels() {
+    let warm = image::RgbImage::from_pixel(4, 4, image::Rgb([200, 100, 50]));
+    let mut set = TileSet::default();
+    let tile = CutTile {
+        img: warm.clone(),
+        region: (0, 0, 4, 4),
+        gains: tiles::gray_world_gains(&warm),
+        alpha: None,
+        phashes: None,
+    };
+    set.add("warm.png", 1.0, vec![tile]);
+    let tile = &set.tiles[0];
+    assert_eq!(tile_key::<3>(tile, KeySpec::default()), [117, 117, 100]);
+    assert!(matches!(&tile.pixels, Pixels::Loaded(img) if *img == warm));
+}
+
+#[test]
+fn oklab_keeps_strong_blues_vivid() {
+    let colors = vec![[40, 40, 255], [70, 90, 170]];
+    let target = [40, 60, 200];
+    let pick = |metric: Metric| {
+        let bldb = BlockDb::new(colors.clone(), move |&c| metric.key(c));
+        *find_closest(&bldb, metric.key(target), metric, 0.0)
+    };
+    // rgb goes for the duller slate blue, Oklab for the saturated one
+    assert_eq!(pick(Metric::Rgb), [70, 90, 170]);
+    assert_eq!(pick(Metric::Oklab), [40, 40, 255]);
+}
+
+#[test]
+fn phash_finds_turned_tiles_by_their_shape() {
+    let corner = image::RgbImage::from_fn(12, 12, |x, y| {
+        image::Rgb([if x * x + 3 * y < 60 { 30 } else { 200 }; 3])
+    });
+    // the same brightness as the corner but no shape to speak of
+    let stripes = image::RgbImage::from_fn(12, 12, |_, y| {
+        image::Rgb([if y % 2 == 0 { 100 } else { 172 }; 3])
+    });
+    let tiles = whole_tiles([("corner", corner.clone()), ("stripes", stripes)], true).tiles;
+    let orientations = Orientation::variants(&[Augment::Flips, Augment::Rotations]);
+    let hashed: Vec<_> = (0..tiles.len())
+        .flat_map(|tile| {
+            let key = tile_key::<3>(&tiles[tile], KeySpec::default());
+            let phashes = tiles[tile].phashes.unwrap();
+            orientations.iter().map(move |&orientation| {
+                let variant = Variant { tile, orientation };
+                (phashes[orientation.index()], key, variant)
+            })
+        })
+        .collect();
+    for &orientation in &orientations {
+        let block = orientation.apply(&corner).into_owned();
+        let key = compute_key::<3, _>(&block, &KeySpec::default());
+        let pick = closest_hash(&hashed, Spectrum::of(&block).hash(), key);
+        assert_eq!(pick.tile, 0, "{:?}", orientation);
+        assert_eq!(pick.orientation, orientation);
+    }
+}
+
+#[test]
+fn temperature_grades_placed_tiles_once() {
+    let tile = image::RgbImage::from_pixel(2, 2, image::Rgb([100, 150, 200]));
+    let neutral = PlaceOptions::default().finish(Cow::Borrowed(&tile));
+    assert!(matches!(neutral, Cow::Borrowed(_)));
+    let warm = PlaceOptions {
+        temperature: Gains::temperature(2000.0),
+        desaturate: true,
+        ..PlaceOptions::default()
+    };
+    // the grade goes on after desaturating, so gray tiles still get warm
+    let placed = warm.finish(Cow::Borrowed(&tile));
+    assert_eq!(placed.get_pixel(1, 1), &image::Rgb([172, 143, 114]));
+}
+
+#[test]
+fn dithering_mixes_tiles_for_in_between_colors() {
+    let tiles = [
+        image::RgbImage::from_pixel(4, 4, image::Rgb([0; 3])),
+        image::RgbImage::from_pixel(4, 4, image::Rgb([255; 3])),
+    ];
+    let keys = KeySpec::default();
+    let bldb = BlockDb::new(tiles.iter().collect(), move |img| {
+        keys.tile_key::<3>(img, None)
+    });
+    let choose =
+        |key, _: &image::SubImage<&image::RgbImage>| *find_closest(&bldb, key, keys.metric, 0.0);
+    let target = image::RgbImage::from_pixel(32, 30, image::Rgb([100; 3]));
+    let white = |picks: &[(u32, u32, &image::RgbImage)]| {
+        picks
+            .iter()
+            .filter(|(_, _, tile)| tile[(0, 0)][0] == 255)
+            .count()
+    };
+
+    let flat = match_blocks(&target, (4, 4), keys, choose);
+    assert_eq!(white(&flat), 0);
+    let dithered = dither_blocks(&target, (4, 4), keys, choose, |tile| {
+        keys.tile_key(tile, None)
+    });
+    // 100 is about 39% of the way to white
+    assert_eq!(dithered.len(), flat.len());
+    assert!(
+        (23..=27).contains(&white(&dithered)),
+        "{}",
+        white(&dithered)
+    );
+    let coords = |picks: &[(u32, u32, &image::RgbImage)]| {
+        picks.iter().map(|&(x, y, _)| (x, y)).collect::<Vec<_>>()
+    };
+    assert_eq!(coords(&dithered), coords(&flat));
+}
+
+#[test]
+fn counts_are_grouped_by_thousands() {
+    assert_eq!(group_thousands(0), "0");
+    assert_eq!(group_thousands(999), "999");
+    assert_eq!(group_thousands(1000), "1,000");
+    assert_eq!(group_thousands(182_344), "182,344");
+    assert_eq!(group_thousands(12_345_678), "12,345,678");
+}
 
-    return out;
+#[test]
+fn sized_paths_keep_their_directory_and_extension() {
+    let sized = |path: &str| sized_path(Path::new(path), 32);
+    assert_eq!(sized("tiles.db"), Path::new("tiles-32.db"));
+    assert_eq!(sized("cache/tiles"), Path::new("cache/tiles-32"));
+    assert_eq!(sized("a.b/tiles.tar.db"), Path::new("a.b/tiles.tar-32.db"));
 }
@@ -0,0 +1,150 @@
+use crate::color;
+use crate::tiles::Orientation;
+use image::{imageops, GenericImageView, GrayImage, Luma, Rgb, RgbImage};
+
+/// Side length of the grayscale thumbnail the DCT is taken of.
+const THUMBNAIL_SIZE: u32 = 32;
+
+/// Frequencies per direction that go into a hash, 8×8 of them give 64 bits.
+const FREQUENCIES: usize = 8;
+
+/// The lowest DCT coefficients of the brightness of an image, by vertical
+/// then horizontal frequency.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Spectrum([f32; FREQUENCIES * FREQUENCIES]);
+
+impl Spectrum {
+    pub fn of<I: GenericImageView<Pixel = Rgb<u8>>>(img: &I) -> Self {
+        let (width, height) = img.dimensions();
+        let gray = GrayImage::from_fn(width, height, |x, y| {
+            Luma([color::luma(img.get_pixel(x, y).0).round() as u8])
+        });
+        let size = THUMBNAIL_SIZE as usize;
+        let thumbnail = imageops::resize(
+            &gray,
+            THUMBNAIL_SIZE,
+            THUMBNAIL_SIZE,
+            imageops::FilterType::Triangle,
+        );
+        let basis = |frequency: usize, at: usize| {
+            let angle = std::f32::consts::PI * ((2 * at + 1) * frequency) as f32;
+            (angle / (2 * size) as f32).cos()
+        };
+        // one direction at a time, the rows first
+        let mut rows = vec![[0.0; FREQUENCIES]; size];
+        for (y, row) in rows.iter_mut().enumerate() {
+            for (u, coefficient) in row.iter_mut().enumerate() {
+                *coefficient = (0..size)
+                    .map(|x| f32::from(thumbnail.get_pixel(x as u32, y as u32)[0]) * basis(u, x))
+                    .sum();
+            }
+        }
+        let mut coefficients = [0.0; FREQUENCIES * FREQUENCIES];
+        for v in 0..FREQUENCIES {
+            for u in 0..FREQUENCIES {
+                coefficients[v * FREQUENCIES + u] =
+                    (0..size).map(|y| rows[y][u] * basis(v, y)).sum();
+            }
+        }
+        Spectrum(coefficients)
+    }
+
+    /// The spectrum of the image turned by `orientation`: mirroring flips
+    /// the sign of the odd horizontal frequencies, a quarter turn clockwise
+    /// swaps the directions before doing the same.
+    pub fn oriented(self, orientation: Orientation) -> Self {
+        let mut spectrum = if orientation.mirrored {
+            self.mirrored()
+        } else {
+            self
+        };
+        for _ in 0..orientation.quarter_turns % 4 {
+            spectrum = spectrum.transposed().mirrored();
+        }
+        spectrum
+    }
+
+    fn mirrored(self) -> Self {
+        let mut coefficients = self.0;
+        for (i, c) in coefficients.iter_mut().enumerate() {
+            if (i % FREQUENCIES) % 2 == 1 {
+                *c = -*c;
+            }
+        }
+        Spectrum(coefficients)
+    }
+
+    fn transposed(self) -> Self {
+        let mut coefficients = self.0;
+        for v in 0..FREQUENCIES {
+            for u in 0..FREQUENCIES {
+                coefficients[v * FREQUENCIES + u] = self.0[u * FREQUENCIES + v];
+            }
+        }
+        Spectrum(coefficients)
+    }
+
+    /// One bit per coefficient, set if it is above the median of all but
+    /// the average brightness, which is always left out.
+    pub fn hash(&self) -> u64 {
+        let mut ac: Vec<f32> = self.0[1..].to_vec();
+        let middle = ac.len() / 2;
+        let median = *ac.select_nth_unstable_by(middle, f32::total_cmp).1;
+        self.0
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|&(_, &c)| c > median)
+            .fold(0, |hash, (i, _)| hash | 1 << i)
+    }
+}
+
+/// The hashes of `tile` turned every way there is, by `Orientation::index`.
+pub fn oriented_hashes(tile: &RgbImage) -> [u64; 8] {
+    let spectrum = Spectrum::of(tile);
+    let mut hashes = [0; 8];
+    for mirrored in [false, true] {
+        for quarter_turns in 0..4 {
+            let orientation = Orientation {
+                mirrored,
+                quarter_turns,
+            };
+            hashes[orientation.index()] = spectrum.oriented(orientation).hash();
+        }
+    }
+    hashes
+}
+
+/// Number of bits two hashes differ in.
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+#[test]
+fn turned_tiles_hash_like_turned_blocks() {
+    use crate::tiles::Augment;
+
+    let tile = RgbImage::from_fn(12, 12, |x, y| {
+        let v = if x * x + 3 * y < 60 { 30 } else { 200 };
+        Rgb([v, (x * 20) as u8, (y * 10) as u8])
+    });
+    let hashes = oriented_hashes(&tile);
+    for orientation in Orientation::variants(&[Augment::Flips, Augment::Rotations]) {
+        let block = Spectrum::of(orientation.apply(&tile).as_ref()).hash();
+        let own = hashes[orientation.index()];
+        assert!(distance(own, block) <= 2, "{:?}", orientation);
+        let others = hashes.iter().filter(|&&h| distance(h, block) <= 2).count();
+        assert_eq!(others, 1, "{:?}", orientation);
+    }
+}
+
+#[test]
+fn views_hash_like_images() {
+    let img = RgbImage::from_fn(20, 20, |x, y| Rgb([(x * y) as u8, (x * 12) as u8, 40]));
+    let view = img.view(4, 2, 10, 10);
+    assert_eq!(
+        Spectrum::of(&view).hash(),
+        Spectrum::of(&view.to_image()).hash()
+    );
+    assert_eq!(distance(0b1011, 0b0110), 3);
+}
@@ -0,0 +1,69 @@
+use image::{imageops, RgbImage};
+
+/// Side length of the thumbnail used as an image's signature.
+const SIGNATURE_SIZE: u32 = 8;
+
+/// Keeps only images that look different enough from every image admitted
+/// before, which collapses burst shots into a single source.
+pub struct NearDuplicates {
+    threshold: f64,
+    kept: Vec<Vec<u8>>,
+    pub collapsed: usize,
+}
+
+impl NearDuplicates {
+    /// `threshold` is the mean per-channel difference (0-255) of the 8x8
+    /// thumbnails below which two images count as the same.
+    pub fn new(threshold: f64) -> Self {
+        NearDuplicates {
+            threshold,
+            kept: Vec::new(),
+            collapsed: 0,
+        }
+    }
+
+    pub fn admit(&mut self, img: &RgbImage) -> bool {
+        let sig = signature(img);
+        if self.kept.iter().any(|k| distance(k, &sig) < self.threshold) {
+            self.collapsed += 1;
+            return false;
+        }
+        self.kept.push(sig);
+        true
+    }
+}
+
+fn signature(img: &RgbImage) -> Vec<u8> {
+    imageops::resize(
+        img,
+        SIGNATURE_SIZE,
+        SIGNATURE_SIZE,
+        imageops::FilterType::Triangle,
+    )
+    .into_raw()
+}
+
+fn distance(a: &[u8], b: &[u8]) -> f64 {
+    let sum: u64 = a
+        .iter()
+        .zip(b)
+        .map(|(&x, &y)| (i64::from(x) - i64::from(y)).unsigned_abs())
+        .sum();
+    sum as f64 / a.len() as f64
+}
+
+#[test]
+fn similar_images_are_collapsed() {
+    let gradient = RgbImage::from_fn(64, 48, |x, y| image::Rgb([x as u8 * 4, y as u8 * 5, 90]));
+    let mut brighter = gradient.clone();
+    for p in brighter.pixels_mut() {
+        p[2] += 2;
+    }
+    let other = RgbImage::from_pixel(64, 48, image::Rgb([200, 10, 10]));
+
+    let mut filter = NearDuplicates::new(4.0);
+    assert!(filter.admit(&gradient));
+    assert!(!filter.admit(&brighter));
+    assert!(filter.admit(&other));
+    assert_eq!(filter.collapsed, 1);
+}
@@ -0,0 +1,1450 @@
+use crate::color::{self, Gains, HexColor};
+use image::imageops::{self, FilterType};
+use image::{GenericImageView, GrayImage, ImageBuffer, Pixel, Rgb, RgbImage};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::convert::{TryFrom, TryInto};
+use std::str::FromStr;
+
+/// How tiles are taken from a source image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileMode {
+    /// cut the image into a grid of tiles
+    Grid,
+    /// shrink the whole image into a single tile
+    WholeImage,
+    /// crop the image to the shape of a tile and shrink that into one
+    Cover,
+    /// cut tiles at random places in the image
+    RandomCrops,
+}
+
+impl TileMode {
+    /// Whether the mode cuts tile-sized cells out of an image, instead of
+    /// shrinking (part of) it into a single tile.
+    pub fn cuts_cells(self) -> bool {
+        matches!(self, TileMode::Grid | TileMode::RandomCrops)
+    }
+}
+
+impl FromStr for TileMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "grid" => Ok(TileMode::Grid),
+            "whole-image" => Ok(TileMode::WholeImage),
+            "cover" => Ok(TileMode::Cover),
+            "random-crops" => Ok(TileMode::RandomCrops),
+            _ => Err(format!(
+                "unknown tile mode \"{}\", expected grid, whole-image, cover or random-crops",
+                value
+            )),
+        }
+    }
+}
+
+/// A part of an image as `(x, y, width, height)`.
+pub type Region = (u32, u32, u32, u32);
+
+/// An owned image with pixels of type `P`.
+type Buffer<P> = ImageBuffer<P, Vec<<P as Pixel>::Subpixel>>;
+
+/// A comma separated list of square tile sizes like `64,32,16`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sizes(pub Vec<u32>);
+
+impl FromStr for Sizes {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut sizes = Vec::new();
+        for part in value.split(',') {
+            let size = part
+                .trim()
+                .parse::<u32>()
+                .ok()
+                .filter(|&s| s > 0)
+                .ok_or_else(|| format!("invalid tile size \"{}\"", part.trim()))?;
+            if !sizes.contains(&size) {
+                sizes.push(size);
+            }
+        }
+        Ok(Sizes(sizes))
+    }
+}
+
+/// Which part of an image taller than the tile shape `TileMode::Cover`
+/// keeps. Wider images are always cropped around their center.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gravity {
+    Center,
+    Top,
+    Bottom,
+}
+
+impl FromStr for Gravity {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "center" => Ok(Gravity::Center),
+            "top" => Ok(Gravity::Top),
+            "bottom" => Ok(Gravity::Bottom),
+            _ => Err(format!(
+                "unknown gravity \"{}\", expected center, top or bottom",
+                value
+            )),
+        }
+    }
+}
+
+/// Which color of a tile or target block it is matched by.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum KeyMode {
+    /// the average of all pixels
+    #[default]
+    Mean,
+    /// the center of the largest cluster of similar pixels
+    Dominant,
+    /// the median of each channel, which a few stray pixels don't move
+    Median,
+    /// the average colors of the four quadrants, top left to bottom right
+    Quadrants,
+    /// the average colors of a 3×3 grid of cells, row by row
+    Grid3,
+    /// the average color followed by the standard deviation of each channel
+    MeanStd,
+    /// the average with pixels weighted by a Gaussian around the center
+    CenterWeighted,
+}
+
+impl KeyMode {
+    /// How many cells across and down a key describes.
+    pub fn cells_per_side(self) -> u32 {
+        match self {
+            KeyMode::Mean
+            | KeyMode::Dominant
+            | KeyMode::Median
+            | KeyMode::MeanStd
+            | KeyMode::CenterWeighted => 1,
+            KeyMode::Quadrants => 2,
+            KeyMode::Grid3 => 3,
+        }
+    }
+
+    /// Number of components of a key, three per cell and three more for the
+    /// deviations of `MeanStd`.
+    pub fn len(self) -> usize {
+        let side = self.cells_per_side() as usize;
+        let deviations = if self == KeyMode::MeanStd { 3 } else { 0 };
+        3 * side * side + deviations
+    }
+}
+
+impl FromStr for KeyMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "mean" => Ok(KeyMode::Mean),
+            "dominant" => Ok(KeyMode::Dominant),
+            "median" => Ok(KeyMode::Median),
+            "quadrants" => Ok(KeyMode::Quadrants),
+            "grid3" => Ok(KeyMode::Grid3),
+            "mean-std" => Ok(KeyMode::MeanStd),
+            "center-weighted" => Ok(KeyMode::CenterWeighted),
+            _ => Err(format!(
+                "unknown key \"{}\", expected mean, dominant, median, quadrants, grid3, mean-std \
+                 or center-weighted",
+                value
+            )),
+        }
+    }
+}
+
+/// How the closest tiles by key are compared before one gets placed.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Rerank {
+    /// the closest key wins
+    #[default]
+    None,
+    /// the tile whose `Histogram` is closest to the block's wins
+    Histogram,
+    /// the tile whose `Edges` are closest to the block's wins
+    Edges,
+}
+
+impl FromStr for Rerank {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(Rerank::None),
+            "histogram" => Ok(Rerank::Histogram),
+            "edges" => Ok(Rerank::Edges),
+            _ => Err(format!(
+                "unknown rerank \"{}\", expected none, histogram or edges",
+                value
+            )),
+        }
+    }
+}
+
+/// Extra variants of every tile to put into the database.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Augment {
+    /// horizontal and vertical mirror images
+    Flips,
+    /// the tile turned by 90°, 180° and 270°
+    Rotations,
+}
+
+impl FromStr for Augment {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "flips" => Ok(Augment::Flips),
+            "rotations" => Ok(Augment::Rotations),
+            _ => Err(format!(
+                "unknown augmentation \"{}\", expected flips or rotations",
+                value
+            )),
+        }
+    }
+}
+
+/// How a tile is turned before it is placed: mirrored left to right first if
+/// `mirrored`, then rotated clockwise by `quarter_turns`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Orientation {
+    pub mirrored: bool,
+    pub quarter_turns: u8,
+}
+
+impl Orientation {
+    pub const ORIGINAL: Orientation = Orientation {
+        mirrored: false,
+        quarter_turns: 0,
+    };
+
+    /// A number for each of the eight orientations, 0-7.
+    pub fn index(self) -> usize {
+        usize::from(self.mirrored) * 4 + usize::from(self.quarter_turns % 4)
+    }
+
+    /// The orientation with `Orientation::index`, `None` past 7.
+    pub fn from_index(index: usize) -> Option<Orientation> {
+        (index < 8).then_some(Orientation {
+            mirrored: index >= 4,
+            quarter_turns: (index % 4) as u8,
+        })
+    }
+
+    /// Every orientation tiles go into the database with, `ORIGINAL` first.
+    /// Flips and rotations together give all eight.
+    pub fn variants(augments: &[Augment]) -> Vec<Orientation> {
+        let rotations = augments.contains(&Augment::Rotations);
+        let flips = augments.contains(&Augment::Flips);
+        let turns: &[u8] = if rotations { &[0, 1, 2, 3] } else { &[0] };
+        let mut variants: Vec<Orientation> = turns
+            .iter()
+            .map(|&quarter_turns| Orientation {
+                mirrored: false,
+                quarter_turns,
+            })
+            .collect();
+        if flips {
+            // a vertical flip is a horizontal one turned upside down
+            let turns: &[u8] = if rotations { &[0, 1, 2, 3] } else { &[0, 2] };
+            variants.extend(turns.iter().map(|&quarter_turns| Orientation {
+                mirrored: true,
+                quarter_turns,
+            }));
+        }
+        variants
+    }
+
+    /// Where the cells of a `side`×`side` grid end up when the tile is
+    /// turned: cell `i` of the turned tile, counted row by row, is cell
+    /// `permute_cells(side)[i]` of the original.
+    pub fn permute_cells(self, side: u32) -> Vec<usize> {
+        let cells = RgbImage::from_fn(side, side, |x, y| Rgb([(y * side + x) as u8, 0, 0]));
+        self.apply(&cells)
+            .pixels()
+            .map(|p| usize::from(p[0]))
+            .collect()
+    }
+
+    pub fn apply(self, tile: &RgbImage) -> Cow<'_, RgbImage> {
+        match (self.mirrored, self.quarter_turns % 4) {
+            (false, 0) => Cow::Borrowed(tile),
+            (true, 0) => Cow::Owned(imageops::flip_horizontal(tile)),
+            (true, 2) => Cow::Owned(imageops::flip_vertical(tile)),
+            (mirrored, turns) => {
+                let tile = if mirrored {
+                    imageops::flip_horizontal(tile)
+                } else {
+                    tile.clone()
+                };
+                Cow::Owned(match turns {
+                    1 => imageops::rotate90(&tile),
+                    2 => imageops::rotate180(&tile),
+                    _ => imageops::rotate270(&tile),
+                })
+            }
+        }
+    }
+}
+
+/// A resampling filter, named like on the command line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Filter(pub FilterType);
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let filter = match value {
+            "nearest" => FilterType::Nearest,
+            "triangle" => FilterType::Triangle,
+            "catmullrom" | "catmull-rom" => FilterType::CatmullRom,
+            "gaussian" => FilterType::Gaussian,
+            "lanczos3" => FilterType::Lanczos3,
+            _ => {
+                return Err(format!(
+                    "unknown filter \"{}\", expected nearest, triangle, catmullrom, gaussian \
+                     or lanczos3",
+                    value
+                ))
+            }
+        };
+        Ok(Filter(filter))
+    }
+}
+
+/// Shrinks `img` with `filter` so neither side exceeds `max` pixels, keeping
+/// the aspect ratio. A `max` of 0 leaves every image alone.
+pub fn limit_dimension<P: Pixel + 'static>(img: Buffer<P>, max: u32, filter: Filter) -> Buffer<P> {
+    let (width, height) = img.dimensions();
+    if max == 0 || (width <= max && height <= max) {
+        return img;
+    }
+    let scale = f64::from(max) / f64::from(width.max(height));
+    let new_width = ((f64::from(width) * scale).round() as u32).max(1);
+    let new_height = ((f64::from(height) * scale).round() as u32).max(1);
+    imageops::resize(&img, new_width, new_height, filter.0)
+}
+
+/// The largest region of a width×height image with the aspect ratio of
+/// `tile`, placed by `gravity`, as `(x, y, width, height)`.
+pub fn aspect_crop(
+    width: u32,
+    height: u32,
+    (tile_width, tile_height): (u32, u32),
+    gravity: Gravity,
+) -> Region {
+    let (w, h) =
+        if u64::from(width) * u64::from(tile_height) > u64::from(height) * u64::from(tile_width) {
+            let w = u64::from(height) * u64::from(tile_width) / u64::from(tile_height);
+            (w as u32, height)
+        } else {
+            let h = u64::from(width) * u64::from(tile_height) / u64::from(tile_width);
+            (width, h as u32)
+        };
+    let x = (width - w) / 2;
+    let spare = height - h;
+    let y = match gravity {
+        Gravity::Center => spare / 2,
+        Gravity::Top => 0,
+        Gravity::Bottom => spare,
+    };
+    (x, y, w, h)
+}
+
+/// The part of a width×height image that `mode` turns into a single tile,
+/// as `(x, y, width, height)`.
+pub fn single_tile_region(
+    mode: TileMode,
+    width: u32,
+    height: u32,
+    tile: (u32, u32),
+    gravity: Gravity,
+) -> Region {
+    match mode {
+        TileMode::Cover => aspect_crop(width, height, tile, gravity),
+        _ => (0, 0, width, height),
+    }
+}
+
+/// Shrinks (or grows) `region` of `img` into one tile.
+pub fn shrink_to_tile<P: Pixel + 'static>(
+    img: &Buffer<P>,
+    (x, y, width, height): Region,
+    (tile_width, tile_height): (u32, u32),
+    filter: Filter,
+) -> Buffer<P> {
+    imageops::resize(
+        &img.view(x, y, width, height),
+        tile_width,
+        tile_height,
+        filter.0,
+    )
+}
+
+/// Maps `region` of an image of size `from` onto the same part of the image
+/// scaled to `to`.
+pub fn scale_region((x, y, width, height): Region, from: (u32, u32), to: (u32, u32)) -> Region {
+    let scale =
+        |v: u32, from: u32, to: u32| (u64::from(v) * u64::from(to) / u64::from(from)) as u32;
+    (
+        scale(x, from.0, to.0),
+        scale(y, from.1, to.1),
+        scale(width, from.0, to.0),
+        scale(height, from.1, to.1),
+    )
+}
+
+/// How `--pad-small-inputs` fills the space around inputs smaller than a
+/// tile.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Padding {
+    Color(Rgb<u8>),
+    /// reflect the image at its edges
+    Mirror,
+}
+
+impl FromStr for Padding {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == "mirror" {
+            return Ok(Padding::Mirror);
+        }
+        value
+            .parse::<HexColor>()
+            .map(|c| Padding::Color(c.0))
+            .map_err(|_| {
+                format!(
+                    "unknown padding \"{}\", expected mirror or a #rrggbb color",
+                    value
+                )
+            })
+    }
+}
+
+/// Grows `img` to at least the size of `tile`, keeping it centered, and
+/// fills the new space with `fill` or else mirrors the image into it. Also
+/// returns where the original image is in the padded one.
+pub fn pad<P: Pixel + 'static>(
+    img: &Buffer<P>,
+    tile: (u32, u32),
+    fill: Option<P>,
+) -> (Buffer<P>, (u32, u32)) {
+    let (width, height) = img.dimensions();
+    let (new_width, new_height) = (width.max(tile.0), height.max(tile.1));
+    let (left, top) = ((new_width - width) / 2, (new_height - height) / 2);
+    let padded = ImageBuffer::from_fn(new_width, new_height, |x, y| {
+        let (sx, sy) = (
+            i64::from(x) - i64::from(left),
+            i64::from(y) - i64::from(top),
+        );
+        let inside = (0..i64::from(width)).contains(&sx) && (0..i64::from(height)).contains(&sy);
+        match fill {
+            Some(fill) if !inside => fill,
+            _ => *img.get_pixel(reflect(sx, width), reflect(sy, height)),
+        }
+    });
+    (padded, (left, top))
+}
+
+/// Folds `v` back into `0..len` the way a mirror at both ends would.
+fn reflect(v: i64, len: u32) -> u32 {
+    let len = i64::from(len);
+    let m = v.rem_euclid(2 * len);
+    (if m < len { m } else { 2 * len - 1 - m }) as u32
+}
+
+/// The part of `region` of a padded image that shows the original image,
+/// placed at `offset` with size `original`, in the original's pixels.
+pub fn unpad_region(
+    (x, y, width, height): Region,
+    (left, top): (u32, u32),
+    original: (u32, u32),
+) -> Region {
+    let x0 = x.saturating_sub(left).min(original.0);
+    let y0 = y.saturating_sub(top).min(original.1);
+    let x1 = (x + width).saturating_sub(left).min(original.0);
+    let y1 = (y + height).saturating_sub(top).min(original.1);
+    (x0, y0, x1 - x0, y1 - y0)
+}
+
+/// Whether `img` is large enough to yield tiles of size `tile` at all.
+pub fn fits(img: &RgbImage, (tile_width, tile_height): (u32, u32)) -> bool {
+    img.width() >= tile_width && img.height() >= tile_height
+}
+
+/// Top left corners of up to `count` distinct cells of size `tile` at random
+/// places inside a width×height image, the same ones for the same `seed`.
+pub fn random_cells(
+    width: u32,
+    height: u32,
+    tile: (u32, u32),
+    count: usize,
+    seed: u64,
+) -> Vec<(u32, u32)> {
+    if width < tile.0 || height < tile.1 {
+        return Vec::new();
+    }
+    let (across, down) = (
+        (width - tile.0 + 1) as usize,
+        (height - tile.1 + 1) as usize,
+    );
+    let places = across * down;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut cells: Vec<(u32, u32)> = rand::seq::index::sample(&mut rng, places, count.min(places))
+        .into_iter()
+        .map(|i| ((i % across) as u32, (i / across) as u32))
+        .collect();
+    cells.sort_unstable();
+    cells
+}
+
+/// Top left corners of the cells of size `tile` in a width×height image,
+/// `stride` pixels apart. Cells overlap if `stride` is less than the tile.
+pub fn grid(width: u32, height: u32, tile: (u32, u32), stride: (u32, u32)) -> Vec<(u32, u32)> {
+    let mut cells = Vec::new();
+    if width < tile.0 || height < tile.1 {
+        return cells;
+    }
+    for x in (0..=width - tile.0).step_by(stride.0.try_into().unwrap()) {
+        for y in (0..=height - tile.1).step_by(stride.1.try_into().unwrap()) {
+            cells.push((x, y));
+        }
+    }
+    cells
+}
+
+/// Cuts `img` into a grid of owned tiles.
+#[cfg(test)]
+pub fn extract_tiles(img: &RgbImage, tile: (u32, u32)) -> Vec<RgbImage> {
+    let (width, height) = img.dimensions();
+    cut_cells(img, &grid(width, height, tile, tile), tile)
+}
+
+/// Copies the tiles of size `tile` at `cells` out of `img`.
+pub fn cut_cells<P: Pixel + Send + Sync + 'static>(
+    img: &Buffer<P>,
+    cells: &[(u32, u32)],
+    tile: (u32, u32),
+) -> Vec<Buffer<P>>
+where
+    P::Subpixel: Send + Sync,
+{
+    cells
+        .par_iter()
+        .map(|&(x, y)| img.view(x, y, tile.0, tile.1).to_image())
+        .collect()
+}
+
+/// Unsharp masking: adds `amount` times the detail a small blur takes away.
+pub fn sharpen(tile: &RgbImage, amount: f32) -> RgbImage {
+    let blurred = imageops::blur(tile, 1.0);
+    let mut out = tile.clone();
+    for (p, b) in out.pixels_mut().zip(blurred.pixels()) {
+        for c in 0..3 {
+            let v = f32::from(p[c]);
+            p[c] = (v + amount * (v - f32::from(b[c])))
+                .round()
+                .clamp(0.0, 255.0) as u8;
+        }
+    }
+    out
+}
+
+/// Scales the HSV saturation of every pixel by `factor`, keeping hue and
+/// value. Saturation stops at 1, so no channel is pushed below 0.
+pub fn saturate(tile: &RgbImage, factor: f32) -> RgbImage {
+    let mut out = tile.clone();
+    for p in out.pixels_mut() {
+        let max = f32::from(p[0].max(p[1]).max(p[2]));
+        let min = f32::from(p[0].min(p[1]).min(p[2]));
+        if max == min {
+            continue;
+        }
+        let saturation = (max - min) / max;
+        let scale = (saturation * factor).clamp(0.0, 1.0) / saturation;
+        for c in 0..3 {
+            p[c] = (max - (max - f32::from(p[c])) * scale).round() as u8;
+        }
+    }
+    out
+}
+
+/// The gray world white balance of `img`, from the averages of its channels.
+pub fn gray_world_gains(img: &RgbImage) -> Gains {
+    let count = f64::from(img.width()) * f64::from(img.height());
+    if count == 0.0 {
+        return Gains::NONE;
+    }
+    let mut sums = [0u64; 3];
+    for p in img.pixels() {
+        for (sum, &v) in sums.iter_mut().zip(p.0.iter()) {
+            *sum += u64::from(v);
+        }
+    }
+    Gains::gray_world(sums.map(|sum| sum as f64 / count))
+}
+
+/// `img` with every pixel scaled by `gains`, clamped to the valid range.
+pub fn white_balance(img: &RgbImage, gains: Gains) -> Cow<'_, RgbImage> {
+    if gains == Gains::NONE {
+        return Cow::Borrowed(img);
+    }
+    let mut out = img.clone();
+    for p in out.pixels_mut() {
+        p.0 = gains.apply(p.0);
+    }
+    Cow::Owned(out)
+}
+
+/// `tile` in shades of gray of the same Rec. 709 luma.
+pub fn desaturate(tile: &RgbImage) -> RgbImage {
+    let mut out = tile.clone();
+    for p in out.pixels_mut() {
+        let gray = color::luma(p.0).round() as u8;
+        *p = Rgb([gray; 3]);
+    }
+    out
+}
+
+/// Share of the darkest and of the brightest pixels `Stretch::of` lets clip.
+const STRETCH_CLIP: f64 = 0.01;
+
+/// Smallest factor `Stretch::of` stretches by, images it would stretch less
+/// already use about the full range.
+const MIN_STRETCH: f64 = 1.05;
+
+/// A linear contrast stretch that maps the luma range `low..=high` onto
+/// the full 0-255.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stretch {
+    low: u8,
+    high: u8,
+}
+
+impl Stretch {
+    pub const NONE: Stretch = Stretch { low: 0, high: 255 };
+
+    /// The stretch between the 1st and 99th percentile of the luma of
+    /// `img`. `NONE` for images that already span about the full range and
+    /// for those of a single brightness, which have no range to stretch.
+    pub fn of(img: &RgbImage) -> Self {
+        let mut counts = [0u64; 256];
+        for p in img.pixels() {
+            counts[color::luma(p.0).round() as usize] += 1;
+        }
+        let clip = (img.pixels().len() as f64 * STRETCH_CLIP) as u64;
+        // the first level, going from one end, past the clipped pixels
+        let percentile = |levels: Vec<usize>| {
+            let mut seen = 0;
+            levels
+                .into_iter()
+                .find(|&level| {
+                    seen += counts[level];
+                    seen > clip
+                })
+                .unwrap_or(0) as u8
+        };
+        let low = percentile((0..256).collect());
+        let high = percentile((0..256).rev().collect());
+        if low >= high || 255.0 / f64::from(high - low) < MIN_STRETCH {
+            return Stretch::NONE;
+        }
+        Stretch { low, high }
+    }
+
+    /// `img` with its contrast stretched.
+    pub fn apply(self, img: &RgbImage) -> Cow<'_, RgbImage> {
+        let (low, range) = (f32::from(self.low), f32::from(self.high - self.low));
+        self.map(img, |v| (v - low) * 255.0 / range)
+    }
+
+    /// `img` with its contrast squeezed back into `low..=high`, the way a
+    /// stretched image looked before.
+    pub fn undo(self, img: &RgbImage) -> Cow<'_, RgbImage> {
+        let (low, range) = (f32::from(self.low), f32::from(self.high - self.low));
+        self.map(img, |v| low + v * range / 255.0)
+    }
+
+    fn map(self, img: &RgbImage, f: impl Fn(f32) -> f32) -> Cow<'_, RgbImage> {
+        if self == Stretch::NONE {
+            return Cow::Borrowed(img);
+        }
+        let mut out = img.clone();
+        for c in out.iter_mut() {
+            *c = f(f32::from(*c)).round().clamp(0.0, 255.0) as u8;
+        }
+        Cow::Owned(out)
+    }
+}
+
+impl Default for Stretch {
+    fn default() -> Self {
+        Stretch::NONE
+    }
+}
+
+/// Bins per channel of a `Histogram`.
+pub const HISTOGRAM_BINS: usize = 4;
+
+/// Share of the pixels of a tile in each of 4×4×4 RGB bins, in 1/65535ths,
+/// for matching on more than the average color.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<u16>", into = "Vec<u16>")]
+pub struct Histogram(pub [u16; HISTOGRAM_BINS * HISTOGRAM_BINS * HISTOGRAM_BINS]);
+
+impl Histogram {
+    pub fn of<I: GenericImageView<Pixel = Rgb<u8>>>(tile: &I) -> Self {
+        let bin = |c: u8| usize::from(c) * HISTOGRAM_BINS / 256;
+        let mut counts = [0u64; HISTOGRAM_BINS * HISTOGRAM_BINS * HISTOGRAM_BINS];
+        for (_, _, p) in tile.pixels() {
+            counts[(bin(p[0]) * HISTOGRAM_BINS + bin(p[1])) * HISTOGRAM_BINS + bin(p[2])] += 1;
+        }
+        let total = (u64::from(tile.width()) * u64::from(tile.height())).max(1);
+        let mut shares = [0; HISTOGRAM_BINS * HISTOGRAM_BINS * HISTOGRAM_BINS];
+        for (share, count) in shares.iter_mut().zip(counts.iter()) {
+            *share = (count * 65535 / total) as u16;
+        }
+        Histogram(shares)
+    }
+
+    /// Chi-square distance to `other`, 0 for the same shares and up to 1
+    /// for shares in disjoint bins.
+    pub fn chi_square(&self, other: &Histogram) -> f64 {
+        let sum: f64 = self
+            .0
+            .iter()
+            .zip(other.0.iter())
+            .filter(|(&a, &b)| a > 0 || b > 0)
+            .map(|(&a, &b)| {
+                let (a, b) = (f64::from(a), f64::from(b));
+                (a - b).powi(2) / (a + b)
+            })
+            .sum();
+        sum / (2.0 * 65535.0)
+    }
+}
+
+impl TryFrom<Vec<u16>> for Histogram {
+    type Error = String;
+
+    fn try_from(bins: Vec<u16>) -> Result<Self, Self::Error> {
+        let len = bins.len();
+        bins.try_into()
+            .map(Histogram)
+            .map_err(|_| format!("histogram with {} bins", len))
+    }
+}
+
+impl From<Histogram> for Vec<u16> {
+    fn from(histogram: Histogram) -> Self {
+        histogram.0.to_vec()
+    }
+}
+
+/// How strong and which way the edges of a tile run: the square roots of
+/// the mean squared horizontal and vertical Sobel gradients of its
+/// brightness, and of their mean product, which is negative for edges
+/// running like "\" and positive for "/".
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Edges(pub [f32; 3]);
+
+impl Edges {
+    pub fn of<I: GenericImageView<Pixel = Rgb<u8>>>(tile: &I) -> Self {
+        let (width, height) = tile.dimensions();
+        if width < 3 || height < 3 {
+            return Edges::default();
+        }
+        let luma: Vec<f64> = tile.pixels().map(|(_, _, p)| color::luma(p.0)).collect();
+        let at = |x: u32, y: u32| luma[(y * width + x) as usize];
+        let mut sums = [0.0f64; 3];
+        for y in 1..height - 1 {
+            for x in 1..width - 1 {
+                let gx = at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1)
+                    - at(x - 1, y - 1)
+                    - 2.0 * at(x - 1, y)
+                    - at(x - 1, y + 1);
+                let gy = at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1)
+                    - at(x - 1, y - 1)
+                    - 2.0 * at(x, y - 1)
+                    - at(x + 1, y - 1);
+                sums[0] += gx * gx;
+                sums[1] += gy * gy;
+                sums[2] += gx * gy;
+            }
+        }
+        let count = f64::from((width - 2) * (height - 2));
+        let root = |v: f64| (v.signum() * (v.abs() / count).sqrt()) as f32;
+        Edges([root(sums[0]), root(sums[1]), root(sums[2])])
+    }
+
+    /// The edges of the tile turned by `orientation`. Quarter turns swap
+    /// horizontal and vertical, turns and mirroring flip the diagonal.
+    pub fn oriented(self, orientation: Orientation) -> Self {
+        let [horizontal, vertical, diagonal] = self.0;
+        let odd_turn = orientation.quarter_turns % 2 == 1;
+        let (horizontal, vertical) = if odd_turn {
+            (vertical, horizontal)
+        } else {
+            (horizontal, vertical)
+        };
+        let diagonal = if odd_turn != orientation.mirrored {
+            -diagonal
+        } else {
+            diagonal
+        };
+        Edges([horizontal, vertical, diagonal])
+    }
+
+    pub fn distance(&self, other: &Edges) -> f64 {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .map(|(&a, &b)| f64::from(a - b).powi(2))
+            .sum::<f64>()
+            .sqrt()
+    }
+}
+
+/// Luminance statistics that `normalize` moves tiles towards.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Normalization {
+    pub mean: f64,
+    pub stddev: f64,
+    /// limit for stretching or squeezing the contrast of a tile, at least 1
+    pub max_gain: f64,
+}
+
+/// Shifts and stretches the brightness of `tile` towards the target mean and
+/// standard deviation of luminance. Every channel of a pixel moves by the
+/// same amount, so the hue stays untouched.
+pub fn normalize(tile: &mut RgbImage, target: Normalization) {
+    let luma = |p: &image::Rgb<u8>| {
+        0.299 * f64::from(p[0]) + 0.587 * f64::from(p[1]) + 0.114 * f64::from(p[2])
+    };
+    let count = f64::from(tile.width() * tile.height());
+    if count == 0.0 {
+        return;
+    }
+    let mean = tile.pixels().map(luma).sum::<f64>() / count;
+    let variance = tile.pixels().map(|p| (luma(p) - mean).powi(2)).sum::<f64>() / count;
+    let gain = if variance > 0.0 {
+        (target.stddev / variance.sqrt()).clamp(1.0 / target.max_gain, target.max_gain)
+    } else {
+        1.0
+    };
+    for p in tile.pixels_mut() {
+        let l = luma(p);
+        let shift = target.mean + (l - mean) * gain - l;
+        for c in p.0.iter_mut() {
+            *c = (f64::from(*c) + shift).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+/// Variance of the pixel values of `tile`, averaged over the three channels.
+pub fn variance(tile: &RgbImage) -> f64 {
+    let count = f64::from(tile.width() * tile.height());
+    if count == 0.0 {
+        return 0.0;
+    }
+    let mut sum = [0.0; 3];
+    let mut squares = [0.0; 3];
+    for p in tile.pixels() {
+        for c in 0..3 {
+            let v = f64::from(p[c]);
+            sum[c] += v;
+            squares[c] += v * v;
+        }
+    }
+    (0..3)
+        .map(|c| squares[c] / count - (sum[c] / count).powi(2))
+        .sum::<f64>()
+        / 3.0
+}
+
+/// The share of the pixels of `tile` in its most common color, with every
+/// channel cut down to 4 levels.
+pub fn dominant_fraction(tile: &RgbImage) -> f64 {
+    let mut buckets = [0u32; 64];
+    for p in tile.pixels() {
+        let bucket = (p[0] >> 6) << 4 | (p[1] >> 6) << 2 | p[2] >> 6;
+        buckets[usize::from(bucket)] += 1;
+    }
+    let count = tile.width() * tile.height();
+    if count == 0 {
+        return 0.0;
+    }
+    f64::from(*buckets.iter().max().unwrap()) / f64::from(count)
+}
+
+/// Splits a width×height image into `side`×`side` cells, row by row. Cells
+/// are never empty, in images narrower than `side` pixels they overlap.
+pub fn cell_regions(width: u32, height: u32, side: u32) -> Vec<Region> {
+    let span = |length: u32, i: u32| {
+        let start = (length * i / side).min(length.saturating_sub(1));
+        let end = (length * (i + 1) / side).max(start + 1);
+        (start, end - start)
+    };
+    let mut cells = Vec::with_capacity((side * side) as usize);
+    for row in 0..side {
+        for column in 0..side {
+            let (x, w) = span(width, column);
+            let (y, h) = span(height, row);
+            cells.push((x, y, w, h));
+        }
+    }
+    cells
+}
+
+/// The median of each channel of `img`, the upper one of the middle two for
+/// an even number of pixels. Black for an empty view.
+pub fn median_color<I: GenericImageView<Pixel = Rgb<u8>>>(img: &I) -> [u8; 3] {
+    let pixels = (img.width() * img.height()) as usize;
+    let mut channels = [(); 3].map(|_| Vec::with_capacity(pixels));
+    for (_, _, p) in img.pixels() {
+        for (values, &v) in channels.iter_mut().zip(p.0.iter()) {
+            values.push(v);
+        }
+    }
+    channels.map(|mut values| {
+        if values.is_empty() {
+            return 0;
+        }
+        let middle = values.len() / 2;
+        *values.select_nth_unstable(middle).1
+    })
+}
+
+/// Clusters the pixels of `img` into up to `DOMINANT_CLUSTERS` groups with
+/// k-means and returns the center of the largest one. The first centers are
+/// picked deterministically, each the pixel farthest from those before it.
+pub fn dominant_color<I: GenericImageView<Pixel = Rgb<u8>>>(img: &I) -> [u8; 3] {
+    let pixels: Vec<[f64; 3]> = img
+        .pixels()
+        .map(|(_, _, p)| [f64::from(p[0]), f64::from(p[1]), f64::from(p[2])])
+        .collect();
+    let Some(&first) = pixels.first() else {
+        return [0; 3];
+    };
+    let dist = |a: &[f64; 3], b: &[f64; 3]| (0..3).map(|c| (a[c] - b[c]).powi(2)).sum::<f64>();
+    let nearest = |centers: &[[f64; 3]], p: &[f64; 3]| {
+        (0..centers.len())
+            .min_by(|&a, &b| dist(&centers[a], p).total_cmp(&dist(&centers[b], p)))
+            .unwrap()
+    };
+    let mut centers = vec![first];
+    while centers.len() < DOMINANT_CLUSTERS {
+        let far = pixels
+            .iter()
+            .max_by(|a, b| {
+                let gap = |p| dist(&centers[nearest(&centers, p)], p);
+                gap(a).total_cmp(&gap(b))
+            })
+            .unwrap();
+        if dist(&centers[nearest(&centers, far)], far) == 0.0 {
+            break;
+        }
+        centers.push(*far);
+    }
+    let mut counts = vec![0; centers.len()];
+    for _ in 0..DOMINANT_ITERATIONS {
+        let mut sums = vec![[0.0; 3]; centers.len()];
+        counts = vec![0; centers.len()];
+        for p in &pixels {
+            let i = nearest(&centers, p);
+            counts[i] += 1;
+            for c in 0..3 {
+                sums[i][c] += p[c];
+            }
+        }
+        let moved: Vec<[f64; 3]> = (0..centers.len())
+            .map(|i| match counts[i] {
+                0 => centers[i],
+                n => sums[i].map(|s| s / f64::from(n)),
+            })
+            .collect();
+        if moved == centers {
+            break;
+        }
+        centers = moved;
+    }
+    let largest = (0..centers.len()).max_by_key(|&i| counts[i]).unwrap();
+    centers[largest].map(|c| c.round() as u8)
+}
+
+/// `k` of the k-means in `dominant_color`.
+const DOMINANT_CLUSTERS: usize = 3;
+
+/// Upper bound for the k-means rounds in `dominant_color`.
+const DOMINANT_ITERATIONS: usize = 8;
+
+/// Leaves out the cells that are fully transparent in `alpha`. Also returns
+/// how many cells were left out.
+pub fn visible_cells(
+    alpha: &GrayImage,
+    cells: Vec<(u32, u32)>,
+    (tile_width, tile_height): (u32, u32),
+) -> (Vec<(u32, u32)>, usize) {
+    let total = cells.len();
+    let visible: Vec<(u32, u32)> = cells
+        .into_iter()
+        .filter(|&(x, y)| {
+            alpha
+                .view(x, y, tile_width, tile_height)
+                .pixels()
+                .any(|(_, _, p)| p[0] != 0)
+        })
+        .collect();
+    let rejected = total - visible.len();
+    (visible, rejected)
+}
+
+#[test]
+fn small_images_yield_no_tiles() {
+    let img = RgbImage::new(16, 16);
+    assert!(!fits(&img, (32, 32)));
+    assert!(extract_tiles(&img, (32, 32)).is_empty());
+    let img = RgbImage::new(100, 70);
+    assert!(fits(&img, (32, 32)));
+    assert_eq!(extract_tiles(&img, (32, 32)).len(), 3 * 2);
+    assert_eq!(extract_tiles(&RgbImage::new(64, 32), (32, 32)).len(), 2);
+    assert!(!fits(&img, (16, 80)));
+}
+
+#[test]
+fn smaller_strides_overlap_tiles() {
+    assert_eq!(grid(100, 70, (32, 32), (32, 32)).len(), 3 * 2);
+    let cells = grid(100, 70, (32, 32), (16, 16));
+    assert_eq!(cells.len(), 5 * 3);
+    assert_eq!(cells[..3], [(0, 0), (0, 16), (0, 32)]);
+    assert_eq!(cells.last(), Some(&(64, 32)));
+}
+
+#[test]
+fn size_lists_are_parsed() {
+    assert_eq!("64, 32,16,32".parse(), Ok(Sizes(vec![64, 32, 16])));
+    assert!("64,,16".parse::<Sizes>().is_err());
+    assert!("0".parse::<Sizes>().is_err());
+}
+
+#[test]
+fn regions_are_scaled_back() {
+    assert_eq!(
+        scale_region((32, 64, 32, 32), (1024, 768), (4096, 3072)),
+        (128, 256, 128, 128)
+    );
+    assert_eq!(
+        scale_region((5, 6, 7, 8), (100, 100), (100, 100)),
+        (5, 6, 7, 8)
+    );
+}
+
+#[test]
+fn random_crops_stay_inside_the_image() {
+    let cells = random_cells(100, 70, (32, 32), 50, 7);
+    assert_eq!(cells.len(), 50);
+    assert!(cells.iter().all(|&(x, y)| x + 32 <= 100 && y + 32 <= 70));
+    assert!(cells.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(cells, random_cells(100, 70, (32, 32), 50, 7));
+    assert_ne!(cells, random_cells(100, 70, (32, 32), 50, 8));
+    assert_eq!(random_cells(33, 32, (32, 32), 200, 7), vec![(0, 0), (1, 0)]);
+    assert!(random_cells(20, 70, (32, 32), 10, 7).is_empty());
+    assert!("random-crops".parse::<TileMode>().unwrap().cuts_cells());
+}
+
+#[test]
+fn small_images_are_padded() {
+    let mut img = RgbImage::from_pixel(3, 2, image::Rgb([10, 10, 10]));
+    img.put_pixel(0, 0, image::Rgb([200, 0, 0]));
+    let (padded, offset) = pad(&img, (7, 4), Some(image::Rgb([0, 0, 255])));
+    assert_eq!(padded.dimensions(), (7, 4));
+    assert_eq!(offset, (2, 1));
+    assert_eq!(padded.get_pixel(2, 1), &image::Rgb([200, 0, 0]));
+    assert_eq!(padded.get_pixel(0, 0), &image::Rgb([0, 0, 255]));
+    assert_eq!(extract_tiles(&padded, (7, 4)).len(), 1);
+
+    let (mirrored, _) = pad(&img, (7, 4), None);
+    assert_eq!(mirrored.get_pixel(1, 1), &image::Rgb([200, 0, 0]));
+    assert_eq!(mirrored.get_pixel(2, 0), &image::Rgb([200, 0, 0]));
+    assert_eq!(unpad_region((0, 0, 7, 4), offset, (3, 2)), (0, 0, 3, 2));
+    assert_eq!("mirror".parse(), Ok(Padding::Mirror));
+    assert!("#00ff00".parse::<Padding>().is_ok());
+    assert!("wrap".parse::<Padding>().is_err());
+}
+
+#[test]
+fn rectangular_tiles_are_cut() {
+    let img = RgbImage::new(100, 70);
+    let tiles = extract_tiles(&img, (16, 32));
+    assert_eq!(tiles.len(), 6 * 2);
+    assert!(tiles.iter().all(|t| t.dimensions() == (16, 32)));
+}
+
+#[test]
+fn transparent_tiles_are_left_out() {
+    let mut alpha = GrayImage::new(100, 70);
+    alpha.put_pixel(40, 10, image::Luma([1]));
+    let (cells, rejected) = visible_cells(&alpha, grid(100, 70, (32, 32), (32, 32)), (32, 32));
+    assert_eq!(cells, vec![(32, 0)]);
+    assert_eq!(rejected, 5);
+}
+
+#[test]
+fn whole_images_become_one_tile() {
+    let mut img = RgbImage::from_pixel(90, 60, image::Rgb([10, 20, 30]));
+    img.put_pixel(0, 0, image::Rgb([255, 255, 255]));
+    let region = single_tile_region(TileMode::WholeImage, 90, 60, (16, 16), Gravity::Top);
+    assert_eq!(region, (0, 0, 90, 60));
+    let tile = shrink_to_tile(&img, region, (16, 16), "triangle".parse().unwrap());
+    assert_eq!(tile.dimensions(), (16, 16));
+    assert_eq!(tile.get_pixel(8, 8), &image::Rgb([10, 20, 30]));
+    assert!("grid".parse::<TileMode>().is_ok());
+    assert!("bicubic".parse::<Filter>().is_err());
+    assert_eq!("catmullrom".parse(), Ok(Filter(FilterType::CatmullRom)));
+}
+
+#[test]
+fn squares_are_cropped_by_gravity() {
+    let square = |w, h, gravity| aspect_crop(w, h, (32, 32), gravity);
+    assert_eq!(square(300, 200, Gravity::Center), (50, 0, 200, 200));
+    assert_eq!(square(300, 200, Gravity::Top), (50, 0, 200, 200));
+    assert_eq!(square(200, 300, Gravity::Center), (0, 50, 200, 200));
+    assert_eq!(square(200, 300, Gravity::Top), (0, 0, 200, 200));
+    assert_eq!(square(200, 300, Gravity::Bottom), (0, 100, 200, 200));
+    assert_eq!(square(201, 202, Gravity::Center), (0, 0, 201, 201));
+    assert_eq!(square(64, 64, Gravity::Bottom), (0, 0, 64, 64));
+    let tall = |w, h| aspect_crop(w, h, (16, 32), Gravity::Top);
+    assert_eq!(tall(300, 200), (100, 0, 100, 200));
+    assert_eq!(tall(100, 300), (0, 0, 100, 200));
+}
+
+#[test]
+fn cover_tiles_keep_the_cropped_part() {
+    // a portrait image, red in the top half and blue in the bottom half
+    let img = RgbImage::from_fn(40, 80, |_, y| {
+        if y < 40 {
+            image::Rgb([255, 0, 0])
+        } else {
+            image::Rgb([0, 0, 255])
+        }
+    });
+    let cover = |gravity| {
+        let region = single_tile_region(TileMode::Cover, 40, 80, (8, 8), gravity);
+        shrink_to_tile(&img, region, (8, 8), Filter(FilterType::Nearest))
+    };
+    assert!(cover(Gravity::Top)
+        .pixels()
+        .all(|p| *p == image::Rgb([255, 0, 0])));
+    assert!(cover(Gravity::Bottom)
+        .pixels()
+        .all(|p| *p == image::Rgb([0, 0, 255])));
+    assert_eq!(cover(Gravity::Center).dimensions(), (8, 8));
+}
+
+#[test]
+fn flipped_variants_are_mirror_images() {
+    let mut tile = RgbImage::new(2, 2);
+    tile.put_pixel(0, 0, image::Rgb([255, 0, 0]));
+    let variants = Orientation::variants(&[Augment::Flips, Augment::Flips]);
+    assert_eq!(variants.len(), 3);
+    let red_at = |o: Orientation| {
+        let turned = o.apply(&tile);
+        let (x, y, _) = turned
+            .enumerate_pixels()
+            .find(|(_, _, p)| p[0] == 255)
+            .unwrap();
+        (x, y)
+    };
+    assert_eq!(red_at(variants[0]), (0, 0));
+    assert_eq!(red_at(variants[1]), (1, 0));
+    assert_eq!(red_at(variants[2]), (0, 1));
+}
+
+#[test]
+fn rotations_compose_with_flips() {
+    let mut tile = RgbImage::new(2, 2);
+    tile.put_pixel(0, 0, image::Rgb([255, 0, 0]));
+    tile.put_pixel(1, 0, image::Rgb([0, 255, 0]));
+    let rotations = Orientation::variants(&[Augment::Rotations]);
+    assert_eq!(rotations.len(), 4);
+    let turned = rotations[1].apply(&tile);
+    assert_eq!(turned.get_pixel(1, 0), &image::Rgb([255, 0, 0]));
+    assert_eq!(turned.get_pixel(1, 1), &image::Rgb([0, 255, 0]));
+
+    let all = Orientation::variants(&[Augment::Flips, Augment::Rotations]);
+    let images: Vec<RgbImage> = all.iter().map(|o| o.apply(&tile).into_owned()).collect();
+    assert_eq!(images.len(), 8);
+    for (i, a) in images.iter().enumerate() {
+        assert!(images[i + 1..].iter().all(|b| a != b));
+    }
+}
+
+#[test]
+fn histograms_count_shares_of_pixels() {
+    let mut tile = RgbImage::from_pixel(4, 4, image::Rgb([0, 0, 0]));
+    for x in 0..4 {
+        tile.put_pixel(x, 0, image::Rgb([255, 100, 0]));
+    }
+    let histogram = Histogram::of(&tile);
+    assert_eq!(histogram.0[0], 49151);
+    assert_eq!(
+        histogram.0[(3 * HISTOGRAM_BINS + 1) * HISTOGRAM_BINS],
+        16383
+    );
+    assert_eq!(histogram.0.iter().filter(|&&share| share > 0).count(), 2);
+    assert!(Histogram::try_from(vec![0; 63]).is_err());
+    assert_eq!(histogram.chi_square(&histogram), 0.0);
+    let black = Histogram::of(&RgbImage::new(4, 4));
+    let white = Histogram::of(&RgbImage::from_pixel(4, 4, Rgb([255; 3])));
+    assert!((black.chi_square(&white) - 1.0).abs() < 1e-6);
+}
+
+#[test]
+fn orientations_are_found_by_index() {
+    let all = Orientation::variants(&[Augment::Flips, Augment::Rotations]);
+    assert_eq!(all.len(), 8);
+    for orientation in all {
+        assert_eq!(
+            Orientation::from_index(orientation.index()),
+            Some(orientation)
+        );
+    }
+    assert_eq!(Orientation::from_index(8), None);
+}
+
+#[test]
+fn cells_follow_the_orientation() {
+    assert_eq!(
+        cell_regions(10, 7, 2),
+        vec![(0, 0, 5, 3), (5, 0, 5, 3), (0, 3, 5, 4), (5, 3, 5, 4)]
+    );
+    assert_eq!(cell_regions(1, 1, 2), vec![(0, 0, 1, 1); 4]);
+    let mirrored = Orientation {
+        mirrored: true,
+        quarter_turns: 0,
+    };
+    assert_eq!(mirrored.permute_cells(2), vec![1, 0, 3, 2]);
+    let turned = Orientation {
+        mirrored: false,
+        quarter_turns: 1,
+    };
+    assert_eq!(turned.permute_cells(2), vec![2, 0, 3, 1]);
+    assert_eq!(
+        Orientation::ORIGINAL.permute_cells(3),
+        (0..9).collect::<Vec<_>>()
+    );
+    assert_eq!(KeyMode::Quadrants.len(), 12);
+    assert_eq!(KeyMode::Grid3.len(), 27);
+    assert_eq!(KeyMode::MeanStd.len(), 6);
+}
+
+#[test]
+fn dominant_colors_are_not_mixed() {
+    let (blue, orange) = (image::Rgb([20, 60, 220]), image::Rgb([240, 140, 20]));
+    let halves = RgbImage::from_fn(10, 10, |x, _| if x < 6 { blue } else { orange });
+    assert_eq!(dominant_color(&halves), blue.0);
+    let even = RgbImage::from_fn(10, 10, |x, _| if x < 5 { blue } else { orange });
+    assert!([blue.0, orange.0].contains(&dominant_color(&even)));
+    let flat = RgbImage::from_pixel(4, 4, orange);
+    assert_eq!(dominant_color(&flat), orange.0);
+    assert_eq!(dominant_color(&halves.view(6, 0, 4, 10)), orange.0);
+}
+
+#[test]
+fn medians_ignore_outliers() {
+    let mut specked = RgbImage::from_pixel(5, 5, Rgb([60, 90, 30]));
+    for (x, y) in [(0, 0), (4, 1), (2, 3)] {
+        specked.put_pixel(x, y, Rgb([255, 255, 255]));
+    }
+    specked.put_pixel(1, 4, Rgb([0, 0, 0]));
+    assert_eq!(median_color(&specked), [60, 90, 30]);
+    let mixed = RgbImage::from_fn(4, 1, |x, _| Rgb([[10, 40, 20, 30][x as usize], 0, 255]));
+    assert_eq!(median_color(&mixed), [30, 0, 255]);
+    assert_eq!(median_color(&mixed.view(0, 0, 0, 1)), [0, 0, 0]);
+}
+
+#[test]
+fn white_balance_removes_a_warm_cast() {
+    let warm = RgbImage::from_fn(4, 4, |x, _| Rgb([(120 + x * 20) as u8, 100, 80]));
+    let gains = gray_world_gains(&warm);
+    let balanced = white_balance(&warm, gains);
+    let means = (0..3).map(|c| balanced.pixels().map(|p| u32::from(p[c])).sum::<u32>() / 16);
+    assert!(means
+        .collect::<Vec<_>>()
+        .windows(2)
+        .all(|w| w[0].abs_diff(w[1]) <= 1));
+    assert!(matches!(
+        white_balance(&warm, Gains::NONE),
+        Cow::Borrowed(_)
+    ));
+    assert_eq!(gray_world_gains(&RgbImage::new(0, 0)), Gains::NONE);
+}
+
+#[test]
+fn desaturated_tiles_keep_their_luma() {
+    let tile = RgbImage::from_pixel(2, 2, image::Rgb([0, 255, 0]));
+    assert_eq!(
+        desaturate(&tile).get_pixel(1, 1),
+        &image::Rgb([182, 182, 182])
+    );
+}
+
+#[test]
+fn saturation_leaves_gray_alone() {
+    let gray = RgbImage::from_pixel(2, 2, image::Rgb([90, 90, 90]));
+    assert_eq!(saturate(&gray, 2.0), gray);
+    let red = RgbImage::from_pixel(2, 2, image::Rgb([200, 50, 50]));
+    assert_eq!(
+        saturate(&red, 1.2).get_pixel(0, 0),
+        &image::Rgb([200, 20, 20])
+    );
+    assert_eq!(
+        saturate(&red, 5.0).get_pixel(0, 0),
+        &image::Rgb([200, 0, 0])
+    );
+    assert_eq!(
+        saturate(&red, 0.0).get_pixel(0, 0),
+        &image::Rgb([200, 200, 200])
+    );
+    assert_eq!(saturate(&red, 1.0), red);
+}
+
+#[test]
+fn sharpening_adds_contrast_at_edges() {
+    let tile = RgbImage::from_fn(8, 8, |x, _| image::Rgb([if x < 4 { 100 } else { 150 }; 3]));
+    let sharp = sharpen(&tile, 1.0);
+    assert!(sharp.get_pixel(3, 4)[0] < 100);
+    assert!(sharp.get_pixel(4, 4)[0] > 150);
+    assert_eq!(sharp.get_pixel(0, 4), tile.get_pixel(0, 4));
+    assert_eq!(sharpen(&tile, 0.0), tile);
+}
+
+#[test]
+fn normalized_tiles_meet_the_target() {
+    let target = Normalization {
+        mean: 128.0,
+        stddev: 40.0,
+        max_gain: 4.0,
+    };
+    // dark, low-contrast stripes
+    let mut tile = RgbImage::from_fn(4, 4, |x, _| {
+        let v = if x % 2 == 0 { 20 } else { 40 };
+        image::Rgb([v, v, v])
+    });
+    normalize(&mut tile, target);
+    assert_eq!(tile.get_pixel(0, 0), &image::Rgb([88, 88, 88]));
+    assert_eq!(tile.get_pixel(1, 0), &image::Rgb([168, 168, 168]));
+
+    let mut flat = RgbImage::from_pixel(2, 2, image::Rgb([255, 0, 0]));
+    normalize(&mut flat, target);
+    assert_eq!(flat.get_pixel(0, 0), &image::Rgb([255, 52, 52]));
+}
+
+#[test]
+fn flat_tiles_have_no_variance() {
+    let flat = RgbImage::from_pixel(4, 4, image::Rgb([90, 10, 200]));
+    assert_eq!(variance(&flat), 0.0);
+    let stripes = RgbImage::from_fn(4, 4, |x, _| {
+        let v = if x % 2 == 0 { 0 } else { 20 };
+        image::Rgb([v, v, 0])
+    });
+    // 100 for red and green, 0 for blue
+    assert!((variance(&stripes) - 200.0 / 3.0).abs() < 1e-9);
+}
+
+#[test]
+fn dominant_colors_are_measured_coarsely() {
+    // a letterbox bar over a quarter of the tile, the rest almost black
+    let tile = RgbImage::from_fn(4, 4, |_, y| {
+        if y == 0 {
+            image::Rgb([255, 255, 255])
+        } else {
+            image::Rgb([(y * 20) as u8, 0, 10])
+        }
+    });
+    assert_eq!(dominant_fraction(&tile), 0.75);
+    let halves = RgbImage::from_fn(4, 4, |x, _| image::Rgb([if x < 2 { 0 } else { 255 }; 3]));
+    assert_eq!(dominant_fraction(&halves), 0.5);
+}
+
+#[test]
+fn large_images_are_downscaled() {
+    let lanczos = Filter(FilterType::Lanczos3);
+    let img = limit_dimension(RgbImage::new(400, 100), 256, lanczos);
+    assert_eq!(img.dimensions(), (256, 64));
+    let img = limit_dimension(RgbImage::new(300, 200), 2048, lanczos);
+    assert_eq!(img.dimensions(), (300, 200));
+    let img = limit_dimension(RgbImage::new(400, 100), 0, lanczos);
+    assert_eq!(img.dimensions(), (400, 100));
+}
+
+#[test]
+fn edges_turn_with_their_tile() {
+    let tile = RgbImage::from_fn(6, 6, |x, y| Rgb([if x + 2 * y < 7 { 20 } else { 220 }; 3]));
+    let edges = Edges::of(&tile);
+    assert!(edges.0[1] > edges.0[0] && edges.0[2] > 0.0);
+    for orientation in Orientation::variants(&[Augment::Flips, Augment::Rotations]) {
+        let turned = Edges::of(orientation.apply(&tile).as_ref());
+        assert!(
+            edges.oriented(orientation).distance(&turned) < 1e-3,
+            "{:?}",
+            orientation
+        );
+    }
+    assert_eq!(
+        Edges::of(&RgbImage::from_pixel(6, 6, Rgb([90; 3]))),
+        Edges::default()
+    );
+}
+
+#[test]
+fn stretching_spreads_foggy_images() {
+    let foggy = RgbImage::from_fn(100, 1, |x, _| Rgb([100 + x as u8 / 2; 3]));
+    let stretch = Stretch::of(&foggy);
+    assert_eq!(
+        stretch,
+        Stretch {
+            low: 100,
+            high: 149
+        }
+    );
+    let stretched = stretch.apply(&foggy);
+    assert_eq!(stretched.get_pixel(0, 0), &Rgb([0; 3]));
+    assert_eq!(stretched.get_pixel(99, 0), &Rgb([255; 3]));
+    assert_eq!(stretch.undo(&stretched).as_ref(), &foggy);
+
+    // a stray pixel at either end doesn't keep the rest from stretching
+    let mut specked = foggy.clone();
+    specked.put_pixel(0, 0, Rgb([0; 3]));
+    specked.put_pixel(99, 0, Rgb([255; 3]));
+    assert_eq!(
+        Stretch::of(&specked),
+        Stretch {
+            low: 100,
+            high: 149
+        }
+    );
+
+    let full = RgbImage::from_fn(256, 1, |x, _| Rgb([x as u8; 3]));
+    assert_eq!(Stretch::of(&full), Stretch::NONE);
+    assert!(matches!(Stretch::of(&full).apply(&full), Cow::Borrowed(_)));
+    let flat = RgbImage::from_pixel(8, 8, Rgb([90, 90, 90]));
+    assert_eq!(Stretch::of(&flat), Stretch::NONE);
+    assert_eq!(Stretch::of(&RgbImage::new(0, 0)), Stretch::NONE);
+}
@@ -0,0 +1,68 @@
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+/// How often the inputs are listed again.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long the listing has to stay the same before a change is acted on, so
+/// copying a folder of photos triggers one render instead of dozens.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Changes {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+pub fn diff(old: &BTreeSet<PathBuf>, new: &BTreeSet<PathBuf>) -> Changes {
+    Changes {
+        added: new.difference(old).cloned().collect(),
+        removed: old.difference(new).cloned().collect(),
+    }
+}
+
+/// Polls `list` until it differs from `known` and then stays the same for
+/// `SETTLE_TIME`.
+pub fn wait_for_changes(
+    known: &BTreeSet<PathBuf>,
+    list: &dyn Fn() -> BTreeSet<PathBuf>,
+) -> Changes {
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        let mut current = list();
+        if current == *known {
+            continue;
+        }
+        let mut stable = Duration::from_secs(0);
+        while stable < SETTLE_TIME {
+            thread::sleep(POLL_INTERVAL);
+            let next = list();
+            if next == current {
+                stable += POLL_INTERVAL;
+            } else {
+                current = next;
+                stable = Duration::from_secs(0);
+            }
+        }
+        let changes = diff(known, &current);
+        if changes != Changes::default() {
+            return changes;
+        }
+    }
+}
+
+#[test]
+fn listings_are_diffed() {
+    let set = |names: &[&str]| names.iter().map(PathBuf::from).collect::<BTreeSet<_>>();
+    let changes = diff(
+        &set(&["a.jpg", "b.jpg"]),
+        &set(&["b.jpg", "c.jpg", "d.jpg"]),
+    );
+    assert_eq!(
+        changes.added,
+        vec![PathBuf::from("c.jpg"), PathBuf::from("d.jpg")]
+    );
+    assert_eq!(changes.removed, vec![PathBuf::from("a.jpg")]);
+}
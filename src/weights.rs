@@ -0,0 +1,68 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Name of the file in an input directory that maps file names to weights.
+pub const WEIGHTS_FILE: &str = "weights.toml";
+
+/// How strongly tiles of each source file are preferred over near-equal
+/// tiles from other files. Files without an entry weigh 1.
+#[derive(Debug, Default)]
+pub struct Weights(HashMap<PathBuf, f64>);
+
+impl Weights {
+    /// Reads `weights.toml` from each of `dirs` that has one. Its keys are
+    /// paths relative to that directory, like `"dog.jpg" = 3.0`.
+    pub fn load(dirs: &[&PathBuf]) -> Result<Self, String> {
+        let mut weights = HashMap::new();
+        for dir in dirs {
+            let file = dir.join(WEIGHTS_FILE);
+            let text = match fs::read_to_string(&file) {
+                Ok(text) => text,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(format!("Could not read {}: {}", file.display(), e)),
+            };
+            let table: BTreeMap<String, f64> = toml::from_str(&text)
+                .map_err(|e| format!("Could not parse {}: {}", file.display(), e))?;
+            for (name, weight) in table {
+                if !(weight >= 0.0 && weight.is_finite()) {
+                    return Err(format!(
+                        "{}: weight of {} must be a non-negative number",
+                        file.display(),
+                        name
+                    ));
+                }
+                weights.insert(dir.join(name), weight);
+            }
+        }
+        Ok(Weights(weights))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, path: &Path) -> f64 {
+        self.0.get(path).copied().unwrap_or(1.0)
+    }
+}
+
+#[test]
+fn weights_are_read_relative_to_their_directory() {
+    let dir = crate::input::scratch_dir("weights");
+    fs::write(
+        dir.join(WEIGHTS_FILE),
+        "\"dog.jpg\" = 3.5\n\"sub/grass.png\" = 0\n",
+    )
+    .unwrap();
+
+    let weights = Weights::load(&[&dir]).unwrap();
+    assert_eq!(weights.get(&dir.join("dog.jpg")), 3.5);
+    assert_eq!(weights.get(&dir.join("sub/grass.png")), 0.0);
+    assert_eq!(weights.get(&dir.join("cat.jpg")), 1.0);
+
+    fs::write(dir.join(WEIGHTS_FILE), "\"dog.jpg\" = -1\n").unwrap();
+    assert!(Weights::load(&[&dir]).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}